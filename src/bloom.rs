@@ -0,0 +1,61 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use serde::Serialize;
+
+// answers whether a key may be in a set without false negatives. A disktable builds one
+// while it reads its pages at open, so a get of a key it does not hold skips the page read.
+// It is never written out, the hashes only have to agree within one process
+pub struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    // a filter over the given key hashes, an empty one lets every key through
+    pub fn new(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        if bits_per_key == 0 || key_hashes.is_empty() {
+            return Self {
+                bits: Vec::new(),
+                hashes: 0,
+            };
+        }
+        // k = bits per key * ln 2 minimises false positives
+        let hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let words = (key_hashes.len() * bits_per_key).div_ceil(64);
+        let mut bloom = Self {
+            bits: vec![0; words],
+            hashes,
+        };
+        for h in key_hashes {
+            let probes: Vec<usize> = bloom.probes(*h).collect();
+            for bit in probes {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    pub fn may_contain(&self, key_hash: u64) -> bool {
+        self.bits.is_empty()
+            || self
+                .probes(key_hash)
+                .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing, the i-th probe is h1 + i * h2
+    fn probes(&self, h: u64) -> impl Iterator<Item = usize> + '_ {
+        let len = self.bits.len() as u64 * 64;
+        let h2 = h.rotate_left(32) | 1;
+        (0..self.hashes as u64).map(move |i| (h.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+// keys are hashed in their encoded form, so they do not need to implement Hash
+pub fn hash_key<K: Serialize>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bincode::serialize(key).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,774 @@
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    rc::Rc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer_manager::{Block, BufferManager},
+    fixed::KnowsSize,
+    kv_store::{EntryTooLarge, KeyRange, KeyValueStore, StoreStats},
+    options::{Options, OptionsError},
+    slotted_page::{PageMut, PageView},
+    storage::{FileSystem, Storage, StorageFile},
+};
+
+const LEAF: u8 = 0;
+const INTERNAL: u8 = 1;
+const FREE: u8 = 2;
+
+const NO_PAGE: u64 = u64::MAX;
+const META_MAGIC: u32 = 0x4250_5432;
+// the kind and link in front of a node's slotted page
+const NODE_HEADER: usize = 9;
+// the header of a slotted page, of the larger fixed kind
+const PAGE_HEADER: usize = 6;
+// the most a cell takes besides its key and value: its slot, the two lengths of a
+// variable cell and the tag of the value's Option
+const CELL_OVERHEAD: usize = 13;
+
+/*
+Meta page (page 0):
+| magic u32 | root page u64 | next unallocated page u64 | first free page u64 | block size u32 |
+Node page format:
+| kind u8 | link u64 | slotted page |
+The slotted page fills the rest of the block. Leaf cells are key and value, internal cells
+are key and child page. For leaves the link is the right sibling, for internal nodes it is
+the child holding keys below the first cell. Inserts and deletes edit the slotted page in
+place, only splits and merges lay nodes out anew. Freed pages keep only the kind and the
+link, which points at the next free page.
+*/
+
+#[derive(Debug)]
+pub struct BPlusStats {
+    pub height: usize,
+    pub pages: usize,
+    pub free_pages: usize,
+}
+
+pub struct BPlusTree<K, V, S: Storage = FileSystem> {
+    file: String,
+    block_size: usize,
+    root: u64,
+    next_page: u64,
+    free_head: u64,
+    _entries: PhantomData<(K, V, S)>,
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > BPlusTree<K, V, S>
+{
+    pub fn new(name: String, manager: &mut BufferManager<S>) -> Self {
+        Self::with_options(name, manager, &Options::default()).unwrap()
+    }
+
+    // opens the tree at name under the data directory of the options, which are checked
+    // first
+    pub fn with_options(
+        name: String,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let storage = manager.storage();
+        storage.create_dir_all(&options.data_dir).unwrap();
+        let file = format!("{}/{}", options.data_dir, name);
+        // the meta page says how large it is, unless it is still only in the buffer
+        // manager, and a new tree takes the configured size
+        let block_size = match manager.block_size(&file) {
+            Some(size) => size,
+            None => {
+                let mut meta = [0; 32];
+                match storage
+                    .open(&file, false)
+                    .and_then(|f| f.read_at(&mut meta, 0))
+                {
+                    Ok(()) if meta[0..4] == bincode::serialize(&META_MAGIC).unwrap()[..] => {
+                        bincode::deserialize::<u32>(&meta[28..32]).unwrap() as usize
+                    }
+                    _ => options.block_size,
+                }
+            }
+        };
+        manager.set_block_size(&file, block_size);
+        let mut s = Self {
+            file,
+            block_size,
+            root: 1,
+            next_page: 2,
+            free_head: NO_PAGE,
+            _entries: PhantomData,
+        };
+
+        match manager.get(s.file.clone(), 0) {
+            Some(block) => {
+                let b = block.as_ref().borrow();
+                let magic: u32 = bincode::deserialize(&b.bytes[0..4]).unwrap();
+                if magic != META_MAGIC {
+                    panic!("{} is not a B+Tree file", s.file);
+                }
+                s.root = bincode::deserialize(&b.bytes[4..12]).unwrap();
+                s.next_page = bincode::deserialize(&b.bytes[12..20]).unwrap();
+                s.free_head = bincode::deserialize(&b.bytes[20..28]).unwrap();
+            }
+            None => {
+                s.write_meta(manager);
+                s.write_node::<V>(manager, s.root, LEAF, NO_PAGE, &[]);
+            }
+        }
+        Ok(s)
+    }
+
+    // returns the separator and page of a new right sibling if the node had to split
+    fn insert(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        k: K,
+        v: V,
+    ) -> Option<(K, u64)> {
+        let (kind, link) = self.header(manager, page);
+        match kind {
+            LEAF => {
+                // the page takes values as they are stored, Some for a leaf
+                let mut value = Some(v);
+                let placed = self.edit(manager, page, |p: &mut PageMut<K, V>| {
+                    let i = p.view().lower_bound(&k);
+                    match i < p.len() && p.view().key(i) == k {
+                        true => p.update(i, &value),
+                        false => p.insert(i, &k, &value),
+                    }
+                });
+                if placed.is_ok() {
+                    return None;
+                }
+                let mut entries: Vec<(K, V)> = self.entries(manager, page);
+                let v = value.take().unwrap();
+                match entries.binary_search_by(|(e, _)| e.cmp(&k)) {
+                    Ok(i) => entries[i].1 = v,
+                    Err(i) => entries.insert(i, (k, v)),
+                }
+                Some(self.split_leaf(manager, page, link, entries))
+            }
+            INTERNAL => {
+                let (i, child) = self.child(manager, page, &k);
+                let (sep, right) = self.insert(manager, child, k, v)?;
+                let cell = Some(right);
+                let placed = self.edit(manager, page, |p: &mut PageMut<K, u64>| {
+                    p.insert(i, &sep, &cell)
+                });
+                if placed.is_ok() {
+                    return None;
+                }
+                let mut entries: Vec<(K, u64)> = self.entries(manager, page);
+                entries.insert(i, (sep, right));
+                Some(self.split_internal(manager, page, link, entries))
+            }
+            _ => panic!("page {} of {} is not a node", page, self.file),
+        }
+    }
+
+    // lays a leaf that no longer fits in a page out as two, returning the separator and
+    // page of the right one
+    fn split_leaf(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        next: u64,
+        mut entries: Vec<(K, V)>,
+    ) -> (K, u64) {
+        let right = self.allocate(manager);
+        let right_entries = entries.split_off(split_point(&sizes(&entries)));
+        let sep = right_entries[0].0.clone();
+        self.write_node(manager, right, LEAF, next, &right_entries);
+        self.write_node(manager, page, LEAF, right, &entries);
+        (sep, right)
+    }
+
+    fn split_internal(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        first: u64,
+        mut entries: Vec<(K, u64)>,
+    ) -> (K, u64) {
+        let right = self.allocate(manager);
+        // the separator moves up, so keep at least one entry for the right node
+        let at = split_point(&sizes(&entries)).min(entries.len() - 2);
+        let mut right_entries = entries.split_off(at);
+        let (sep, right_first) = right_entries.remove(0);
+        self.write_node(manager, right, INTERNAL, right_first, &right_entries);
+        self.write_node(manager, page, INTERNAL, first, &entries);
+        (sep, right)
+    }
+
+    // returns whether the node at page is now under-filled
+    fn remove(&mut self, manager: &mut BufferManager<S>, page: u64, k: &K) -> bool {
+        let (kind, first) = self.header(manager, page);
+        match kind {
+            LEAF => {
+                let Some(slot) = self.slot_of(manager, page, k) else {
+                    return false;
+                };
+                self.edit(manager, page, |p: &mut PageMut<K, V>| {
+                    p.delete(slot).unwrap()
+                });
+                self.underfilled::<V>(manager, page)
+            }
+            INTERNAL => {
+                let (i, child) = self.child(manager, page, k);
+                if !self.remove(manager, child, k) || self.len(manager, page) == 0 {
+                    return false;
+                }
+                self.rebalance(manager, page, first, i);
+                self.underfilled::<u64>(manager, page)
+            }
+            _ => panic!("page {} of {} is not a node", page, self.file),
+        }
+    }
+
+    // merges the under-filled child at position i of parent with a neighbour, or evens
+    // the two out when they do not fit in one page. Position 0 is the first child, i is
+    // the cell at slot i - 1
+    fn rebalance(&mut self, manager: &mut BufferManager<S>, parent: u64, first: u64, i: usize) {
+        let entries: Vec<(K, u64)> = self.entries(manager, parent);
+        let right_pos = if i == 0 { 1 } else { i };
+        let left_page = if right_pos == 1 {
+            first
+        } else {
+            entries[right_pos - 2].1
+        };
+        let (sep, right_page) = entries[right_pos - 1].clone();
+        // of the right child's cell in the parent
+        let slot = right_pos - 1;
+        let (kind, left_link) = self.header(manager, left_page);
+        let (_, right_link) = self.header(manager, right_page);
+
+        match kind {
+            LEAF => {
+                let mut merged: Vec<(K, V)> = self.entries(manager, left_page);
+                merged.extend(self.entries::<V>(manager, right_page));
+                if let Some(buf) = self.layout(LEAF, right_link, &merged) {
+                    self.merge(manager, parent, slot, left_page, right_page, buf);
+                    return;
+                }
+                let right = merged.split_off(split_point(&sizes(&merged)));
+                if !self.replace_separator(manager, parent, slot, &right[0].0, right_page) {
+                    return;
+                }
+                self.write_node(manager, left_page, LEAF, right_page, &merged);
+                self.write_node(manager, right_page, LEAF, right_link, &right);
+            }
+            INTERNAL => {
+                let mut merged: Vec<(K, u64)> = self.entries(manager, left_page);
+                merged.push((sep, right_link));
+                merged.extend(self.entries::<u64>(manager, right_page));
+                if let Some(buf) = self.layout(INTERNAL, left_link, &merged) {
+                    self.merge(manager, parent, slot, left_page, right_page, buf);
+                    return;
+                }
+                let at = split_point(&sizes(&merged)).min(merged.len() - 2);
+                let mut right = merged.split_off(at);
+                let (new_sep, new_right_first) = right.remove(0);
+                if !self.replace_separator(manager, parent, slot, &new_sep, right_page) {
+                    return;
+                }
+                self.write_node(manager, left_page, INTERNAL, left_link, &merged);
+                self.write_node(manager, right_page, INTERNAL, new_right_first, &right);
+            }
+            _ => panic!("siblings at different depths in {}", self.file),
+        }
+    }
+
+    // writes the two children laid out as one to the left page and drops the right one
+    // from the parent
+    fn merge(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        parent: u64,
+        slot: usize,
+        left_page: u64,
+        right_page: u64,
+        buf: Vec<u8>,
+    ) {
+        manager.write(
+            &self.file,
+            self.offset(left_page),
+            &buf,
+            self.block_size as u32,
+        );
+        self.free(manager, right_page);
+        self.edit(manager, parent, |p: &mut PageMut<K, u64>| {
+            p.delete(slot).unwrap()
+        });
+    }
+
+    // puts key in place of the separator at slot of the parent. False when the parent has
+    // no room for it, which leaves the parent as it was and the children uneven
+    fn replace_separator(
+        &self,
+        manager: &mut BufferManager<S>,
+        parent: u64,
+        slot: usize,
+        key: &K,
+        child: u64,
+    ) -> bool {
+        let child = Some(child);
+        self.edit(manager, parent, |p: &mut PageMut<K, u64>| {
+            let old = p.view().key(slot);
+            p.delete(slot).unwrap();
+            match p.insert(slot, key, &child) {
+                Ok(()) => true,
+                Err(_) => {
+                    p.insert(slot, &old, &child).unwrap();
+                    false
+                }
+            }
+        })
+    }
+
+    fn allocate(&mut self, manager: &mut BufferManager<S>) -> u64 {
+        let page = if self.free_head != NO_PAGE {
+            let page = self.free_head;
+            self.free_head = self.header(manager, page).1;
+            page
+        } else {
+            self.next_page += 1;
+            self.next_page - 1
+        };
+        self.write_meta(manager);
+        page
+    }
+
+    // nodes whose cells take less than this many bytes borrow from or merge with a
+    // sibling
+    fn min_fill(&self) -> usize {
+        self.block_size / 4
+    }
+
+    // entries are capped so that a split always leaves two halves that fit in a page
+    fn max_entry(&self) -> usize {
+        (self.block_size - NODE_HEADER - PAGE_HEADER) / 4
+    }
+
+    fn free(&mut self, manager: &mut BufferManager<S>, page: u64) {
+        let mut buf = vec![0; self.block_size];
+        buf[0] = FREE;
+        buf[1..NODE_HEADER].copy_from_slice(&self.free_head.to_le_bytes());
+        manager.write(&self.file, self.offset(page), &buf, self.block_size as u32);
+        self.free_head = page;
+        self.write_meta(manager);
+    }
+
+    fn write_meta(&self, manager: &mut BufferManager<S>) {
+        let mut buf = vec![0; self.block_size];
+        buf[0..4].copy_from_slice(&bincode::serialize(&META_MAGIC).unwrap());
+        buf[4..12].copy_from_slice(&bincode::serialize(&self.root).unwrap());
+        buf[12..20].copy_from_slice(&bincode::serialize(&self.next_page).unwrap());
+        buf[20..28].copy_from_slice(&bincode::serialize(&self.free_head).unwrap());
+        buf[28..32].copy_from_slice(&bincode::serialize(&(self.block_size as u32)).unwrap());
+        manager.write(&self.file, 0, &buf, self.block_size as u32);
+    }
+
+    fn offset(&self, page: u64) -> usize {
+        page as usize * self.block_size
+    }
+
+    fn block(&self, manager: &mut BufferManager<S>, page: u64) -> Rc<RefCell<Block>> {
+        manager
+            .get(self.file.clone(), self.offset(page))
+            .unwrap_or_else(|| panic!("page {} missing from {}", page, self.file))
+    }
+
+    // the kind and link of the node at page
+    fn header(&self, manager: &mut BufferManager<S>, page: u64) -> (u8, u64) {
+        let block = self.block(manager, page);
+        let b = block.as_ref().borrow();
+        (b.bytes[0], link(&b.bytes))
+    }
+
+    fn len(&self, manager: &mut BufferManager<S>, page: u64) -> usize {
+        let block = self.block(manager, page);
+        let b = block.as_ref().borrow();
+        PageView::<K, V>::new(&b.bytes[NODE_HEADER..]).len()
+    }
+
+    // the cells of the node at page, in key order
+    fn entries<T: for<'a> Deserialize<'a>>(
+        &self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+    ) -> Vec<(K, T)> {
+        let block = self.block(manager, page);
+        let b = block.as_ref().borrow();
+        let view: PageView<K, T> = PageView::new(&b.bytes[NODE_HEADER..]);
+        (0..view.len())
+            .map(|i| (view.key(i), view.value(i).unwrap()))
+            .collect()
+    }
+
+    // the slot of k in the leaf at page
+    fn slot_of(&self, manager: &mut BufferManager<S>, page: u64, k: &K) -> Option<usize> {
+        let block = self.block(manager, page);
+        let b = block.as_ref().borrow();
+        let view: PageView<K, V> = PageView::new(&b.bytes[NODE_HEADER..]);
+        let i = view.lower_bound(k);
+        (i < view.len() && view.key(i) == *k).then_some(i)
+    }
+
+    // position of the child of the internal node at page covering k, and its page
+    fn child(&self, manager: &mut BufferManager<S>, page: u64, k: &K) -> (usize, u64) {
+        let block = self.block(manager, page);
+        let b = block.as_ref().borrow();
+        child_for(&b.bytes, k)
+    }
+
+    fn underfilled<T: Serialize + for<'a> Deserialize<'a>>(
+        &self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+    ) -> bool {
+        let block = self.block(manager, page);
+        let mut buf = block.as_ref().borrow().bytes[NODE_HEADER..].to_vec();
+        let free = PageMut::<K, T>::new(&mut buf).free_space();
+        buf.len() - free < self.min_fill()
+    }
+
+    // edits the slotted page of the node at page where it is cached
+    fn edit<T, R>(
+        &self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        f: impl FnOnce(&mut PageMut<K, T>) -> R,
+    ) -> R
+    where
+        T: Serialize + for<'a> Deserialize<'a>,
+    {
+        manager
+            .edit(&self.file, self.offset(page), |buf| {
+                f(&mut PageMut::new(&mut buf[NODE_HEADER..]))
+            })
+            .unwrap_or_else(|| panic!("page {} missing from {}", page, self.file))
+    }
+
+    // a block holding the node, None when its cells do not fit in one
+    fn layout<T>(&self, kind: u8, link: u64, entries: &[(K, T)]) -> Option<Vec<u8>>
+    where
+        T: Serialize + for<'a> Deserialize<'a> + KnowsSize + Clone,
+    {
+        let mut buf = vec![0; self.block_size];
+        buf[0] = kind;
+        buf[1..NODE_HEADER].copy_from_slice(&link.to_le_bytes());
+        let mut p: PageMut<K, T> = PageMut::init(&mut buf[NODE_HEADER..]);
+        for (i, (k, t)) in entries.iter().enumerate() {
+            p.insert(i, k, &Some(t.clone())).ok()?;
+        }
+        Some(buf)
+    }
+
+    fn write_node<T>(
+        &self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        kind: u8,
+        link: u64,
+        entries: &[(K, T)],
+    ) where
+        T: Serialize + for<'a> Deserialize<'a> + KnowsSize + Clone,
+    {
+        let buf = self
+            .layout(kind, link, entries)
+            .unwrap_or_else(|| panic!("node of page {} of {} overflows", page, self.file));
+        manager.write(&self.file, self.offset(page), &buf, self.block_size as u32);
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > KeyValueStore<K, V, S> for BPlusTree<K, V, S>
+{
+    fn get(&self, manager: &mut BufferManager<S>, k: K) -> Option<V> {
+        let mut page = self.root;
+        loop {
+            let block = self.block(manager, page);
+            let b = block.as_ref().borrow();
+            match b.bytes[0] {
+                LEAF => {
+                    let view: PageView<K, V> = PageView::new(&b.bytes[NODE_HEADER..]);
+                    return view.get(&k).flatten();
+                }
+                INTERNAL => page = child_for(&b.bytes, &k).1,
+                _ => panic!("page {} of {} is not a node", page, self.file),
+            }
+        }
+    }
+
+    fn put(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        v: Option<V>,
+    ) -> Result<(), EntryTooLarge> {
+        let Some(v) = v else {
+            self.delete(manager, k);
+            return Ok(());
+        };
+        self.fits(&k, &v)?;
+
+        if let Some((sep, right)) = self.insert(manager, self.root, k, v) {
+            let new_root = self.allocate(manager);
+            self.write_node(manager, new_root, INTERNAL, self.root, &[(sep, right)]);
+            self.root = new_root;
+            self.write_meta(manager);
+        }
+        Ok(())
+    }
+
+    fn fits(&self, k: &K, v: &V) -> Result<(), EntryTooLarge> {
+        let size = entry_size(k, v);
+        if size > self.max_entry() {
+            return Err(EntryTooLarge {
+                size,
+                block_size: self.block_size,
+            });
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, manager: &mut BufferManager<S>, k: K) {
+        self.remove(manager, self.root, &k);
+
+        // an internal root left with a single child hands the root role to it
+        let (kind, first) = self.header(manager, self.root);
+        if kind == INTERNAL && self.len(manager, self.root) == 0 {
+            let old_root = self.root;
+            self.root = first;
+            self.free(manager, old_root);
+            self.write_meta(manager);
+        }
+    }
+
+    // walks the leaves left to right through their sibling links
+    fn scan(&self, manager: &mut BufferManager<S>, range: KeyRange<K>) -> Vec<(K, V)> {
+        let mut page = self.root;
+        loop {
+            let (kind, first) = self.header(manager, page);
+            if kind == LEAF {
+                break;
+            }
+            page = match range.start_bound() {
+                Bound::Included(k) | Bound::Excluded(k) => self.child(manager, page, k).1,
+                Bound::Unbounded => first,
+            };
+        }
+
+        let mut out = Vec::new();
+        while page != NO_PAGE {
+            let (kind, next) = self.header(manager, page);
+            if kind != LEAF {
+                panic!("sibling link of {} points at an internal node", self.file);
+            }
+            for (k, v) in self.entries::<V>(manager, page) {
+                let past_end = match range.end_bound() {
+                    Bound::Included(e) => k > *e,
+                    Bound::Excluded(e) => k >= *e,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    return out;
+                }
+                if range.contains(&k) {
+                    out.push((k, v));
+                }
+            }
+            page = next;
+        }
+        out
+    }
+
+    // pages are updated in place in the buffer pool, so there is nothing held back
+    fn flush(&mut self, _manager: &mut BufferManager<S>) {}
+
+    fn stats(&self, manager: &mut BufferManager<S>) -> StoreStats {
+        let mut height = 1;
+        let mut page = self.root;
+        loop {
+            let (kind, first) = self.header(manager, page);
+            if kind != INTERNAL {
+                break;
+            }
+            height += 1;
+            page = first;
+        }
+
+        let mut free_pages = 0;
+        let mut free = self.free_head;
+        while free != NO_PAGE {
+            free_pages += 1;
+            free = self.header(manager, free).1;
+        }
+
+        StoreStats::BTree(BPlusStats {
+            height,
+            pages: self.next_page as usize - 1,
+            free_pages,
+        })
+    }
+
+    fn destroy(self: Box<Self>, manager: &mut BufferManager<S>) {
+        manager.remove(&self.file);
+        let _ = manager.storage().delete(&self.file);
+    }
+}
+
+fn link(buf: &[u8]) -> u64 {
+    u64::from_le_bytes(buf[1..NODE_HEADER].try_into().unwrap())
+}
+
+// position of the child of an internal node covering k, and its page. Position 0 is the
+// node's first child
+fn child_for<K: Ord + for<'a> Deserialize<'a>>(buf: &[u8], k: &K) -> (usize, u64) {
+    let view: PageView<K, u64> = PageView::new(&buf[NODE_HEADER..]);
+    let mut i = view.lower_bound(k);
+    if i < view.len() && view.key(i) == *k {
+        i += 1;
+    }
+    match i {
+        0 => (0, link(buf)),
+        i => (i, view.value(i - 1).unwrap()),
+    }
+}
+
+// the most bytes an entry takes in a node, counting its slot
+fn entry_size<A: Serialize, B: Serialize>(a: &A, b: &B) -> usize {
+    CELL_OVERHEAD
+        + bincode::serialized_size(a).unwrap() as usize
+        + bincode::serialized_size(b).unwrap() as usize
+}
+
+fn sizes<A: Serialize, B: Serialize>(entries: &[(A, B)]) -> Vec<usize> {
+    entries.iter().map(|(a, b)| entry_size(a, b)).collect()
+}
+
+// index splitting entries into two runs of roughly equal bytes, both non-empty
+fn split_point(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut acc = 0;
+    for (i, s) in sizes.iter().enumerate() {
+        acc += s;
+        if acc * 2 >= total {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{kv_store::Rng, storage::MemStorage};
+
+    type Tree = BPlusTree<u64, String, MemStorage>;
+
+    #[test]
+    fn splits_and_merges_keep_every_entry() {
+        let storage = MemStorage::new();
+        let options = Options::default().cache_size(64 * 4096);
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree: Tree =
+            BPlusTree::with_options("t".to_string(), &mut manager, &options).unwrap();
+        let mut model = BTreeMap::new();
+        let mut rng = Rng(7);
+        let mut height = 0;
+        for op in 0..20000 {
+            let k = rng.below(2000);
+            match rng.below(3) {
+                0 | 1 => {
+                    let v = format!("{}-{}", op, "v".repeat(rng.below(600) as usize));
+                    tree.put(&mut manager, k, Some(v.clone())).unwrap();
+                    model.insert(k, v);
+                }
+                _ => {
+                    tree.delete(&mut manager, k);
+                    model.remove(&k);
+                }
+            }
+            if let StoreStats::BTree(s) = tree.stats(&mut manager) {
+                height = height.max(s.height);
+            }
+        }
+        assert!(height >= 3, "height {}", height);
+        assert!(tree.put(&mut manager, 1, Some("x".repeat(5000))).is_err());
+        manager.flush();
+
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let mut tree: Tree =
+            BPlusTree::with_options("t".to_string(), &mut manager, &options).unwrap();
+        for k in 0..2000 {
+            assert_eq!(tree.get(&mut manager, k).as_ref(), model.get(&k));
+        }
+        let all = tree.scan(&mut manager, (Bound::Included(100), Bound::Excluded(1500)));
+        let expected: Vec<(u64, String)> = model
+            .range(100..1500)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        assert_eq!(all, expected);
+
+        // emptied, the tree hands its pages back to the free list
+        for k in 0..2000 {
+            tree.delete(&mut manager, k);
+        }
+        match tree.stats(&mut manager) {
+            StoreStats::BTree(s) => {
+                assert_eq!(s.height, 1);
+                assert_eq!(s.free_pages, s.pages - 1);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn trees_keep_the_block_size_they_were_written_with() {
+        let storage = MemStorage::new();
+        let options = Options::default()
+            .block_size(16 * 1024)
+            .cache_size(64 * 16 * 1024);
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree: Tree =
+            BPlusTree::with_options("t".to_string(), &mut manager, &options).unwrap();
+        // entries past the limit of a 4 KiB page
+        let value = |k: u64| format!("{}-{}", k, "v".repeat(3000));
+        for k in 0..50 {
+            tree.put(&mut manager, k, Some(value(k))).unwrap();
+        }
+        manager.flush();
+        let len = storage.open("disktables/t", false).unwrap().len().unwrap();
+        assert_eq!(len % (16 * 1024), 0);
+
+        // opened with the default size, the tree goes by its meta page
+        let options = Options::default().cache_size(64 * 16 * 1024);
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let mut tree: Tree =
+            BPlusTree::with_options("t".to_string(), &mut manager, &options).unwrap();
+        for k in 0..50 {
+            assert_eq!(tree.get(&mut manager, k), Some(value(k)));
+        }
+        tree.put(&mut manager, 50, Some(value(50))).unwrap();
+        assert_eq!(tree.get(&mut manager, 50), Some(value(50)));
+        match tree.stats(&mut manager) {
+            StoreStats::BTree(s) => assert!(s.height >= 2, "height {}", s.height),
+            other => panic!("{:?}", other),
+        }
+    }
+}
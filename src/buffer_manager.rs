@@ -1,194 +1,834 @@
 use std::{
     cell::RefCell,
-    collections::VecDeque,
-    fs::OpenOptions,
-    io::{Read, Seek, SeekFrom, Write},
-    os::unix::fs::{FileExt, OpenOptionsExt},
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs::File,
+    io,
     rc::Rc,
 };
 
-use crate::BLOCK_SIZE;
+use crate::{
+    aligned_buf::AlignedBuf,
+    io_backend::{self, IoBackend, IoBackendKind, SyncIo},
+    storage::{FileSystem, Storage, StorageFile},
+};
+
+// the most blocks a walk reads in one batch
+const MAX_READ_BATCH: usize = 32;
+
+// the percentage of cached blocks that may be dirty unless set otherwise
+pub const DEFAULT_DIRTY_RATIO: usize = 20;
+
+// the most dirty blocks trickle writes back at a time
+const WRITEBACK_BATCH: usize = 8;
+
+// how many blocks of a file in a row, each right after the one before, make a read of the
+// next one count as sequential
+const SEQUENTIAL_RUN: usize = 2;
 
-pub const O_DIRECT: i32 = 0o0040000; // Double check value
-pub const O_CREAT: i32 = 0o0000100;
+// how the reader goes on after a block. A sequential read brings the following blocks of
+// the file in with the same batch, so a walk misses once per batch instead of once per
+// block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    // the reader jumps around, unless the buffer manager sees it going block by block
+    Random,
+    // a scan, which reads the blocks after this one next
+    Sequential,
+    // a sequential read of blocks needed only once, like those of a merge or of building
+    // an index. They are evicted first and make room among themselves, so they never push
+    // out the blocks other readers keep coming back to, and reading them again does not
+    // make them recent
+    Background,
+}
 
 #[derive(Debug)]
 pub struct Block {
-    pub bytes: Vec<u8>,
+    // aligned, so the block can be read and written with O_DIRECT
+    pub bytes: AlignedBuf,
     key: (String, usize),
     dirty_bit: bool,
 }
 
-// LRU buffer manager
-pub struct BufferManager {
+#[derive(Debug)]
+pub struct BufferStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub dirty: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    // blocks read ahead of a sequential reader
+    pub prefetched: usize,
+    // dirty blocks written back ahead of eviction, while they stayed cached
+    pub written_back: usize,
+    // writes that waited for dirty blocks to be written back first
+    pub throttled: usize,
+    pub io: &'static str,
+}
+
+// LRU buffer manager. Every file has its own block size, which whoever opens the file
+// registers before reading or writing it. The files live in the storage, which the trees
+// built on the manager reach through it too.
+//
+// Dirty blocks are written back before they reach the cold end where reads evict them,
+// so a read rarely waits for a write. Past half of the dirty ratio, writes hand a batch
+// of the coldest dirty blocks to the I/O backend without waiting. A write that reaches
+// the dirty ratio, counting blocks still on their way to disk, waits until they are all
+// written. There is no writer thread: below half of the ratio blocks are only written
+// back by trickle, which the owner of the manager calls when it is idle, and by flush
+// and eviction
+pub struct BufferManager<S: Storage = FileSystem> {
     pub num_blocks: usize,
     blocks: VecDeque<Rc<RefCell<Block>>>,
+    block_sizes: HashMap<String, usize>,
+    hits: usize,
+    misses: usize,
+    evictions: usize,
+    prefetched: usize,
+    written_back: usize,
+    throttled: usize,
+    dirty_ratio: usize,
+    // how many cached blocks are dirty, kept with their dirty bits
+    dirty: usize,
+    // files written since the last checkpoint
+    unsynced: BTreeSet<String>,
+    // for each file, the block after the last one read and how many were read in a row
+    streams: HashMap<String, (usize, usize)>,
+    // a handle for each file read or written so far, kept until the file is removed
+    files: HashMap<String, Rc<S::File>>,
+    storage: S,
+    io: Box<dyn IoBackend<S::File>>,
 }
 
 impl BufferManager {
     pub fn new(num_blocks: usize) -> Self {
+        Self::with_backend(num_blocks, io_backend::open(IoBackendKind::Uring))
+    }
+
+    pub fn with_backend(num_blocks: usize, io: Box<dyn IoBackend<File>>) -> Self {
+        Self::with_storage_and_backend(num_blocks, FileSystem::default(), io)
+    }
+}
+
+impl<S: Storage> BufferManager<S> {
+    // reads and writes the storage's files one block at a time
+    pub fn with_storage(num_blocks: usize, storage: S) -> Self {
+        Self::with_storage_and_backend(num_blocks, storage, Box::new(SyncIo))
+    }
+
+    pub fn with_storage_and_backend(
+        num_blocks: usize,
+        storage: S,
+        io: Box<dyn IoBackend<S::File>>,
+    ) -> Self {
         let v: VecDeque<Rc<RefCell<Block>>> = VecDeque::with_capacity(num_blocks);
         Self {
-            num_blocks: num_blocks,
+            num_blocks,
             blocks: v,
+            block_sizes: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            prefetched: 0,
+            written_back: 0,
+            throttled: 0,
+            dirty_ratio: DEFAULT_DIRTY_RATIO,
+            dirty: 0,
+            unsynced: BTreeSet::new(),
+            streams: HashMap::new(),
+            files: HashMap::new(),
+            storage,
+            io,
         }
     }
 
-    fn renew(self: &mut Self, index: usize) {
-        let b = self.blocks.remove(index);
-        match b {
-            Some(x) => {
-                self.blocks.push_front(x);
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    // the cached handle of the file, opened on first use. create makes the file when it
+    // does not exist yet
+    fn handle(&mut self, file: &str, create: bool) -> io::Result<Rc<S::File>> {
+        if let Some(fd) = self.files.get(file) {
+            return Ok(fd.clone());
+        }
+        let fd = Rc::new(self.storage.open_direct(file, create)?);
+        self.files.insert(file.to_string(), fd.clone());
+        Ok(fd)
+    }
+
+    fn renew(&mut self, index: usize) {
+        if let Some(b) = self.blocks.remove(index) {
+            self.blocks.push_front(b);
+        }
+    }
+
+    fn add(&mut self, b: Rc<RefCell<Block>>) {
+        self.make_room(1);
+        self.blocks.push_front(b);
+    }
+
+    // adds the blocks read together, the first of them is the one asked for
+    fn add_batch(&mut self, blocks: &[Rc<RefCell<Block>>], access: Access) {
+        // all at once, so the blocks do not evict each other
+        self.make_room(blocks.len());
+        match access {
+            // at the cold end, the first one last, as it is read first and needed least
+            // after that
+            Access::Background => self.blocks.extend(blocks.iter().rev().cloned()),
+            _ => {
+                for b in blocks.iter().rev() {
+                    self.blocks.push_front(b.clone());
+                }
             }
-            None => {}
         }
     }
 
-    fn add(self: &mut Self, b: Rc<RefCell<Block>>) {
-        if self.blocks.len() == self.num_blocks {
+    fn make_room(&mut self, n: usize) {
+        while !self.blocks.is_empty() && self.blocks.len() + n > self.num_blocks {
             // if page is dirty write it out to disk
             let block = self.blocks.pop_back().unwrap();
-            let filepath = &block.borrow().key.0;
+            self.evictions += 1;
             let dirty_bit = block.borrow().dirty_bit;
 
+            // written back without waiting, a read of the block waits for it instead
             if dirty_bit {
-                let mut fd = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .custom_flags(O_DIRECT)
-                    .open(filepath)
-                    .unwrap();
-                let offset = block.borrow().key.1;
-                let block_offset = offset - (offset % BLOCK_SIZE);
-
-                let _n = fd
-                    .write_at(&block.borrow().bytes, block_offset as u64)
-                    .unwrap();
-                fd.flush().unwrap();
-
-                let mut s = Vec::new();
-                fd.seek(SeekFrom::Start(0)).unwrap();
-                fd.read_to_end(&mut s).unwrap();
+                self.dirty -= 1;
+                self.submit(&block.borrow());
             }
         }
+    }
 
-        self.blocks.push_front(b);
+    fn submit(&mut self, block: &Block) {
+        let (file, offset) = &block.key;
+        let fd = match self.handle(file, false) {
+            Ok(fd) => fd,
+            Err(e) => panic!("cannot write back {}: {}", file, e),
+        };
+        self.unsynced.insert(file.clone());
+        self.io.write(file, fd, *offset as u64, block.bytes.clone());
     }
 
-    pub fn get(self: &mut Self, file: String, offset: usize) -> Option<Rc<RefCell<Block>>> {
-        let block_offset = offset - (offset % BLOCK_SIZE);
-        match self.blocks.iter().position(|x| {
-            return x.as_ref().borrow().key.0 == file.clone()
-                && x.as_ref().borrow().key.1 == block_offset;
-        }) {
-            Some(x) => {
-                self.renew(x);
-                return Some(self.blocks[0].clone());
+    // hands up to n of the coldest dirty blocks to the I/O backend, which they stay
+    // cached and clean for, and returns how many there were
+    fn write_back(&mut self, n: usize) -> usize {
+        let dirty: Vec<Rc<RefCell<Block>>> = self
+            .blocks
+            .iter()
+            .rev()
+            .filter(|b| b.as_ref().borrow().dirty_bit)
+            .take(n)
+            .cloned()
+            .collect();
+        for block in dirty.iter() {
+            let mut b = block.as_ref().borrow_mut();
+            self.submit(&b);
+            b.dirty_bit = false;
+            self.dirty -= 1;
+        }
+        dirty.len()
+    }
+
+    // writes back a batch of the coldest dirty blocks without waiting for them. The
+    // manager only calls it from writes past half of the dirty ratio, so owners call it
+    // between requests to keep the dirty blocks below that
+    pub fn trickle(&mut self) {
+        self.written_back += self.write_back(WRITEBACK_BATCH);
+    }
+
+    // after a write, starts writing back past half of the dirty ratio and holds the
+    // writer up at the ratio itself. Blocks on their way to disk still count, as the
+    // backend holds a copy of each until its write completes
+    fn balance_dirty(&mut self) {
+        let dirty = self.dirty;
+        let max_dirty = (self.num_blocks * self.dirty_ratio / 100).max(1);
+        let background = max_dirty / 2;
+        if dirty + self.io.pending() >= max_dirty {
+            self.throttled += 1;
+            self.written_back += self.write_back(dirty.saturating_sub(background));
+            self.io.wait_all();
+        } else if dirty > background {
+            self.written_back += self.write_back(WRITEBACK_BATCH.min(dirty - background));
+        }
+    }
+
+    // the percentage of cached blocks that may be dirty before writes wait
+    pub fn set_dirty_ratio(&mut self, percent: usize) {
+        self.dirty_ratio = percent;
+    }
+
+    pub fn get(&mut self, file: String, offset: usize) -> Option<Rc<RefCell<Block>>> {
+        self.get_with(file, offset, Access::Random)
+    }
+
+    // like get, for a reader that says how it goes on
+    pub fn get_with(
+        &mut self,
+        file: String,
+        offset: usize,
+        access: Access,
+    ) -> Option<Rc<RefCell<Block>>> {
+        let block_size = self.registered_block_size(&file);
+        let block_offset = offset - (offset % block_size);
+        let access = self.follow(&file, block_offset, block_size, access);
+        if let Some(block) = self.cached(&file, block_offset, access) {
+            return Some(block);
+        }
+
+        // read from disk, along with the following blocks for a sequential reader. A file
+        // that was never written has no blocks
+        let fd = match self.handle(&file, false) {
+            Ok(fd) => fd,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.misses += 1;
+                return None;
             }
-            None => {
-                // read from disk
-                let fd = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .custom_flags(O_DIRECT)
-                    .open(&file)
-                    .unwrap();
-
-                let len = fd.metadata().unwrap().len();
-                if (block_offset + BLOCK_SIZE) as u64 > len {
-                    return None;
-                }
+            Err(e) => panic!("cannot open {}: {}", file, e),
+        };
+        let ahead = match access {
+            Access::Random => 1,
+            _ => self.read_batch_len(),
+        };
+        let offsets: Vec<usize> = (0..ahead).map(|i| block_offset + i * block_size).collect();
+        for o in offsets.iter() {
+            self.io.wait_for(&file, *o as u64);
+        }
+        let len = fd.len().unwrap() as usize;
+        let offsets: Vec<usize> = offsets
+            .into_iter()
+            .filter(|o| o + block_size <= len)
+            .filter(|o| *o == block_offset || !self.is_cached(&file, *o))
+            .collect();
+        if offsets.first() != Some(&block_offset) {
+            self.misses += 1;
+            return None;
+        }
 
-                let mut buf = [0; BLOCK_SIZE];
-                fd.read_at(&mut buf, block_offset as u64).unwrap();
+        let reads: Vec<(u64, usize)> = offsets.iter().map(|o| (*o as u64, block_size)).collect();
+        let bufs = self.io.read_batch(&fd, &reads);
+        let blocks = offsets.into_iter().zip(bufs).collect();
+        self.insert(&file, blocks, access).into_iter().next()
+    }
 
-                let new_block = Rc::new(RefCell::new(Block {
-                    bytes: buf.to_vec(),
-                    key: (file, block_offset),
-                    dirty_bit: false,
-                }));
+    // counts a read of a block as a hit when it is cached. A background read leaves it
+    // where it is in the LRU order
+    pub fn cached(
+        &mut self,
+        file: &str,
+        offset: usize,
+        access: Access,
+    ) -> Option<Rc<RefCell<Block>>> {
+        let x = self.blocks.iter().position(|x| {
+            let b = x.as_ref().borrow();
+            b.key.0 == file && b.key.1 == offset
+        })?;
+        self.hits += 1;
+        if access == Access::Background {
+            return Some(self.blocks[x].clone());
+        }
+        self.renew(x);
+        Some(self.blocks[0].clone())
+    }
 
-                self.add(new_block.clone());
+    // adds blocks that missed the cache and were read together, the first is the one asked
+    // for and the others were read ahead. Files that store their blocks in another form
+    // load them themselves and add them here, such blocks must not be written to, they are
+    // never written back
+    pub fn insert(
+        &mut self,
+        file: &str,
+        blocks: Vec<(usize, AlignedBuf)>,
+        access: Access,
+    ) -> Vec<Rc<RefCell<Block>>> {
+        self.misses += 1;
+        self.prefetched += blocks.len().saturating_sub(1);
+        let blocks: Vec<Rc<RefCell<Block>>> = blocks
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Rc::new(RefCell::new(Block {
+                    bytes,
+                    key: (file.to_string(), offset),
+                    dirty_bit: false,
+                }))
+            })
+            .collect();
+        self.add_batch(&blocks, access);
+        blocks
+    }
 
-                return Some(new_block);
+    // notes the read in the file's stream, a random read that carries on a long enough
+    // run of blocks is taken as sequential
+    fn follow(
+        &mut self,
+        file: &str,
+        block_offset: usize,
+        block_size: usize,
+        access: Access,
+    ) -> Access {
+        let run = match self.streams.get_mut(file) {
+            Some(stream) => {
+                *stream = match stream.0 == block_offset {
+                    true => (block_offset + block_size, stream.1 + 1),
+                    false => (block_offset + block_size, 0),
+                };
+                stream.1
+            }
+            None => {
+                self.streams
+                    .insert(file.to_string(), (block_offset + block_size, 0));
+                0
             }
+        };
+        match access {
+            Access::Random if run >= SEQUENTIAL_RUN => Access::Sequential,
+            access => access,
         }
     }
 
-    pub fn rename(self: &mut Self, from: &String, to: &String) {
-        self.blocks
-            .retain(|x| x.as_ref().borrow().key.0 != to.clone());
+    pub fn is_cached(&self, file: &str, offset: usize) -> bool {
+        self.blocks.iter().any(|x| {
+            let b = x.as_ref().borrow();
+            b.key.0 == file && b.key.1 == offset
+        })
+    }
+
+    // reads the byte ranges of a file the buffer manager does not write, in one batch
+    pub fn read_batch(&mut self, file: &S::File, reads: &[(u64, usize)]) -> Vec<AlignedBuf> {
+        self.io.read_batch(file, reads)
+    }
+
+    // how many blocks a walk should read at once, few enough that they do not push each
+    // other out of the cache
+    pub fn read_batch_len(&self) -> usize {
+        (self.num_blocks / 4).clamp(1, MAX_READ_BATCH)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) {
+        self.drop_blocks(to);
+        self.streams.remove(from);
+        self.streams.remove(to);
+        if self.unsynced.remove(from) {
+            self.unsynced.insert(to.to_string());
+        }
+        if let Some(size) = self.block_sizes.remove(from) {
+            self.block_sizes.insert(to.to_string(), size);
+        }
+        // a handle follows the file it was opened on
+        self.files.remove(to);
+        if let Some(fd) = self.files.remove(from) {
+            self.files.insert(to.to_string(), fd);
+        }
 
         // TODO: this is probably a map operation
         let thing = self.blocks.iter();
         for t in thing {
             let mut a = t.as_ref().borrow_mut();
-            if a.key.0 == from.clone() {
-                a.key.0 = to.clone();
+            if a.key.0 == from {
+                a.key.0 = to.to_string();
+            }
+        }
+    }
+
+    // forgets the cached blocks of a file, dirty or not
+    fn drop_blocks(&mut self, file: &str) {
+        let dirty = &mut self.dirty;
+        self.blocks.retain(|x| {
+            let b = x.as_ref().borrow();
+            let keep = b.key.0 != file;
+            if !keep && b.dirty_bit {
+                *dirty -= 1;
             }
+            keep
+        });
+    }
+
+    pub fn set_block_size(&mut self, file: &str, block_size: usize) {
+        self.block_sizes.insert(file.to_string(), block_size);
+    }
+
+    // None until the file is registered
+    pub fn block_size(&self, file: &str) -> Option<usize> {
+        self.block_sizes.get(file).copied()
+    }
+
+    fn registered_block_size(&self, file: &str) -> usize {
+        match self.block_sizes.get(file) {
+            Some(size) => *size,
+            None => panic!("the block size of {} is not known", file),
         }
     }
 
-    pub fn write(self: &mut Self, file: &String, offset: usize, buf: &Vec<u8>, buf_size: u32) {
-        let block_offset = offset - (offset % BLOCK_SIZE);
-        self.get(file.clone(), block_offset);
+    // drops every cached block of a file without writing it back, used once the file is deleted
+    pub fn remove(&mut self, file: &str) {
+        self.drop_blocks(file);
+        self.block_sizes.remove(file);
+        self.streams.remove(file);
+        self.unsynced.remove(file);
+        self.files.remove(file);
+    }
+
+    pub fn write(&mut self, file: &str, offset: usize, buf: &[u8], buf_size: u32) {
+        let block_size = self.registered_block_size(file);
+        let block_offset = offset - (offset % block_size);
+        self.get(file.to_string(), block_offset);
 
         match self.blocks.iter().position(|x| {
-            return x.as_ref().borrow().key == (file.clone(), block_offset);
+            let b = x.as_ref().borrow();
+            b.key.0 == file && b.key.1 == block_offset
         }) {
             Some(x) => {
                 let mut block = self.blocks[x].as_ref().borrow_mut();
-                let in_block_offset = offset % BLOCK_SIZE;
+                let in_block_offset = offset % block_size;
 
                 block.bytes[in_block_offset..in_block_offset + buf_size as usize]
                     .copy_from_slice(buf);
+                self.dirty += !block.dirty_bit as usize;
                 block.dirty_bit = true;
             }
             None => {
-                if block_offset == 0 {
-                    // try to create the file
-                    // TODO: do this properly
-                    let _fd = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .create(true)
-                        .custom_flags(O_DIRECT)
-                        .open(&file)
-                        .unwrap();
+                // the block is written back to the file, which may not exist yet
+                if let Err(e) = self.handle(file, true) {
+                    panic!("cannot create {}: {}", file, e);
                 }
-                // create new page in file?
+                // create new page in file? A whole block, as O_DIRECT writes no less
+                let mut bytes = AlignedBuf::zeroed(block_size);
+                let in_block_offset = offset % block_size;
+                bytes[in_block_offset..in_block_offset + buf_size as usize].copy_from_slice(buf);
                 let new_block = Rc::new(RefCell::new(Block {
-                    bytes: buf.clone(),
+                    bytes,
                     dirty_bit: true,
-                    key: (file.clone(), block_offset),
+                    key: (file.to_string(), block_offset),
                 }));
                 self.add(new_block);
+                self.dirty += 1;
             }
         }
+        self.balance_dirty();
     }
 
-    pub fn flush(self: &mut Self) {
-        for block_ref in self.blocks.iter_mut() {
-            let mut b = block_ref.as_ref().borrow_mut();
-            if b.dirty_bit {
-                let mut fd = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .custom_flags(O_DIRECT)
-                    .open(b.key.0.clone())
-                    .unwrap();
-                let offset = b.key.1;
-                let block_offset = offset - (offset % BLOCK_SIZE);
+    // runs f on the bytes of a block where they are cached and marks the block dirty, for
+    // files that edit their pages in place. None when the block is not in the file
+    pub fn edit<T>(
+        &mut self,
+        file: &str,
+        offset: usize,
+        f: impl FnOnce(&mut [u8]) -> T,
+    ) -> Option<T> {
+        let block = self.get(file.to_string(), offset)?;
+        let result = {
+            let mut b = block.as_ref().borrow_mut();
+            self.dirty += !b.dirty_bit as usize;
+            b.dirty_bit = true;
+            f(&mut b.bytes)
+        };
+        self.balance_dirty();
+        Some(result)
+    }
 
-                let _n = fd.write_at(&b.bytes, block_offset as u64).unwrap();
-                fd.flush().unwrap();
+    pub fn stats(&self) -> BufferStats {
+        BufferStats {
+            capacity: self.num_blocks,
+            cached: self.blocks.len(),
+            dirty: self.dirty,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            prefetched: self.prefetched,
+            written_back: self.written_back,
+            throttled: self.throttled,
+            io: self.io.name(),
+        }
+    }
+
+    // submits every dirty block together and returns once all of them are written
+    pub fn flush(&mut self) {
+        self.write_back(self.blocks.len());
+        self.io.wait_all();
+    }
 
-                let mut s = Vec::new();
-                fd.seek(SeekFrom::Start(0)).unwrap();
-                fd.read_to_end(&mut s).unwrap();
+    // flushes and then syncs every file written since the last checkpoint, so all of
+    // it survives a crash
+    pub fn checkpoint(&mut self) {
+        self.flush();
+        for file in std::mem::take(&mut self.unsynced) {
+            // a file removed since has nothing left to sync
+            if let Some(fd) = self.files.get(&file) {
+                fd.sync().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemFile, MemStorage};
+
+    const BLOCK: usize = 4096;
+
+    #[test]
+    fn reads_of_missing_files_find_nothing_and_create_nothing() {
+        let storage = MemStorage::new();
+        let mut manager = BufferManager::with_storage(8, storage.clone());
+        let file = "dir/blocks".to_string();
+        manager.set_block_size(&file, BLOCK);
+        assert!(manager.get(file.clone(), 0).is_none());
+        assert!(storage.open(&file, false).is_err());
+
+        manager.write(&file, BLOCK + 3, &[7; 4], 4);
+        manager.flush();
+        assert_eq!(
+            storage.open(&file, false).unwrap().len().unwrap(),
+            2 * BLOCK as u64
+        );
+        assert_eq!(
+            manager.get(file.clone(), BLOCK).unwrap().borrow().bytes[3],
+            7
+        );
+
+        // a file created again after it was removed is read through a new handle
+        manager.remove(&file);
+        storage.delete(&file).unwrap();
+        manager.set_block_size(&file, BLOCK);
+        assert!(manager.get(file.clone(), BLOCK).is_none());
+        manager.write(&file, 0, &[9; 4], 4);
+        manager.flush();
+        let mut manager = BufferManager::with_storage(8, storage);
+        manager.set_block_size(&file, BLOCK);
+        assert_eq!(manager.get(file.clone(), 0).unwrap().borrow().bytes[0], 9);
+    }
+
+    fn counted_dirty<S: Storage>(manager: &BufferManager<S>) -> usize {
+        manager
+            .blocks
+            .iter()
+            .filter(|b| b.borrow().dirty_bit)
+            .count()
+    }
+
+    #[test]
+    fn the_dirty_count_follows_the_dirty_bits() {
+        let storage = MemStorage::new();
+        let mut manager = BufferManager::with_storage(16, storage.clone());
+        manager.set_dirty_ratio(50);
+        let files = ["a", "b", "c"].map(|f| f.to_string());
+        for f in files.iter() {
+            manager.set_block_size(f, BLOCK);
+        }
+        let mut rng = crate::kv_store::Rng(42);
+        for i in 0..2000 {
+            let file = &files[rng.below(3) as usize];
+            let offset = rng.below(24) as usize * BLOCK;
+            match rng.below(10) {
+                0..=5 => manager.write(file, offset, &[i as u8; 8], 8),
+                6 | 7 => {
+                    manager.edit(file, offset, |bytes| bytes[0] = i as u8);
+                }
+                8 => manager.trickle(),
+                _ if i % 7 == 0 => {
+                    manager.rename(&files[0], &files[2]);
+                    storage.rename(&files[0], &files[2]).ok();
+                    manager.set_block_size(&files[0], BLOCK);
+                }
+                _ => {
+                    manager.remove(file);
+                    storage.delete(file).ok();
+                    manager.set_block_size(file, BLOCK);
+                }
+            }
+            assert_eq!(manager.stats().dirty, counted_dirty(&manager));
+        }
+        manager.checkpoint();
+        assert_eq!(manager.stats().dirty, 0);
+    }
+
+    // a manager of capacity blocks over a file of n blocks, and of 4 hot ones read once,
+    // backwards so they do not look like a scan
+    fn with_files(capacity: usize, n: usize) -> BufferManager<MemStorage> {
+        let storage = MemStorage::new();
+        let mut manager = BufferManager::with_storage(capacity, storage.clone());
+        for (file, n) in [("scan", n), ("hot", 4)] {
+            manager.set_block_size(file, BLOCK);
+            for i in 0..n {
+                manager.write(file, i * BLOCK, &[i as u8; 4], 4);
+            }
+        }
+        manager.flush();
+        let mut manager = BufferManager::with_storage(capacity, storage);
+        for file in ["scan", "hot"] {
+            manager.set_block_size(file, BLOCK);
+        }
+        for i in (0..4).rev() {
+            manager.get("hot".to_string(), i * BLOCK).unwrap();
+        }
+        manager
+    }
+
+    #[test]
+    fn sequential_readers_get_the_next_blocks_with_the_same_read() {
+        let mut manager = with_files(32, 40);
+        let batch = manager.read_batch_len();
+        assert_eq!(batch, 8);
+        let scan = |m: &mut BufferManager<MemStorage>, i: usize, access| {
+            let block = m.get_with("scan".to_string(), i * BLOCK, access).unwrap();
+            assert_eq!(block.borrow().bytes[0], i as u8);
+        };
+
+        scan(&mut manager, 0, Access::Sequential);
+        assert_eq!(manager.stats().prefetched, batch - 1);
+        assert!(manager.is_cached("scan", (batch - 1) * BLOCK));
+        assert!(!manager.is_cached("scan", batch * BLOCK));
+        let misses = manager.stats().misses;
+        for i in 1..batch {
+            scan(&mut manager, i, Access::Sequential);
+        }
+        assert_eq!(manager.stats().misses, misses);
+
+        // random reads block after block are taken as sequential once the run is long
+        // enough, the read at the end of the file brings in only what is left
+        for i in 30..30 + SEQUENTIAL_RUN {
+            scan(&mut manager, i, Access::Random);
+        }
+        assert_eq!(manager.stats().prefetched, batch - 1);
+        scan(&mut manager, 30 + SEQUENTIAL_RUN, Access::Random);
+        let ahead = 40 - (30 + SEQUENTIAL_RUN) - 1;
+        assert_eq!(manager.stats().prefetched, batch - 1 + ahead);
+        assert!(manager.is_cached("scan", 39 * BLOCK));
+    }
+
+    #[test]
+    fn background_reads_evict_each_other_before_anything_else() {
+        let hot_left = |access| {
+            let mut manager = with_files(16, 40);
+            for i in 0..40 {
+                manager.get_with("scan".to_string(), i * BLOCK, access);
+            }
+            assert!(manager.stats().evictions > 0);
+            (0..4)
+                .filter(|i| manager.is_cached("hot", i * BLOCK))
+                .count()
+        };
+        assert_eq!(hot_left(Access::Sequential), 0);
+        assert_eq!(hot_left(Access::Background), 4);
+
+        // reading a block in the background again leaves it at the cold end
+        let mut manager = with_files(8, 4);
+        manager.get_with("scan".to_string(), 0, Access::Background);
+        for _ in 0..3 {
+            manager.get_with("scan".to_string(), 0, Access::Background);
+        }
+        assert!(manager.is_cached("scan", 0));
+        manager.get("scan".to_string(), 3 * BLOCK);
+        manager.get("hot".to_string(), 0);
+        manager.set_block_size("other", BLOCK);
+        for i in 0..4 {
+            manager.write("other", i * BLOCK, &[0; 4], 4);
+        }
+        assert!(!manager.is_cached("scan", 0));
+        assert!(manager.is_cached("scan", 3 * BLOCK));
+    }
+
+    type InFlight = Rc<RefCell<Vec<(String, Rc<MemFile>, u64, AlignedBuf)>>>;
+
+    // holds every write in flight until it is waited for
+    struct Deferred(InFlight);
+
+    impl IoBackend<MemFile> for Deferred {
+        fn read_batch(&mut self, file: &MemFile, reads: &[(u64, usize)]) -> Vec<AlignedBuf> {
+            SyncIo.read_batch(file, reads)
+        }
+
+        fn write(&mut self, path: &str, file: Rc<MemFile>, offset: u64, data: AlignedBuf) {
+            self.0
+                .borrow_mut()
+                .push((path.to_string(), file, offset, data));
+        }
+
+        fn wait_for(&mut self, path: &str, offset: u64) {
+            let mut in_flight = self.0.borrow_mut();
+            while let Some(i) = in_flight
+                .iter()
+                .position(|(p, _, o, _)| p == path && *o == offset)
+            {
+                let (_, file, offset, data) = in_flight.remove(i);
+                file.write_at(&data, offset).unwrap();
+            }
+        }
+
+        fn wait_all(&mut self) {
+            for (_, file, offset, data) in self.0.borrow_mut().drain(..) {
+                file.write_at(&data, offset).unwrap();
             }
-            b.dirty_bit = false;
         }
+
+        fn pending(&mut self) -> usize {
+            self.0.borrow().len()
+        }
+
+        fn name(&self) -> &'static str {
+            "deferred"
+        }
+    }
+
+    #[test]
+    fn writes_wait_at_the_dirty_ratio_counting_blocks_in_flight() {
+        let in_flight = InFlight::default();
+        let backend = Box::new(Deferred(in_flight.clone()));
+        let mut manager = BufferManager::with_storage_and_backend(20, MemStorage::new(), backend);
+        manager.set_dirty_ratio(50);
+        manager.set_block_size("f", BLOCK);
+        let (max_dirty, background) = (10, 5);
+
+        // up to half of the ratio blocks stay dirty and nothing is written
+        for i in 0..background {
+            manager.write("f", i * BLOCK, &[1; 4], 4);
+        }
+        assert_eq!(manager.stats().dirty, background);
+        assert_eq!(in_flight.borrow().len(), 0);
+
+        // past it each write sends the coldest dirty block on its way, until the blocks
+        // in flight reach the ratio and a write waits for all of them
+        for i in background..20 {
+            manager.write("f", i * BLOCK, &[1; 4], 4);
+            let stats = manager.stats();
+            assert_eq!(stats.dirty, background);
+            assert!(stats.dirty + in_flight.borrow().len() < max_dirty);
+        }
+        let stats = manager.stats();
+        assert!(stats.throttled > 0);
+        assert_eq!(stats.written_back, 20 - background);
+        assert_eq!(stats.evictions, 0);
+    }
+
+    #[test]
+    fn trickle_writes_back_the_coldest_dirty_blocks_a_batch_at_a_time() {
+        let storage = MemStorage::new();
+        let mut manager = BufferManager::with_storage(100, storage.clone());
+        manager.set_block_size("f", BLOCK);
+        let n = WRITEBACK_BATCH + 2;
+        for i in 0..n {
+            manager.write("f", i * BLOCK, &[i as u8 + 1; 4], 4);
+        }
+        assert_eq!(manager.stats().written_back, 0);
+        let on_disk = |i: usize| {
+            let mut buf = [0; 4];
+            let file = storage.open("f", false).unwrap();
+            file.read_at(&mut buf, (i * BLOCK) as u64).is_ok() && buf[0] == i as u8 + 1
+        };
+
+        manager.trickle();
+        assert_eq!(manager.stats().dirty, n - WRITEBACK_BATCH);
+        assert!((0..WRITEBACK_BATCH).all(on_disk));
+        assert!(!on_disk(n - 1));
+        manager.trickle();
+        manager.trickle();
+        let stats = manager.stats();
+        assert_eq!((stats.dirty, stats.written_back), (0, n));
+        assert!((0..n).all(on_disk));
+        // the blocks stay cached, clean
+        assert_eq!(stats.cached, n);
+        let misses = stats.misses;
+        manager.get("f".to_string(), 0).unwrap();
+        assert_eq!(manager.stats().misses, misses);
     }
 }
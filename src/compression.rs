@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+// how disktable pages are compressed on disk. The buffer manager caches them
+// decompressed, so only reads from disk pay for it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+// zstd's own default, a fair trade of ratio for speed
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    // the codec a file records in its header
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Snappy => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Snappy),
+            3 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => raw.to_vec(),
+            Compression::Lz4 => lz4_flex::compress(raw),
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(raw).unwrap(),
+            Compression::Zstd => zstd::bulk::compress(raw, ZSTD_LEVEL).unwrap(),
+        }
+    }
+
+    // raw_len is the length of the data before it was compressed
+    pub fn decompress(&self, data: &[u8], raw_len: usize) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::decompress(data, raw_len).unwrap(),
+            Compression::Snappy => snap::raw::Decoder::new().decompress_vec(data).unwrap(),
+            Compression::Zstd => zstd::bulk::decompress(data, raw_len).unwrap(),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::key_encoding;
+
+// how disktable pages lay out their entries, recorded in the file header
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PageFormat {
+    // slotted pages, every key stored whole
+    Slotted,
+    // prefix data blocks, keys share their prefix with the key before them
+    Prefix,
+}
+
+impl PageFormat {
+    pub fn id(&self) -> u8 {
+        match self {
+            PageFormat::Slotted => 0,
+            PageFormat::Prefix => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(PageFormat::Slotted),
+            1 => Some(PageFormat::Prefix),
+            _ => None,
+        }
+    }
+}
+
+/*
+Prefix data block format:
+| entry 0 | entry 1 | ... | free space | restart 0 u32 | ... | restart n-1 u32 | entries end u32 | restart count u32 |
+An entry is
+| shared u16 | unshared u16 | value len u32 | key bytes after the shared ones | value |
+where the key is in the key encoding and shared counts the bytes it starts with that the
+key before it starts with too. Every restart interval entries a key is stored whole, with
+shared 0, and the offset of its entry goes in the restart array. Values are bincode.
+*/
+
+const ENTRY_HEADER: usize = 8;
+const TRAILER: usize = 8;
+const RESTART: usize = 4;
+
+// fills a block with entries given in key order, like a SlottedPage does
+pub struct DataBlockBuilder<K, V> {
+    entries: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    pub num_cells: usize,
+    block_size: usize,
+    restart_interval: usize,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<K: Serialize, V: Serialize> DataBlockBuilder<K, V> {
+    pub fn new(block_size: usize, restart_interval: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            num_cells: 0,
+            block_size,
+            restart_interval,
+            _types: PhantomData,
+        }
+    }
+
+    // hands the entry back when the block has no room for it
+    pub fn add_cell(&mut self, k: K, v: Option<V>) -> Result<(), (K, Option<V>)> {
+        let key = key_encoding::to_bytes(&k).unwrap();
+        let value = bincode::serialize(&v).unwrap();
+        let restart = self.num_cells.is_multiple_of(self.restart_interval);
+        let shared = match restart {
+            true => 0,
+            false => key
+                .iter()
+                .zip(self.last_key.iter())
+                .take_while(|(a, b)| a == b)
+                .count()
+                .min(u16::MAX as usize),
+        };
+        let unshared = key.len() - shared;
+
+        let trailer = (self.restarts.len() + restart as usize) * RESTART + TRAILER;
+        let entry = ENTRY_HEADER + unshared + value.len();
+        if unshared > u16::MAX as usize || self.entries.len() + entry + trailer > self.block_size {
+            return Err((k, v));
+        }
+
+        if restart {
+            self.restarts.push(self.entries.len() as u32);
+        }
+        self.entries.extend((shared as u16).to_le_bytes());
+        self.entries.extend((unshared as u16).to_le_bytes());
+        self.entries.extend((value.len() as u32).to_le_bytes());
+        self.entries.extend(&key[shared..]);
+        self.entries.extend(value);
+        self.last_key = key;
+        self.num_cells += 1;
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut block = vec![0; self.block_size];
+        block[..self.entries.len()].copy_from_slice(&self.entries);
+        let mut at = self.block_size - self.restarts.len() * RESTART - TRAILER;
+        for r in self.restarts.iter() {
+            block[at..at + RESTART].copy_from_slice(&r.to_le_bytes());
+            at += RESTART;
+        }
+        block[at..at + 4].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        block[at + 4..at + 8].copy_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        block
+    }
+}
+
+// reads entries straight out of an encoded block
+pub struct DataBlock<'a> {
+    bytes: &'a [u8],
+    entries_end: usize,
+    restarts_start: usize,
+    restart_count: usize,
+}
+
+impl<'a> DataBlock<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let trailer = bytes.len() - TRAILER;
+        let entries_end = read_u32(bytes, trailer) as usize;
+        let restart_count = read_u32(bytes, trailer + 4) as usize;
+        Self {
+            bytes,
+            entries_end,
+            restarts_start: trailer - restart_count * RESTART,
+            restart_count,
+        }
+    }
+
+    fn restart(&self, i: usize) -> usize {
+        read_u32(self.bytes, self.restarts_start + i * RESTART) as usize
+    }
+
+    // puts the key of the entry at offset into key, which holds the key before it, and
+    // returns the value and where the next entry starts
+    fn entry(&self, offset: usize, key: &mut Vec<u8>) -> (&'a [u8], usize) {
+        let shared = u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]]) as usize;
+        let unshared =
+            u16::from_le_bytes([self.bytes[offset + 2], self.bytes[offset + 3]]) as usize;
+        let value_len = read_u32(self.bytes, offset + 4) as usize;
+        let key_start = offset + ENTRY_HEADER;
+        key.truncate(shared);
+        key.extend(&self.bytes[key_start..key_start + unshared]);
+        let value_start = key_start + unshared;
+        let next = value_start + value_len;
+        (&self.bytes[value_start..next], next)
+    }
+
+    // every entry from the restart point on, as key encoding and value bytes
+    fn entries_from(&self, restart: usize) -> impl Iterator<Item = (Vec<u8>, &'a [u8])> + '_ {
+        let mut offset = match restart < self.restart_count {
+            true => self.restart(restart),
+            false => self.entries_end,
+        };
+        let mut key = Vec::new();
+        std::iter::from_fn(move || {
+            if offset >= self.entries_end {
+                return None;
+            }
+            let (value, next) = self.entry(offset, &mut key);
+            offset = next;
+            Some((key.clone(), value))
+        })
+    }
+
+    // the first entry at or after target. Binary searches the restart points for the last
+    // one at or before it, then decodes keys from there on
+    pub fn seek<K: for<'b> Deserialize<'b> + Ord, V: for<'b> Deserialize<'b>>(
+        &self,
+        target: &K,
+    ) -> Option<(K, Option<V>)> {
+        let (mut lo, mut hi) = (0, self.restart_count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let mut key = Vec::new();
+            self.entry(self.restart(mid), &mut key);
+            let k: K = key_encoding::from_bytes(&key).unwrap();
+            if k <= *target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        for (key, value) in self.entries_from(lo.saturating_sub(1)) {
+            let k: K = key_encoding::from_bytes(&key).unwrap();
+            if k >= *target {
+                return Some((k, bincode::deserialize(value).unwrap()));
+            }
+        }
+        None
+    }
+
+    pub fn decode<K: for<'b> Deserialize<'b> + Ord, V: for<'b> Deserialize<'b>>(
+        &self,
+    ) -> BTreeMap<K, Option<V>> {
+        self.entries_from(0)
+            .map(|(key, value)| {
+                (
+                    key_encoding::from_bytes(&key).unwrap(),
+                    bincode::deserialize(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 4096;
+
+    fn paths(n: usize) -> Vec<String> {
+        (0..n)
+            .map(|i| format!("/home/user/projects/{:03}/src/{}.rs", i / 10, i % 10))
+            .collect()
+    }
+
+    fn build(keys: &[String], restart_interval: usize) -> Vec<u8> {
+        let mut builder = DataBlockBuilder::new(BLOCK, restart_interval);
+        for (i, k) in keys.iter().enumerate() {
+            let v = (i % 3 != 0).then_some(i as u64);
+            builder.add_cell(k.clone(), v).unwrap();
+        }
+        builder.encode()
+    }
+
+    #[test]
+    fn blocks_give_back_every_entry() {
+        let keys = paths(40);
+        for restart_interval in [1, 4, 16, 100] {
+            let block = build(&keys, restart_interval);
+            let entries: BTreeMap<String, Option<u64>> = DataBlock::new(&block).decode();
+            assert_eq!(entries.len(), keys.len());
+            for (i, k) in keys.iter().enumerate() {
+                assert_eq!(entries[k], (i % 3 != 0).then_some(i as u64));
+            }
+        }
+    }
+
+    #[test]
+    fn seek_finds_the_first_entry_at_or_after_a_key() {
+        let keys = paths(40);
+        for restart_interval in [1, 3, 16] {
+            let block = build(&keys, restart_interval);
+            let block = DataBlock::new(&block);
+            for (i, k) in keys.iter().enumerate() {
+                let (found, v) = block.seek::<String, u64>(k).unwrap();
+                assert_eq!((&found, v), (k, (i % 3 != 0).then_some(i as u64)));
+
+                // a key just past this one lands on the next
+                let after = format!("{}\0", k);
+                let next = block.seek::<String, u64>(&after).map(|(k, _)| k);
+                assert_eq!(next.as_ref(), keys.get(i + 1));
+            }
+            assert_eq!(
+                block.seek::<String, u64>(&String::new()).unwrap().0,
+                keys[0]
+            );
+            assert!(block.seek::<String, u64>(&"/zzz".to_string()).is_none());
+        }
+    }
+
+    #[test]
+    fn shared_prefixes_fit_more_keys() {
+        let keys = paths(1000);
+        let mut prefix = DataBlockBuilder::new(BLOCK, 16);
+        let mut whole = DataBlockBuilder::new(BLOCK, 1);
+        for k in keys.iter() {
+            let _ = prefix.add_cell(k.clone(), Some(0u64));
+            let _ = whole.add_cell(k.clone(), Some(0u64));
+        }
+        assert!(prefix.num_cells > whole.num_cells * 2);
+
+        // a full block turns entries down and still decodes what it took
+        let taken = prefix.num_cells;
+        let extra = "/zzz".to_string();
+        assert_eq!(prefix.add_cell(extra.clone(), None), Err((extra, None)));
+        let block = prefix.encode();
+        let entries: BTreeMap<String, Option<u64>> = DataBlock::new(&block).decode();
+        assert_eq!(entries.keys().cloned().collect::<Vec<_>>(), keys[..taken]);
+    }
+}
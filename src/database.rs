@@ -0,0 +1,495 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt::{self, Debug},
+    marker::PhantomData,
+    mem::take,
+    rc::Rc,
+};
+
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer_manager::BufferManager,
+    clock::Clock,
+    fixed::KnowsSize,
+    kv_store::{EntryTooLarge, KeyRange, KeyValueStore, StoreStats},
+    lsm_tree::{LSMTree, MergeOperator, WriteBatch},
+    manifest::{FamilyState, Manifest},
+    options::{Options, OptionsError},
+    storage::{FileSystem, Storage},
+    wal::Wal,
+};
+
+/*
+A database is a directory under the data directory holding its column families, one LSM tree each,
+next to a shared log and a manifest:
+  LOG       every write to any family, one record per Database::write listing the
+            family name and its tree's record for each family the batch touches
+  MANIFEST  for each family, its live disktable generation and the last sequence
+            number that generation holds
+On open the log is replayed into each family past the sequence number in the manifest.
+*/
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    InvalidName(String),
+    // the family is already open with other key or value types
+    WrongTypes(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseError::InvalidName(name) => {
+                write!(f, "{:?} is not a valid column family name", name)
+            }
+            DatabaseError::WrongTypes(name) => write!(
+                f,
+                "column family {} is already open with other key or value types",
+                name
+            ),
+        }
+    }
+}
+
+// a typed handle to a column family of the database that returned it
+pub struct ColumnFamily<K, V> {
+    id: usize,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for ColumnFamily<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for ColumnFamily<K, V> {}
+
+// writes to any number of column families, which Database::write applies all together
+#[derive(Default)]
+pub struct Batch {
+    // a WriteBatch for each family written to
+    writes: Vec<(usize, Box<dyn Any>)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put<K: Serialize + 'static, V: Serialize + 'static>(
+        &mut self,
+        cf: ColumnFamily<K, V>,
+        k: K,
+        v: V,
+    ) {
+        self.family(cf).put(k, v);
+    }
+
+    pub fn put_with_ttl<K: Serialize + 'static, V: Serialize + 'static>(
+        &mut self,
+        cf: ColumnFamily<K, V>,
+        k: K,
+        v: V,
+        ttl: TimeDelta,
+    ) {
+        self.family(cf).put_with_ttl(k, v, ttl);
+    }
+
+    pub fn delete<K: Serialize + 'static, V: Serialize + 'static>(
+        &mut self,
+        cf: ColumnFamily<K, V>,
+        k: K,
+    ) {
+        self.family(cf).delete(k);
+    }
+
+    // needs the family to have a merge operator
+    pub fn merge<K: Serialize + 'static, V: Serialize + 'static>(
+        &mut self,
+        cf: ColumnFamily<K, V>,
+        k: K,
+        operand: V,
+    ) {
+        self.family(cf).merge(k, operand);
+    }
+
+    fn family<K: Serialize + 'static, V: Serialize + 'static>(
+        &mut self,
+        cf: ColumnFamily<K, V>,
+    ) -> &mut WriteBatch<K, V> {
+        let i = match self.writes.iter().position(|(id, _)| *id == cf.id) {
+            Some(i) => i,
+            None => {
+                self.writes
+                    .push((cf.id, Box::new(WriteBatch::<K, V>::new())));
+                self.writes.len() - 1
+            }
+        };
+        self.writes[i].1.downcast_mut().unwrap()
+    }
+}
+
+// a column family with its types erased, so one database can hold families of any types
+trait Family<S: Storage> {
+    fn as_any(&self) -> &dyn Any;
+    // None when the batch, a WriteBatch of the family's types, is empty
+    fn prepare(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        batch: Box<dyn Any>,
+    ) -> Result<Option<Vec<u8>>, EntryTooLarge>;
+    fn apply(&mut self, record: &[u8]);
+    fn flush(&mut self, manager: &mut BufferManager<S>);
+    fn stats(&self, manager: &mut BufferManager<S>) -> StoreStats;
+    fn set_clock(&mut self, clock: Rc<dyn Clock>);
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+        S: Storage + 'static,
+    > Family<S> for LSMTree<K, V, S>
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn prepare(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        batch: Box<dyn Any>,
+    ) -> Result<Option<Vec<u8>>, EntryTooLarge> {
+        let batch = *batch.downcast::<WriteBatch<K, V>>().unwrap();
+        self.prepare_record(manager, batch)
+    }
+
+    fn apply(&mut self, record: &[u8]) {
+        self.apply_record(record);
+    }
+
+    fn flush(&mut self, manager: &mut BufferManager<S>) {
+        KeyValueStore::flush(self, manager);
+    }
+
+    fn stats(&self, manager: &mut BufferManager<S>) -> StoreStats {
+        KeyValueStore::stats(self, manager)
+    }
+
+    fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        LSMTree::set_clock(self, clock);
+    }
+}
+
+pub struct Database<S: Storage = FileSystem> {
+    name: String,
+    wal: Wal<S::File>,
+    manifest: Rc<RefCell<Manifest>>,
+    families: Vec<(String, Box<dyn Family<S>>)>,
+    // logged records of families that are not open yet, replayed when they are
+    unreplayed: Vec<(String, Vec<u8>)>,
+    clock: Option<Rc<dyn Clock>>,
+    options: Options,
+}
+
+impl<S: Storage + 'static> Database<S> {
+    pub fn open(name: &str, manager: &mut BufferManager<S>) -> Self {
+        Self::with_options(name, manager, &Options::default()).unwrap()
+    }
+
+    // every family of the database is opened with these options, on the storage of the
+    // buffer manager
+    pub fn with_options(
+        name: &str,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let storage = manager.storage();
+        let path = format!("{}/{}", options.data_dir, name);
+        storage.create_dir_all(&path).unwrap();
+        // left behind by a flush that did not finish, the old log is still complete
+        let _ = storage.delete(&format!("{}/LOG.new", path));
+
+        let manifest = Manifest::open(storage, format!("{}/MANIFEST", path), options.sync);
+        let (wal, records) = Wal::open(storage, format!("{}/LOG", path), options.sync);
+        let unreplayed = records
+            .iter()
+            .flat_map(|r| bincode::deserialize::<Vec<(String, Vec<u8>)>>(r).unwrap())
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            wal,
+            manifest: Rc::new(RefCell::new(manifest)),
+            families: Vec::new(),
+            unreplayed,
+            clock: None,
+            options: options.clone(),
+        })
+    }
+
+    // every column family ever created, open or not
+    pub fn families(&self) -> Vec<String> {
+        self.manifest.borrow().families().cloned().collect()
+    }
+
+    // opens the column family, creating it if it does not exist yet. It has to be opened
+    // with the same types every time
+    pub fn family<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+    ) -> Result<ColumnFamily<K, V>, DatabaseError> {
+        self.open_family(manager, name, None)
+    }
+
+    // like family, the operator must be the same every time the family is opened
+    pub fn family_with_merge_operator<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+        merge_operator: Rc<dyn MergeOperator<K, V>>,
+    ) -> Result<ColumnFamily<K, V>, DatabaseError> {
+        self.open_family(manager, name, Some(merge_operator))
+    }
+
+    fn open_family<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+        merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+    ) -> Result<ColumnFamily<K, V>, DatabaseError> {
+        if name.is_empty() || name.contains('/') || name.starts_with("LOG") || name == "MANIFEST" {
+            return Err(DatabaseError::InvalidName(name.to_string()));
+        }
+        if let Some(id) = self.families.iter().position(|(n, _)| n == name) {
+            if !self.families[id].1.as_any().is::<LSMTree<K, V, S>>() {
+                return Err(DatabaseError::WrongTypes(name.to_string()));
+            }
+            return Ok(ColumnFamily {
+                id,
+                _types: PhantomData,
+            });
+        }
+
+        if self.manifest.borrow().family(name).is_none() {
+            self.manifest
+                .borrow_mut()
+                .record(manager.storage(), name, FamilyState::default());
+        }
+        let mut tree: LSMTree<K, V, S> = LSMTree::open_family(
+            format!("{}/{}", self.name, name),
+            name,
+            manager,
+            &self.options,
+            self.manifest.clone(),
+            merge_operator,
+        );
+        if let Some(clock) = &self.clock {
+            tree.set_clock(clock.clone());
+        }
+        let (mine, rest) = take(&mut self.unreplayed)
+            .into_iter()
+            .partition(|(n, _)| n == name);
+        self.unreplayed = rest;
+        for (_, record) in mine {
+            tree.apply_record(&record);
+        }
+
+        self.families.push((name.to_string(), Box::new(tree)));
+        Ok(ColumnFamily {
+            id: self.families.len() - 1,
+            _types: PhantomData,
+        })
+    }
+
+    // the time deadlines are checked against in every family
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        for (_, family) in self.families.iter_mut() {
+            family.set_clock(clock.clone());
+        }
+        self.clock = Some(clock);
+    }
+
+    fn tree<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &self,
+        cf: ColumnFamily<K, V>,
+    ) -> &LSMTree<K, V, S> {
+        self.families[cf.id]
+            .1
+            .as_any()
+            .downcast_ref()
+            .expect("column family of another database")
+    }
+
+    pub fn get<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &self,
+        manager: &mut BufferManager<S>,
+        cf: ColumnFamily<K, V>,
+        k: K,
+    ) -> Option<V> {
+        self.tree(cf).get(manager, k)
+    }
+
+    pub fn scan<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &self,
+        manager: &mut BufferManager<S>,
+        cf: ColumnFamily<K, V>,
+        range: KeyRange<K>,
+    ) -> Vec<(K, V)> {
+        self.tree(cf).scan(manager, range)
+    }
+
+    // None deletes the key
+    pub fn put<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug + 'static,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + 'static,
+    >(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        cf: ColumnFamily<K, V>,
+        k: K,
+        v: Option<V>,
+    ) -> Result<(), EntryTooLarge> {
+        let mut batch = Batch::new();
+        match v {
+            Some(v) => batch.put(cf, k, v),
+            None => batch.delete(cf, k),
+        }
+        self.write(manager, batch)
+    }
+
+    // logs the writes to every family as one record before applying any, so after a
+    // crash either all of them are replayed or none. Once the log outgrows what the
+    // memtables can hold, every family is merged and the log starts over. A batch with an
+    // entry too large for a page is turned down as a whole
+    pub fn write(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        batch: Batch,
+    ) -> Result<(), EntryTooLarge> {
+        let mut records = Vec::new();
+        for (id, writes) in batch.writes {
+            if let Some(record) = self.families[id].1.prepare(manager, writes)? {
+                records.push((id, record));
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let logged: Vec<(&str, &[u8])> = records
+            .iter()
+            .map(|(id, record)| (self.families[*id].0.as_str(), record.as_slice()))
+            .collect();
+        self.wal.append(&bincode::serialize(&logged).unwrap());
+        for (id, record) in records {
+            self.families[id].1.apply(&record);
+        }
+
+        let limit = 2 * self.families.len() * self.options.memtable_budget;
+        if self.wal.len() > limit as u64 {
+            self.flush(manager);
+        }
+        Ok(())
+    }
+
+    // merges every open family and starts a new log, which keeps only the records of
+    // families that are not open
+    pub fn flush(&mut self, manager: &mut BufferManager<S>) {
+        for (_, family) in self.families.iter_mut() {
+            family.flush(manager);
+        }
+
+        let path = format!("{}/{}/LOG", self.options.data_dir, self.name);
+        let storage = manager.storage();
+        let (mut wal, _) = Wal::open(storage, format!("{}.new", path), self.options.sync);
+        for (name, record) in self.unreplayed.iter() {
+            let logged = vec![(name.as_str(), record.as_slice())];
+            wal.append(&bincode::serialize(&logged).unwrap());
+        }
+        wal.rename(storage, path);
+        self.wal = wal;
+    }
+
+    pub fn wal_bytes(&self) -> u64 {
+        self.wal.len()
+    }
+
+    pub fn stats(&self, manager: &mut BufferManager<S>) -> Vec<(String, StoreStats)> {
+        self.families
+            .iter()
+            .map(|(name, family)| (name.clone(), family.stats(manager)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+
+    #[test]
+    fn families_come_back_from_the_log_and_after_a_flush() {
+        let storage = MemStorage::new();
+        let options = Options::default();
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut db = Database::with_options("db", &mut manager, &options).unwrap();
+        let users = db.family::<u64, String>(&mut manager, "users").unwrap();
+        let counts = db.family::<String, u64>(&mut manager, "counts").unwrap();
+        let mut batch = Batch::new();
+        batch.put(users, 1, "ann".to_string());
+        batch.put(counts, "users".to_string(), 1);
+        db.write(&mut manager, batch).unwrap();
+        assert!(db
+            .put(&mut manager, users, 2, Some("x".repeat(5000)))
+            .is_err());
+        drop(db);
+
+        // nothing flushed, the log has it all
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut db = Database::with_options("db", &mut manager, &options).unwrap();
+        assert_eq!(db.families(), vec!["counts", "users"]);
+        let users = db.family::<u64, String>(&mut manager, "users").unwrap();
+        assert_eq!(db.get(&mut manager, users, 1), Some("ann".to_string()));
+        assert_eq!(db.get(&mut manager, users, 2), None);
+        db.put(&mut manager, users, 3, Some("bob".to_string()))
+            .unwrap();
+        db.flush(&mut manager);
+        manager.flush();
+        drop(db);
+
+        // the counts family was not open for the flush, its write is carried over
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let mut db = Database::with_options("db", &mut manager, &options).unwrap();
+        let users = db.family::<u64, String>(&mut manager, "users").unwrap();
+        let counts = db.family::<String, u64>(&mut manager, "counts").unwrap();
+        assert_eq!(db.get(&mut manager, users, 3), Some("bob".to_string()));
+        assert_eq!(db.get(&mut manager, counts, "users".to_string()), Some(1));
+        assert!(matches!(
+            db.family::<u64, u64>(&mut manager, "users"),
+            Err(DatabaseError::WrongTypes(_))
+        ));
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    io,
+    ops::Bound,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::Once,
+};
+
+use crate::{
+    buffer_manager::BufferManager,
+    kv_store::{KeyValueStore, Rng},
+    lsm_tree::LSMTree,
+    options::{Options, SyncPolicy},
+    storage::{Storage, StorageFile},
+};
+
+/*
+Fault storage model:
+Files live in memory. Every file has the bytes the last sync made durable and the writes
+and truncations made since, which a crash may lose. Creating, renaming and deleting files
+is durable at once, as on a filesystem that journals its metadata. A crash also ends the
+process: every handle opened before it fails from then on, like the file descriptors of a
+process that is gone.
+*/
+
+// the smallest unit a disk writes whole, a torn write keeps some of its sectors
+const SECTOR: usize = 512;
+
+// an error a write or sync can be made to return
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Eio,
+    Enospc,
+}
+
+impl Fault {
+    fn error(&self) -> io::Error {
+        match self {
+            Fault::Eio => io::Error::from_raw_os_error(5),
+            Fault::Enospc => io::Error::from_raw_os_error(28),
+        }
+    }
+}
+
+// what a crash does to the writes that were not synced yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Crash {
+    // all of them are lost
+    DropUnsynced,
+    // each one is lost or reaches the disk, either whole or torn, with only some of its
+    // sectors written
+    TearWrites,
+}
+
+enum Unsynced {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+#[derive(Default)]
+struct Inode {
+    durable: Vec<u8>,
+    // applied to durable in order, they give what the file reads as now
+    unsynced: Vec<Unsynced>,
+    current: Vec<u8>,
+}
+
+struct FaultState {
+    files: BTreeMap<String, Rc<RefCell<Inode>>>,
+    // bumped by every crash, handles opened in an earlier epoch fail
+    epoch: u64,
+    // writes and syncs to go before the next one fails, and how
+    countdown: Option<(usize, Fault)>,
+    // whether an error was returned since the last call to take_faulted
+    faulted: bool,
+    rng: Rng,
+}
+
+// a storage that loses what a crash would lose. Clones share the files, so a tree reopened
+// on a clone after a crash finds what survived it
+#[derive(Clone)]
+pub struct FaultStorage {
+    state: Rc<RefCell<FaultState>>,
+}
+
+pub struct FaultFile {
+    inode: Rc<RefCell<Inode>>,
+    state: Rc<RefCell<FaultState>>,
+    epoch: u64,
+}
+
+impl FaultStorage {
+    // seed drives which unsynced writes a crash keeps
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(FaultState {
+                files: BTreeMap::new(),
+                epoch: 0,
+                countdown: None,
+                faulted: false,
+                rng: Rng(seed.max(1)),
+            })),
+        }
+    }
+
+    // the write or sync after the next ops ones returns the fault, once
+    pub fn fail_after(&self, ops: usize, fault: Fault) {
+        self.state.borrow_mut().countdown = Some((ops, fault));
+    }
+
+    // true when a write or sync failed since the last call, so the caller can tell an
+    // injected error from a bug
+    pub fn take_faulted(&self) -> bool {
+        std::mem::take(&mut self.state.borrow_mut().faulted)
+    }
+
+    // ends the process the files were written by and leaves them as a disk would after
+    // losing power
+    pub fn crash(&self, crash: Crash) {
+        let mut state = self.state.borrow_mut();
+        state.epoch += 1;
+        state.countdown = None;
+        state.faulted = false;
+        let inodes: Vec<_> = state.files.values().cloned().collect();
+        for inode in inodes {
+            let mut inode = inode.borrow_mut();
+            let mut bytes = inode.durable.clone();
+            if crash == Crash::TearWrites {
+                for change in inode.unsynced.iter() {
+                    match change {
+                        _ if state.rng.below(2) == 0 => {}
+                        Unsynced::SetLen(len) => bytes.resize(*len as usize, 0),
+                        Unsynced::Write(offset, data) => {
+                            let torn = state.rng.below(4) == 0;
+                            for (i, sector) in data.chunks(SECTOR).enumerate() {
+                                if torn && state.rng.below(2) == 0 {
+                                    continue;
+                                }
+                                write_into(&mut bytes, *offset as usize + i * SECTOR, sector);
+                            }
+                        }
+                    }
+                }
+            }
+            inode.unsynced.clear();
+            inode.current = bytes.clone();
+            inode.durable = bytes;
+        }
+    }
+}
+
+impl FaultState {
+    // the error the next write or sync returns, if any
+    fn inject(&mut self) -> io::Result<()> {
+        match self.countdown {
+            Some((0, fault)) => {
+                self.countdown = None;
+                self.faulted = true;
+                Err(fault.error())
+            }
+            Some((n, fault)) => {
+                self.countdown = Some((n - 1, fault));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Storage for FaultStorage {
+    type File = FaultFile;
+
+    fn open(&self, path: &str, create: bool) -> io::Result<FaultFile> {
+        let mut state = self.state.borrow_mut();
+        let epoch = state.epoch;
+        let inode = match state.files.get(path) {
+            Some(inode) => inode.clone(),
+            None if create => state.files.entry(path.to_string()).or_default().clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, path.to_string())),
+        };
+        Ok(FaultFile {
+            inode,
+            state: self.state.clone(),
+            epoch,
+        })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let inode = state
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, from.to_string()))?;
+        state.files.insert(to.to_string(), inode);
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        match self.state.borrow_mut().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, path.to_string())),
+        }
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .state
+            .borrow()
+            .files
+            .keys()
+            .filter_map(|path| {
+                let (parent, name) = path.rsplit_once('/').unwrap_or((".", path));
+                (parent == dir).then(|| name.to_string())
+            })
+            .collect())
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FaultFile {
+    // fails once the process that opened the file has crashed
+    fn check(&self) -> io::Result<()> {
+        match self.state.borrow().epoch == self.epoch {
+            true => Ok(()),
+            false => Err(Fault::Eio.error()),
+        }
+    }
+}
+
+impl StorageFile for FaultFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check()?;
+        let inode = self.inode.borrow();
+        let start = offset as usize;
+        if start + buf.len() > inode.current.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buf.copy_from_slice(&inode.current[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check()?;
+        self.state.borrow_mut().inject()?;
+        let mut inode = self.inode.borrow_mut();
+        write_into(&mut inode.current, offset as usize, buf);
+        inode.unsynced.push(Unsynced::Write(offset, buf.to_vec()));
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.check()?;
+        Ok(self.inode.borrow().current.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.check()?;
+        let mut inode = self.inode.borrow_mut();
+        inode.current.resize(len as usize, 0);
+        inode.unsynced.push(Unsynced::SetLen(len));
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.check()?;
+        self.state.borrow_mut().inject()?;
+        let mut inode = self.inode.borrow_mut();
+        inode.durable = inode.current.clone();
+        inode.unsynced.clear();
+        Ok(())
+    }
+}
+
+fn write_into(bytes: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if offset + data.len() > bytes.len() {
+        bytes.resize(offset + data.len(), 0);
+    }
+    bytes[offset..offset + data.len()].copy_from_slice(data);
+}
+
+type Tree = LSMTree<u64, String, FaultStorage>;
+
+fn open_tree(storage: &FaultStorage, options: &Options) -> (BufferManager<FaultStorage>, Tree) {
+    let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+    let tree = LSMTree::with_options("crash".to_string(), &mut manager, options, None).unwrap();
+    (manager, tree)
+}
+
+thread_local! {
+    // set while a check runs on this thread
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+static QUIET_HOOK: Once = Once::new();
+
+// injected errors unwind out of the tree like any other, without printing them. Panics on
+// other threads, like other tests, still print
+fn quiet_panics(quiet: bool) {
+    QUIET_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET.get() {
+                hook(info)
+            }
+        }));
+    });
+    QUIET.set(quiet);
+}
+
+// runs a random workload against an LSM tree that syncs every write, failing writes and
+// crashing now and then with one of the given crashes. After every crash the reopened tree
+// must hold every write that returned, and may hold the one that was cut short, but
+// nothing else. Returns the number of crashes
+pub fn check_crash_recovery(
+    options: &Options,
+    crashes: &[Crash],
+    ops: usize,
+    seed: u64,
+) -> Result<usize, String> {
+    let kinds = crashes;
+    let options = options.clone().sync(SyncPolicy::Always);
+    let key_space = (ops as u64 / 4).max(16);
+    let mut rng = Rng(seed.max(1));
+    let storage = FaultStorage::new(seed);
+    let mut model: BTreeMap<u64, String> = BTreeMap::new();
+    let (mut manager, mut tree) = open_tree(&storage, &options);
+    let mut crashes = 0;
+
+    quiet_panics(true);
+    let result = (|| {
+        for op in 0..ops {
+            if rng.below(20) == 0 {
+                let fault = [Fault::Eio, Fault::Enospc][rng.below(2) as usize];
+                storage.fail_after(rng.below(8) as usize, fault);
+            }
+            let k = rng.below(key_space);
+            // the write the operation makes, None for one that only reads or merges
+            let mut write = None;
+            let ran = panic::catch_unwind(AssertUnwindSafe(|| match rng.below(10) {
+                0..=4 => {
+                    let v = format!("{}-{}", op, "v".repeat(rng.below(64) as usize));
+                    write = Some((k, Some(v.clone())));
+                    tree.put(&mut manager, k, Some(v)).unwrap();
+                    Ok(())
+                }
+                5 | 6 => {
+                    write = Some((k, None));
+                    tree.delete(&mut manager, k);
+                    Ok(())
+                }
+                7 => {
+                    let got = tree.get(&mut manager, k);
+                    match got.as_ref() == model.get(&k) {
+                        true => Ok(()),
+                        false => Err(format!(
+                            "op {}: get {} returned {:?}, expected {:?}",
+                            op,
+                            k,
+                            got,
+                            model.get(&k)
+                        )),
+                    }
+                }
+                8 => {
+                    tree.flush(&mut manager);
+                    Ok(())
+                }
+                _ => {
+                    tree.compact(&mut manager);
+                    Ok(())
+                }
+            }));
+
+            let cut_short = match ran {
+                Ok(Err(e)) => return Err(e),
+                Ok(Ok(())) => {
+                    if let Some((k, v)) = write.take() {
+                        apply(&mut model, k, v);
+                    }
+                    false
+                }
+                Err(_) if storage.take_faulted() => true,
+                Err(e) => return Err(format!("op {}: {}", op, panic_message(&e))),
+            };
+            if !cut_short && rng.below(50) != 0 {
+                continue;
+            }
+
+            crashes += 1;
+            let crash = kinds[rng.below(kinds.len() as u64) as usize];
+            storage.crash(crash);
+            let reopened = panic::catch_unwind(AssertUnwindSafe(|| open_tree(&storage, &options)));
+            (manager, tree) = match reopened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    return Err(format!(
+                        "op {}: reopening after {:?} failed: {}",
+                        op,
+                        crash,
+                        panic_message(&e)
+                    ))
+                }
+            };
+            let all: BTreeMap<u64, String> = tree
+                .scan(&mut manager, (Bound::Unbounded, Bound::Unbounded))
+                .into_iter()
+                .collect();
+            if all == model {
+                continue;
+            }
+            // the write that failed may have made it
+            if let Some((k, v)) = write {
+                apply(&mut model, k, v);
+                if all == model {
+                    continue;
+                }
+            }
+            return Err(format!(
+                "op {}: after {:?} the tree holds {} keys, expected {}",
+                op,
+                crash,
+                all.len(),
+                model.len()
+            ));
+        }
+        Ok(crashes)
+    })();
+    quiet_panics(false);
+    Box::new(tree).destroy(&mut manager);
+    result
+}
+
+fn apply(model: &mut BTreeMap<u64, String>, k: u64, v: Option<String>) {
+    match v {
+        Some(v) => model.insert(k, v),
+        None => model.remove(&k),
+    };
+}
+
+fn panic_message(e: &Box<dyn std::any::Any + Send>) -> String {
+    match (e.downcast_ref::<String>(), e.downcast_ref::<&str>()) {
+        (Some(s), _) => s.clone(),
+        (None, Some(s)) => s.to_string(),
+        (None, None) => "panic".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Compression, data_block::PageFormat};
+
+    const SEEDS: [u64; 3] = [3, 0x5eed, 0xc0ffee];
+
+    // small memtables so the workload flushes and merges between crashes
+    fn options() -> [Options; 2] {
+        let default = Options::default()
+            .memtable_budget(1 << 12)
+            .cache_size(1 << 16);
+        let prefix = default
+            .clone()
+            .page_format(PageFormat::Prefix)
+            .compression(Compression::Zstd);
+        [default, prefix]
+    }
+
+    fn check(crash: Crash) {
+        for options in options() {
+            for seed in SEEDS {
+                match check_crash_recovery(&options, &[crash], 800, seed) {
+                    Ok(crashes) => assert!(crashes > 0),
+                    Err(e) => panic!(
+                        "{:?} with {:?} pages, seed {}: {}",
+                        crash, options.page_format, seed, e
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trees_keep_synced_writes_when_unsynced_ones_are_dropped() {
+        check(Crash::DropUnsynced);
+    }
+
+    #[test]
+    fn trees_keep_synced_writes_when_unsynced_ones_are_torn() {
+        check(Crash::TearWrites);
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 use chrono::{DateTime, Local};
 
 pub trait KnowsSize {
@@ -75,3 +77,15 @@ impl KnowsSize for String {
         return -1;
     }
 }
+
+impl KnowsSize for () {
+    fn bit_width() -> i16 {
+        return 0;
+    }
+}
+
+impl<T> KnowsSize for Option<T> {
+    fn bit_width() -> i16 {
+        return -1;
+    }
+}
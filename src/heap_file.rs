@@ -0,0 +1,362 @@
+use std::{fmt::Debug, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer_manager::{Access, BufferManager},
+    fixed::KnowsSize,
+    kv_store::EntryTooLarge,
+    options::{Options, OptionsError},
+    slotted_page::{PageMut, PageView},
+    storage::{FileSystem, Storage, StorageFile},
+};
+
+const META_MAGIC: u32 = 0x48_45_41_50;
+// a row's cell takes its slot and the two lengths of a variable cell besides the row
+const CELL_OVERHEAD: usize = 12;
+// the header of a slotted page, of the larger fixed kind
+const PAGE_HEADER: usize = 6;
+
+/*
+Heap file format:
+| meta page | page 1 | page 2 | ... |
+The meta page is | magic u32 | block size u32 | page count u64 | and the other pages are
+slotted pages whose cells have an empty key and the row as value, or no value once the row
+is deleted. Slots are appended and never removed, so a record id stays valid for as long as
+the row lives and a deleted one never names another row.
+*/
+
+// where a row lives, slot is its position in the page's slot array
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rid {
+    pub page: u64,
+    pub slot: u16,
+}
+
+impl KnowsSize for Rid {
+    fn bit_width() -> i16 {
+        10
+    }
+}
+
+#[derive(Debug)]
+pub struct HeapStats {
+    pub pages: usize,
+    pub rows: usize,
+    pub deleted: usize,
+    pub free_bytes: usize,
+}
+
+// rows in no particular key order, addressed by record id. The free space map is rebuilt
+// from the pages when the file opens
+pub struct HeapFile<R, S: Storage = FileSystem> {
+    file: String,
+    block_size: usize,
+    pages: u64,
+    // free bytes of every page, page 1 first
+    free_space: Vec<usize>,
+    _rows: PhantomData<(R, S)>,
+}
+
+impl<R: Serialize + for<'a> Deserialize<'a> + KnowsSize + Clone + Debug, S: Storage>
+    HeapFile<R, S>
+{
+    pub fn new(name: String, manager: &mut BufferManager<S>) -> Self {
+        Self::with_options(name, manager, &Options::default()).unwrap()
+    }
+
+    // opens the heap at name under the data directory of the options, which are checked
+    // first
+    pub fn with_options(
+        name: String,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let storage = manager.storage();
+        storage.create_dir_all(&options.data_dir).unwrap();
+        let file = format!("{}/{}", options.data_dir, name);
+        // like the B+Tree, the meta page says how large pages are unless it is still only
+        // in the buffer manager
+        let block_size = match manager.block_size(&file) {
+            Some(size) => size,
+            None => {
+                let mut meta = [0; 8];
+                match storage
+                    .open(&file, false)
+                    .and_then(|f| f.read_at(&mut meta, 0))
+                {
+                    Ok(()) if meta[0..4] == META_MAGIC.to_le_bytes() => {
+                        u32::from_le_bytes(meta[4..8].try_into().unwrap()) as usize
+                    }
+                    _ => options.block_size,
+                }
+            }
+        };
+        manager.set_block_size(&file, block_size);
+        let mut s = Self {
+            file,
+            block_size,
+            pages: 0,
+            free_space: Vec::new(),
+            _rows: PhantomData,
+        };
+
+        match manager.get(s.file.clone(), 0) {
+            Some(block) => {
+                let b = block.as_ref().borrow();
+                if b.bytes[0..4] != META_MAGIC.to_le_bytes() {
+                    panic!("{} is not a heap file", s.file);
+                }
+                s.pages = u64::from_le_bytes(b.bytes[8..16].try_into().unwrap());
+            }
+            None => s.write_meta(manager),
+        }
+        for page in 1..=s.pages {
+            let mut buf = s.read_page(manager, page, Access::Background);
+            let free = PageMut::<(), R>::new(&mut buf).free_space();
+            s.free_space.push(free);
+        }
+        Ok(s)
+    }
+
+    // appends the row to the first page with room for it, or to a new page
+    pub fn insert(&mut self, manager: &mut BufferManager<S>, row: R) -> Result<Rid, EntryTooLarge> {
+        self.place(manager, &Some(row))
+    }
+
+    // whether a page takes the row, turned down rows are never written
+    pub fn fits(&self, row: &R) -> Result<(), EntryTooLarge> {
+        self.need(&Some(row)).map(|_| ())
+    }
+
+    // the bytes the row takes in a page
+    fn need(&self, row: &Option<&R>) -> Result<usize, EntryTooLarge> {
+        let need = bincode::serialized_size(row).unwrap() as usize + CELL_OVERHEAD;
+        if need > self.block_size - PAGE_HEADER {
+            return Err(EntryTooLarge {
+                size: need,
+                block_size: self.block_size,
+            });
+        }
+        Ok(need)
+    }
+
+    fn place(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        row: &Option<R>,
+    ) -> Result<Rid, EntryTooLarge> {
+        let need = self.need(&row.as_ref())?;
+        let page = match self.free_space.iter().position(|free| *free >= need) {
+            Some(i) => i as u64 + 1,
+            None => self.allocate(manager),
+        };
+        let slot = self
+            .edit(manager, page, |p| {
+                let slot = p.len();
+                p.insert(slot, &(), row).map(|_| slot)
+            })
+            .unwrap_or_else(|e| panic!("page {} of {}: {}", page, self.file, e));
+        Ok(Rid {
+            page,
+            slot: slot as u16,
+        })
+    }
+
+    // None for a deleted row or one that was never there
+    pub fn get(&self, manager: &mut BufferManager<S>, rid: Rid) -> Option<R> {
+        if rid.page == 0 || rid.page > self.pages {
+            return None;
+        }
+        let buf = self.read_page(manager, rid.page, Access::Random);
+        let view: PageView<(), R> = PageView::new(&buf);
+        if rid.slot as usize >= view.len() {
+            return None;
+        }
+        view.value(rid.slot as usize)
+    }
+
+    // replaces the row in place when its page has room, otherwise the row moves and the
+    // returned id is where it went. A row too large for any page leaves the old one as it
+    // was
+    pub fn update(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        rid: Rid,
+        row: R,
+    ) -> Result<Rid, EntryTooLarge> {
+        self.fits(&row)?;
+        let row = Some(row);
+        let updated = self.edit(manager, rid.page, |p| p.update(rid.slot as usize, &row));
+        match updated {
+            Ok(()) => Ok(rid),
+            Err(_) => {
+                self.delete(manager, rid);
+                self.place(manager, &row)
+            }
+        }
+    }
+
+    // leaves a tombstone in the slot, the row's bytes are free for other rows of the page
+    pub fn delete(&mut self, manager: &mut BufferManager<S>, rid: Rid) {
+        if rid.page == 0 || rid.page > self.pages {
+            return;
+        }
+        // a tombstone is never longer than the row it replaces, so it always fits
+        let slot = rid.slot as usize;
+        let _ = self.edit(manager, rid.page, |p| match slot < p.len() {
+            true => p.update(slot, &None),
+            false => Ok(()),
+        });
+    }
+
+    // every live row, in page and then slot order
+    pub fn scan(&self, manager: &mut BufferManager<S>) -> Vec<(Rid, R)> {
+        let mut rows = Vec::new();
+        for page in 1..=self.pages {
+            let buf = self.read_page(manager, page, Access::Sequential);
+            let view: PageView<(), R> = PageView::new(&buf);
+            for slot in 0..view.len() {
+                if let Some(row) = view.value(slot) {
+                    let rid = Rid {
+                        page,
+                        slot: slot as u16,
+                    };
+                    rows.push((rid, row));
+                }
+            }
+        }
+        rows
+    }
+
+    pub fn stats(&self, manager: &mut BufferManager<S>) -> HeapStats {
+        let mut rows = 0;
+        let mut deleted = 0;
+        for page in 1..=self.pages {
+            let buf = self.read_page(manager, page, Access::Background);
+            let view: PageView<(), R> = PageView::new(&buf);
+            for slot in 0..view.len() {
+                match view.value(slot) {
+                    Some(_) => rows += 1,
+                    None => deleted += 1,
+                }
+            }
+        }
+        HeapStats {
+            pages: self.pages as usize,
+            rows,
+            deleted,
+            free_bytes: self.free_space.iter().sum(),
+        }
+    }
+
+    pub fn destroy(self, manager: &mut BufferManager<S>) {
+        manager.remove(&self.file);
+        let _ = manager.storage().delete(&self.file);
+    }
+
+    fn allocate(&mut self, manager: &mut BufferManager<S>) -> u64 {
+        self.pages += 1;
+        let mut buf = vec![0; self.block_size];
+        let free = PageMut::<(), R>::init(&mut buf).free_space();
+        self.write_page(manager, self.pages, &buf);
+        self.free_space.push(free);
+        self.write_meta(manager);
+        self.pages
+    }
+
+    // applies f to a copy of the page and writes it back, keeping the free space map
+    // up to date
+    fn edit<T, E>(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        page: u64,
+        f: impl FnOnce(&mut PageMut<(), R>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut buf = self.read_page(manager, page, Access::Random);
+        let mut p = PageMut::new(&mut buf);
+        let result = f(&mut p)?;
+        self.free_space[page as usize - 1] = p.free_space();
+        self.write_page(manager, page, &buf);
+        Ok(result)
+    }
+
+    fn read_page(&self, manager: &mut BufferManager<S>, page: u64, access: Access) -> Vec<u8> {
+        let block = manager
+            .get_with(self.file.clone(), page as usize * self.block_size, access)
+            .unwrap_or_else(|| panic!("page {} missing from {}", page, self.file));
+        let bytes = block.as_ref().borrow().bytes.to_vec();
+        bytes
+    }
+
+    fn write_page(&self, manager: &mut BufferManager<S>, page: u64, buf: &[u8]) {
+        manager.write(
+            &self.file,
+            page as usize * self.block_size,
+            buf,
+            self.block_size as u32,
+        );
+    }
+
+    fn write_meta(&self, manager: &mut BufferManager<S>) {
+        let mut buf = vec![0; self.block_size];
+        buf[0..4].copy_from_slice(&META_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        buf[8..16].copy_from_slice(&self.pages.to_le_bytes());
+        self.write_page(manager, 0, &buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+
+    type Heap = HeapFile<String, MemStorage>;
+
+    #[test]
+    fn rows_keep_their_ids_until_they_move() {
+        let storage = MemStorage::new();
+        let options = Options::default();
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut heap: Heap =
+            HeapFile::with_options("h".to_string(), &mut manager, &options).unwrap();
+
+        let rids: Vec<Rid> = (0..200)
+            .map(|i| heap.insert(&mut manager, format!("row {}", i)).unwrap())
+            .collect();
+        assert!(rids.iter().any(|rid| rid.page > 1));
+        assert_eq!(heap.get(&mut manager, rids[7]), Some("row 7".to_string()));
+
+        // a shorter row stays put, one that outgrows a full page moves
+        assert_eq!(
+            heap.update(&mut manager, rids[7], "7".to_string()),
+            Ok(rids[7])
+        );
+        let moved = heap
+            .update(&mut manager, rids[0], "x".repeat(2000))
+            .unwrap();
+        assert_ne!(moved, rids[0]);
+        assert_eq!(heap.get(&mut manager, rids[0]), None);
+        assert_eq!(heap.get(&mut manager, moved), Some("x".repeat(2000)));
+
+        heap.delete(&mut manager, rids[1]);
+        assert_eq!(heap.get(&mut manager, rids[1]), None);
+        assert!(heap.insert(&mut manager, "x".repeat(5000)).is_err());
+        assert!(heap
+            .update(&mut manager, rids[2], "x".repeat(5000))
+            .is_err());
+        assert_eq!(heap.get(&mut manager, rids[2]), Some("row 2".to_string()));
+        manager.flush();
+
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let heap: Heap = HeapFile::with_options("h".to_string(), &mut manager, &options).unwrap();
+        let rows = heap.scan(&mut manager);
+        assert_eq!(rows.len(), 199);
+        assert!(rows.contains(&(rids[7], "7".to_string())));
+        assert!(rows.contains(&(moved, "x".repeat(2000))));
+        let stats = heap.stats(&mut manager);
+        assert_eq!((stats.rows, stats.deleted), (199, 2));
+    }
+}
@@ -0,0 +1,336 @@
+use std::{fs::File, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{aligned_buf::AlignedBuf, storage::StorageFile};
+
+// how the buffer manager and disktables reach the disk
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IoBackendKind {
+    // io_uring, which reads a batch of blocks with one system call and writes back without
+    // waiting. Falls back to sync where the kernel does not offer it
+    Uring,
+    // one blocking pread or pwrite per block
+    Sync,
+}
+
+// how the files of a storage are read and written, io_uring only reaches real files
+pub trait IoBackend<F = File> {
+    // reads len bytes at every offset of the file, returned in the order asked for
+    fn read_batch(&mut self, file: &F, reads: &[(u64, usize)]) -> Vec<AlignedBuf>;
+    // starts writing data at offset. Until wait_for or wait_all returns for it the write
+    // may still be in flight, so the file at path must not be read there. The backend
+    // keeps the handle until then
+    fn write(&mut self, path: &str, file: Rc<F>, offset: u64, data: AlignedBuf);
+    fn wait_for(&mut self, path: &str, offset: u64);
+    fn wait_all(&mut self);
+    // how many writes are still in flight
+    fn pending(&mut self) -> usize;
+    fn name(&self) -> &'static str;
+}
+
+// the backend of that kind, or sync when io_uring cannot be set up
+pub fn open(kind: IoBackendKind) -> Box<dyn IoBackend> {
+    match kind {
+        #[cfg(target_os = "linux")]
+        IoBackendKind::Uring => match uring::UringIo::new(RING_ENTRIES) {
+            Ok(io) => Box::new(io),
+            Err(_) => Box::new(SyncIo),
+        },
+        _ => Box::new(SyncIo),
+    }
+}
+
+#[cfg(target_os = "linux")]
+const RING_ENTRIES: u32 = 64;
+
+pub struct SyncIo;
+
+impl<F: StorageFile> IoBackend<F> for SyncIo {
+    fn read_batch(&mut self, file: &F, reads: &[(u64, usize)]) -> Vec<AlignedBuf> {
+        reads
+            .iter()
+            .map(|(offset, len)| {
+                let mut buf = AlignedBuf::zeroed(*len);
+                file.read_at(&mut buf, *offset).unwrap();
+                buf
+            })
+            .collect()
+    }
+
+    fn write(&mut self, _path: &str, file: Rc<F>, offset: u64, data: AlignedBuf) {
+        file.write_at(&data, offset).unwrap();
+    }
+
+    fn wait_for(&mut self, _path: &str, _offset: u64) {}
+
+    fn wait_all(&mut self) {}
+
+    fn pending(&mut self) -> usize {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "sync"
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod uring {
+    use std::{
+        collections::HashMap,
+        fs::File,
+        io,
+        os::unix::{fs::FileExt, io::AsRawFd},
+        rc::Rc,
+    };
+
+    use io_uring::{cqueue, opcode, squeue, types, IoUring};
+
+    use super::IoBackend;
+    use crate::aligned_buf::AlignedBuf;
+
+    // user data of reads, the rest of it is the position in the batch. Writes count up
+    // from 0 and never get this far
+    const READ_TAG: u64 = 1 << 63;
+
+    // a write the kernel may still be copying from, which owns its file and buffer until
+    // it completes
+    struct Write {
+        path: String,
+        offset: u64,
+        file: Rc<File>,
+        data: AlignedBuf,
+    }
+
+    pub struct UringIo {
+        ring: IoUring,
+        next_write: u64,
+        writes: HashMap<u64, Write>,
+    }
+
+    impl UringIo {
+        pub fn new(entries: u32) -> io::Result<Self> {
+            Ok(Self {
+                ring: IoUring::new(entries)?,
+                next_write: 0,
+                writes: HashMap::new(),
+            })
+        }
+
+        // queues the entry, making room by submitting and then waiting for completions
+        // when the submission queue is full
+        fn push(&mut self, entry: squeue::Entry, reads: &mut [Option<i32>]) {
+            loop {
+                // the buffers the entry points at live in the batch or in self.writes until
+                // its completion is reaped
+                if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                    return;
+                }
+                if self.ring.submit().unwrap() == 0 {
+                    self.ring.submit_and_wait(1).unwrap();
+                    self.reap(reads);
+                }
+            }
+        }
+
+        // handles every completion so far, recording read results in reads
+        fn reap(&mut self, reads: &mut [Option<i32>]) {
+            let done: Vec<cqueue::Entry> = self.ring.completion().collect();
+            for cqe in done {
+                let id = cqe.user_data();
+                if id & READ_TAG != 0 {
+                    reads[(id & !READ_TAG) as usize] = Some(cqe.result());
+                    continue;
+                }
+                let write = self.writes.remove(&id).unwrap();
+                let written = check(cqe.result(), &write.path);
+                // a short write finishes with a blocking one
+                if written < write.data.len() {
+                    write
+                        .file
+                        .write_all_at(&write.data[written..], write.offset + written as u64)
+                        .unwrap();
+                }
+            }
+        }
+
+        fn wait_until(&mut self, done: impl Fn(&Self) -> bool) {
+            while !done(self) {
+                self.ring.submit_and_wait(1).unwrap();
+                self.reap(&mut []);
+            }
+        }
+    }
+
+    impl IoBackend for UringIo {
+        fn read_batch(&mut self, file: &File, reads: &[(u64, usize)]) -> Vec<AlignedBuf> {
+            let mut bufs: Vec<AlignedBuf> = reads
+                .iter()
+                .map(|(_, len)| AlignedBuf::zeroed(*len))
+                .collect();
+            let mut results = vec![None; reads.len()];
+            for (i, ((offset, _), buf)) in reads.iter().zip(bufs.iter_mut()).enumerate() {
+                let entry = opcode::Read::new(
+                    types::Fd(file.as_raw_fd()),
+                    buf.as_mut_ptr(),
+                    buf.len() as u32,
+                )
+                .offset(*offset)
+                .build()
+                .user_data(READ_TAG | i as u64);
+                self.push(entry, &mut results);
+            }
+            while results.iter().any(|r| r.is_none()) {
+                self.ring.submit_and_wait(1).unwrap();
+                self.reap(&mut results);
+            }
+            for (i, result) in results.into_iter().enumerate() {
+                // a short read finishes with a blocking one
+                let read = check(result.unwrap(), "a batch read");
+                let (offset, len) = reads[i];
+                if read < len {
+                    file.read_exact_at(&mut bufs[i][read..], offset + read as u64)
+                        .unwrap();
+                }
+            }
+            bufs
+        }
+
+        fn write(&mut self, path: &str, file: Rc<File>, offset: u64, data: AlignedBuf) {
+            // the kernel may run two writes to the same place in either order, so the
+            // older one lands first
+            self.wait_for(path, offset);
+            let id = self.next_write;
+            self.next_write += 1;
+            let entry = opcode::Write::new(
+                types::Fd(file.as_raw_fd()),
+                data.as_ptr(),
+                data.len() as u32,
+            )
+            .offset(offset)
+            .build()
+            .user_data(id);
+            // the buffer stays where it is when the write moves into the map
+            self.writes.insert(
+                id,
+                Write {
+                    path: path.to_string(),
+                    offset,
+                    file,
+                    data,
+                },
+            );
+            self.push(entry, &mut []);
+            self.ring.submit().unwrap();
+        }
+
+        fn wait_for(&mut self, path: &str, offset: u64) {
+            self.wait_until(|s| {
+                !s.writes
+                    .values()
+                    .any(|w| w.path == path && w.offset == offset)
+            });
+        }
+
+        fn wait_all(&mut self) {
+            self.wait_until(|s| s.writes.is_empty());
+        }
+
+        fn pending(&mut self) -> usize {
+            self.reap(&mut []);
+            self.writes.len()
+        }
+
+        fn name(&self) -> &'static str {
+            "io_uring"
+        }
+    }
+
+    // the kernel must not write into or out of buffers that are gone
+    impl Drop for UringIo {
+        fn drop(&mut self) {
+            self.wait_all();
+        }
+    }
+
+    // the bytes a completion moved, panicking on an error like the blocking calls do
+    fn check(result: i32, what: &str) -> usize {
+        if result < 0 {
+            panic!("{}: {}", what, io::Error::from_raw_os_error(-result));
+        }
+        result as usize
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        os::unix::fs::FileExt,
+    };
+
+    use super::*;
+    use crate::kv_store::Rng;
+
+    const BLOCK: usize = 4096;
+
+    #[test]
+    fn uring_reads_and_writes_what_sync_does() {
+        // kernels without io_uring, or sandboxes that forbid it, fall back to sync anyway
+        let Ok(mut uring) = uring::UringIo::new(RING_ENTRIES) else {
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("nopedb-io-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let open = |name: &str| {
+            let path = dir.join(name);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            (path.to_str().unwrap().to_string(), Rc::new(file))
+        };
+        let (sync_path, sync_file) = open("sync");
+        let (uring_path, uring_file) = open("uring");
+
+        // more writes than the ring has entries, many of them over blocks written before
+        let mut rng = Rng(11);
+        for i in 0..4 * RING_ENTRIES as usize {
+            let offset = rng.below(64) * BLOCK as u64;
+            let mut data = AlignedBuf::zeroed(BLOCK);
+            data.fill(i as u8);
+            data[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            SyncIo.write(&sync_path, sync_file.clone(), offset, data.clone());
+            uring.write(&uring_path, uring_file.clone(), offset, data);
+            if i % 50 == 0 {
+                uring.wait_for(&uring_path, offset);
+                let mut buf = vec![0; 8];
+                uring_file.read_exact_at(&mut buf, offset).unwrap();
+                assert_eq!(buf, (i as u64).to_le_bytes());
+            }
+        }
+        uring.wait_all();
+        assert_eq!(IoBackend::<File>::pending(&mut uring), 0);
+        assert_eq!(
+            fs::read(&sync_path).unwrap(),
+            fs::read(&uring_path).unwrap()
+        );
+
+        let len = sync_file.metadata().unwrap().len();
+        let reads: Vec<(u64, usize)> = (0..2 * RING_ENTRIES as u64)
+            .map(|_| {
+                let offset = rng.below(len - BLOCK as u64);
+                (offset, 1 + rng.below(BLOCK as u64) as usize)
+            })
+            .collect();
+        let expected = SyncIo.read_batch(&*sync_file, &reads);
+        let got = uring.read_batch(&uring_file, &reads);
+        assert!(expected.iter().zip(got.iter()).all(|(e, g)| e[..] == g[..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use std::{fmt, ops::Bound};
+
+use crate::{
+    bplus_tree::BPlusStats,
+    buffer_manager::BufferManager,
+    heap_file::HeapStats,
+    lsm_tree::LSMStats,
+    storage::{FileSystem, Storage},
+};
+
+// a key range as passed to scan, e.g. (Bound::Included(a), Bound::Excluded(b))
+pub type KeyRange<K> = (Bound<K>, Bound<K>);
+
+#[derive(Debug)]
+pub enum StoreStats {
+    Lsm(LSMStats),
+    BTree(BPlusStats),
+    Heap(HeapStats),
+}
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreStats::Lsm(s) => write!(
+                f,
+                "{} memtable entries ({} bytes), {} disktable pages ({} bytes, compression ratio {:.2}), {} merges, {} open snapshots, {} retired disktables, {} wal bytes",
+                s.memtable_entries,
+                s.memtable_bytes,
+                s.disktable_pages,
+                s.disktable_bytes,
+                s.compression_ratio(),
+                s.merges,
+                s.snapshots,
+                s.retired_disktables,
+                s.wal_bytes
+            ),
+            StoreStats::BTree(s) => write!(
+                f,
+                "B+Tree of height {}, {} pages, {} free",
+                s.height, s.pages, s.free_pages
+            ),
+            StoreStats::Heap(s) => write!(
+                f,
+                "heap of {} pages, {} rows, {} deleted, {} bytes free",
+                s.pages, s.rows, s.deleted, s.free_bytes
+            ),
+        }
+    }
+}
+
+// a put turned down before anything was written, as the entry would not fit in a page
+// of the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryTooLarge {
+    // of the key and value serialized
+    pub size: usize,
+    pub block_size: usize,
+}
+
+impl fmt::Display for EntryTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entry of {} bytes does not fit in a page of {} bytes",
+            self.size, self.block_size
+        )
+    }
+}
+
+// the operations every access method offers to tables, indexes and the shell, on the
+// storage of the buffer manager
+pub trait KeyValueStore<K, V, S: Storage = FileSystem> {
+    fn get(&self, manager: &mut BufferManager<S>, k: K) -> Option<V>;
+
+    // None deletes the key
+    fn put(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        v: Option<V>,
+    ) -> Result<(), EntryTooLarge>;
+
+    // whether put takes the entry, for callers that check several writes before making
+    // any of them
+    fn fits(&self, k: &K, v: &V) -> Result<(), EntryTooLarge>;
+
+    fn delete(&mut self, manager: &mut BufferManager<S>, k: K);
+
+    // returns every live key in the range in ascending order
+    fn scan(&self, manager: &mut BufferManager<S>, range: KeyRange<K>) -> Vec<(K, V)>;
+
+    // hands any writes held outside the buffer pool to it, so a pool flush persists them
+    fn flush(&mut self, manager: &mut BufferManager<S>);
+
+    // reclaims space held by deleted entries, stores that reuse space as they go only flush
+    fn compact(&mut self, manager: &mut BufferManager<S>) {
+        self.flush(manager);
+    }
+
+    fn stats(&self, manager: &mut BufferManager<S>) -> StoreStats;
+
+    // deletes the backing file and forgets any cached pages
+    fn destroy(self: Box<Self>, manager: &mut BufferManager<S>);
+}
+
+// xorshift64, enough to drive reproducible operation sequences
+#[cfg(test)]
+pub(crate) struct Rng(pub(crate) u64);
+
+#[cfg(test)]
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{bplus_tree::BPlusTree, lsm_tree::LSMTree, options::Options, storage::MemStorage};
+
+    type Opener<'a, S = FileSystem> =
+        &'a dyn Fn(String, &mut BufferManager<S>) -> Box<dyn KeyValueStore<u64, String, S>>;
+
+    fn random_bound(rng: &mut Rng, key_space: u64) -> Bound<u64> {
+        match rng.below(3) {
+            0 => Bound::Unbounded,
+            1 => Bound::Included(rng.below(key_space)),
+            _ => Bound::Excluded(rng.below(key_space)),
+        }
+    }
+
+    // runs a random sequence of operations against a fresh store and a BTreeMap model,
+    // reopening the store now and then so persisted state is checked as well
+    fn check_conformance<S: Storage>(
+        name: &str,
+        open: Opener<S>,
+        manager: &mut BufferManager<S>,
+        ops: usize,
+        seed: u64,
+    ) -> Result<(), String> {
+        let key_space = (ops as u64 / 4).max(16);
+        let mut rng = Rng(seed.max(1));
+        let mut model: BTreeMap<u64, String> = BTreeMap::new();
+
+        open(name.to_string(), manager).destroy(manager);
+        let mut store = open(name.to_string(), manager);
+
+        for op in 0..ops {
+            let k = rng.below(key_space);
+            match rng.below(10) {
+                0..=3 => {
+                    let v = format!("{}-{}", op, "v".repeat(rng.below(64) as usize));
+                    store.put(manager, k, Some(v.clone())).unwrap();
+                    model.insert(k, v);
+                }
+                4 | 5 => {
+                    store.delete(manager, k);
+                    model.remove(&k);
+                }
+                6 | 7 => {
+                    let got = store.get(manager, k);
+                    if got.as_ref() != model.get(&k) {
+                        return Err(format!(
+                            "op {}: get {} returned {:?}, expected {:?}",
+                            op,
+                            k,
+                            got,
+                            model.get(&k)
+                        ));
+                    }
+                }
+                8 => {
+                    let range = (
+                        random_bound(&mut rng, key_space),
+                        random_bound(&mut rng, key_space),
+                    );
+                    let got = store.scan(manager, range);
+                    let expected: Vec<(u64, String)> = match range {
+                        (
+                            Bound::Included(s) | Bound::Excluded(s),
+                            Bound::Included(e) | Bound::Excluded(e),
+                        ) if s > e => Vec::new(),
+                        (Bound::Excluded(s), Bound::Excluded(e)) if s == e => Vec::new(),
+                        _ => model.range(range).map(|(k, v)| (*k, v.clone())).collect(),
+                    };
+                    if got != expected {
+                        return Err(format!(
+                            "op {}: scan {:?} returned {} entries, expected {}",
+                            op,
+                            range,
+                            got.len(),
+                            expected.len()
+                        ));
+                    }
+                }
+                _ => {
+                    if rng.below(ops as u64 / 100 + 1) == 0 {
+                        store.flush(manager);
+                        store = open(name.to_string(), manager);
+                    } else if rng.below(4) == 0 {
+                        store.compact(manager);
+                    }
+                }
+            }
+        }
+
+        store.flush(manager);
+        let store = open(name.to_string(), manager);
+        let all = store.scan(manager, (Bound::Unbounded, Bound::Unbounded));
+        let expected: Vec<(u64, String)> = model.into_iter().collect();
+        store.destroy(manager);
+        if all != expected {
+            return Err(format!(
+                "final scan returned {} entries, expected {}",
+                all.len(),
+                expected.len()
+            ));
+        }
+        Ok(())
+    }
+
+    const SEEDS: [u64; 4] = [1, 7, 0x5eed, 0xdead_beef];
+
+    // small pages and memtables so a few thousand operations split nodes and flush
+    fn options() -> Options {
+        Options::default()
+            .memtable_budget(1 << 12)
+            .cache_size(1 << 16)
+    }
+
+    fn check(name: &str, open: Opener<MemStorage>, options: &Options) {
+        for seed in SEEDS {
+            let mut manager =
+                BufferManager::with_storage(options.cache_blocks(), MemStorage::new());
+            if let Err(e) = check_conformance(name, open, &mut manager, 3000, seed) {
+                panic!("{} with seed {}: {}", name, seed, e);
+            }
+        }
+    }
+
+    #[test]
+    fn lsm_trees_behave_like_a_map() {
+        let options = options();
+        check(
+            "conformance.lsm",
+            &|name, manager| {
+                Box::new(LSMTree::with_options(name, manager, &options, None).unwrap())
+            },
+            &options,
+        );
+    }
+
+    #[test]
+    fn bplus_trees_behave_like_a_map() {
+        let options = options();
+        check(
+            "conformance.btree",
+            &|name, manager| Box::new(BPlusTree::with_options(name, manager, &options).unwrap()),
+            &options,
+        );
+    }
+}
@@ -1,262 +1,805 @@
 use std::{
+    cell::RefCell,
+    cmp::Ordering,
     collections::{btree_map::IntoIter, BTreeMap},
-    fmt::Debug,
-    fs::{create_dir, remove_file, rename},
-    ops::Bound,
+    fmt::{self, Debug},
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    rc::Rc,
 };
 
+use chrono::TimeDelta;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    buffer_manager::BufferManager,
+    aligned_buf::AlignedBuf,
+    bloom::{hash_key, Bloom},
+    buffer_manager::{Access, Block, BufferManager},
+    clock::{Clock, SystemClock},
+    compression::Compression,
+    data_block::{DataBlock, DataBlockBuilder, PageFormat},
     fixed::KnowsSize,
-    slotted_page::{decode, encode, SlottedPage},
-    BLOCK_SIZE,
+    kv_store::{EntryTooLarge, KeyRange, KeyValueStore, StoreStats},
+    manifest::{FamilyState, Manifest},
+    options::{Options, OptionsError, SyncPolicy},
+    slotted_page::{decode, encode, PageView, SlottedPage},
+    storage::{FileSystem, Storage, StorageFile},
+    wal::Wal,
 };
 
-pub struct LSMTree<K, V> {
-    memtable: BTreeMap<K, Option<V>>,
+#[derive(Debug)]
+pub struct LSMStats {
+    pub memtable_entries: usize,
+    pub memtable_bytes: usize,
+    pub disktable_pages: usize,
+    // what the pages take on disk, and would take uncompressed
+    pub disktable_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub merges: usize,
+    pub snapshots: usize,
+    pub retired_disktables: usize,
+    pub wal_bytes: u64,
+}
+
+impl LSMStats {
+    // uncompressed bytes per byte on disk, 1 for an empty disktable
+    pub fn compression_ratio(&self) -> f64 {
+        if self.disktable_bytes == 0 {
+            return 1.0;
+        }
+        self.uncompressed_bytes as f64 / self.disktable_bytes as f64
+    }
+}
+
+// the deadline of versions that do not expire
+const NEVER: i64 = i64::MAX;
+
+// one version of a key. Versions sort by key and then newest first, so the first entry
+// at or after (key, seq) is the newest version of key visible to a reader at seq. A value
+// reads as deleted from its deadline on, in milliseconds since the epoch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Version<K> {
+    pub key: K,
+    pub seq: u64,
+    pub expires: i64,
+}
+
+// where a merge is in the disktable: the entry it is at, the rest of that entry's page
+// and the page's number
+type DiskPosition<K, V> = (
+    (Version<K>, Option<V>),
+    IntoIter<Version<K>, Option<V>>,
+    usize,
+);
+
+impl<K: Ord> PartialEq for Version<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for Version<K> {}
+
+impl<K: Ord> Ord for Version<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<K: Ord> PartialOrd for Version<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: KnowsSize> KnowsSize for Version<K> {
+    fn bit_width() -> i16 {
+        match K::bit_width() {
+            -1 => -1,
+            n => n + 16,
+        }
+    }
+}
+
+// what a memtable version holds. Operands from merge_value stay as they are until the
+// version is read or merged to disk, so disktables only hold values and tombstones. An
+// operand keeps the time it was written at, to tell whether the value below it had
+// already expired
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Entry<V> {
+    Put(V),
+    Delete,
+    Merge(V, i64),
+}
+
+impl<V> From<Option<V>> for Entry<V> {
+    fn from(v: Option<V>) -> Self {
+        match v {
+            Some(v) => Entry::Put(v),
+            None => Entry::Delete,
+        }
+    }
+}
+
+// a write as it was asked for, LSMTree::write turns time-to-live into a deadline and
+// stamps operands with the time of the write
+enum Op<V> {
+    Put(V, Option<TimeDelta>),
+    Delete,
+    Merge(V),
+}
+
+impl<V> From<Option<V>> for Op<V> {
+    fn from(v: Option<V>) -> Self {
+        match v {
+            Some(v) => Op::Put(v, None),
+            None => Op::Delete,
+        }
+    }
+}
+
+// combines the value of a key with an operand written by LSMTree::merge_value. Operands
+// are applied oldest first, each to the result of the ones before it
+pub trait MergeOperator<K, V> {
+    // existing is None when the key has no value
+    fn merge(&self, key: &K, existing: Option<V>, operand: V) -> V;
+}
+
+#[derive(Debug)]
+pub enum TransactionError<K> {
+    Conflict(K),
+    TooLarge(EntryTooLarge),
+}
+
+impl<K: Debug> fmt::Display for TransactionError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::Conflict(k) => write!(
+                f,
+                "write conflict on {:?}, it was committed after this transaction began",
+                k
+            ),
+            TransactionError::TooLarge(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/*
+Disktable format:
+| header block | page 0 | page 1 | ... | page table |
+The header block is zero past
+| magic u32 | block size u32 | compression u8 | page count u32 | page table offset u64 | page format u8 |
+and the page table holds | offset u64 | length u32 | for every page. A page is a slotted
+page or a prefix data block of the block size, compressed with the codec of the file unless that did not make it
+shorter; a page of the full block size is stored as it is. A generation that was never
+written has no file at all.
+*/
+
+const DISKTABLE_MAGIC: u32 = 0x4c_53_4d_31;
+const DISKTABLE_HEADER: usize = 22;
+const PAGE_TABLE_ENTRY: usize = 12;
+
+// one generation of the disktable. Merges write the next generation instead of rewriting
+// this one, so its pages never change while a snapshot reads them
+pub struct Disktable<K, V, S: Storage = FileSystem> {
+    path: String,
+    file: Option<S::File>,
+    block_size: usize,
+    compression: Compression,
+    format: PageFormat,
+    // where every page starts in the file and how many bytes it takes there
+    pages: Vec<(u64, u32)>,
+    // the first key of every page and the page number
+    index: BTreeMap<Version<K>, usize>,
+    bloom: Bloom,
+    max_seq: u64,
+    _values: PhantomData<V>,
+}
+
+// a read-only view of the tree as it was when the snapshot was taken. It holds on to that
+// memtable and disktable generation, so later writes and merges do not show through; the
+// first write after a snapshot copies the memtable
+pub struct Snapshot<K, V, S: Storage = FileSystem> {
+    seq: u64,
+    memtable: Rc<BTreeMap<Version<K>, Entry<V>>>,
+    disktable: Rc<Disktable<K, V, S>>,
+    merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+    clock: Rc<dyn Clock>,
+}
+
+#[derive(Debug)]
+pub enum CasError<V> {
+    // the current value, which was not the expected one
+    Mismatch(Option<V>),
+    TooLarge(EntryTooLarge),
+}
+
+// puts, deletes and merge operands that LSMTree::write applies together: they go into the
+// write-ahead log as one record and take consecutive sequence numbers, and readers see all
+// of them or none
+pub struct WriteBatch<K, V> {
+    ops: Vec<(K, Op<V>)>,
+    // what the batch adds to the memtable
+    size: usize,
+}
+
+impl<K: Serialize, V: Serialize> WriteBatch<K, V> {
+    pub fn new() -> Self {
+        Self {
+            ops: Vec::new(),
+            size: 0,
+        }
+    }
+
+    pub fn put(&mut self, k: K, v: V) {
+        self.push(k, Op::Put(v, None));
+    }
+
+    // v reads as deleted once ttl has passed after the batch is written
+    pub fn put_with_ttl(&mut self, k: K, v: V, ttl: TimeDelta) {
+        self.push(k, Op::Put(v, Some(ttl)));
+    }
+
+    pub fn delete(&mut self, k: K) {
+        self.push(k, Op::Delete);
+    }
+
+    // needs the tree to have a merge operator
+    pub fn merge(&mut self, k: K, operand: V) {
+        self.push(k, Op::Merge(operand));
+    }
+
+    fn push(&mut self, k: K, op: Op<V>) {
+        let value = match &op {
+            Op::Put(v, _) | Op::Merge(v) => bincode::serialized_size(v).unwrap() as usize,
+            Op::Delete => 0,
+        };
+        // the key is stored as a Version, which adds the sequence number and deadline,
+        // and the entry adds its tag and an operand's write time
+        self.size += bincode::serialized_size(&k).unwrap() as usize + 16 + 12 + value;
+        self.ops.push((k, op));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<K: Serialize, V: Serialize> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// reads see the tree as of the snapshot plus the transaction's own writes, which are
+// buffered here until commit. The tree keeps the versions the transaction needs to detect
+// a conflict until it commits, rolls back or is dropped
+pub struct Transaction<K, V, S: Storage = FileSystem> {
+    snapshot: Snapshot<K, V, S>,
+    writes: BTreeMap<K, Option<V>>,
+    _pin: Pin,
+}
+
+// live transaction sequence numbers and how many transactions began at each
+type Pins = Rc<RefCell<BTreeMap<u64, usize>>>;
+
+// holds a sequence number among a tree's live ones until dropped
+struct Pin {
+    seq: u64,
+    pins: Pins,
+}
+
+impl Pin {
+    fn new(pins: &Pins, seq: u64) -> Self {
+        *pins.borrow_mut().entry(seq).or_insert(0) += 1;
+        Self {
+            seq,
+            pins: pins.clone(),
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.borrow_mut();
+        if let Some(n) = pins.get_mut(&self.seq) {
+            *n -= 1;
+            if *n == 0 {
+                pins.remove(&self.seq);
+            }
+        }
+    }
+}
+
+// where a tree logs its writes and records its merges
+enum Journal<F> {
+    // a log of its own for each disktable generation, replayed into the memtable on open
+    Own(Wal<F>),
+    // a column family of a Database, which logs the writes of all its families together
+    // and replays them. The manifest records up to which sequence number the live
+    // generation holds them
+    Family {
+        name: String,
+        manifest: Rc<RefCell<Manifest>>,
+    },
+}
+
+pub struct LSMTree<K, V, S: Storage = FileSystem> {
+    memtable: Rc<BTreeMap<Version<K>, Entry<V>>>,
     memtable_size: usize,
-    disktable: String,
-    disktable_index: BTreeMap<K, usize>,
+    // generation n of the disktable lives at path.n
+    path: String,
+    generation: u64,
+    disktable: Rc<Disktable<K, V, S>>,
+    // older generations still held by snapshots, deleted once the last one is dropped
+    retired: Vec<Rc<Disktable<K, V, S>>>,
+    journal: Journal<S::File>,
     merge_count: usize,
+    // the last sequence number handed out
+    seq: u64,
+    // the sequence numbers of live transactions, shared with them so dropping one
+    // releases it
+    snapshots: Pins,
+    merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+    clock: Rc<dyn Clock>,
+    options: Options,
 }
 
 impl<
         K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
         V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
-    > LSMTree<K, V>
+        S: Storage,
+    > LSMTree<K, V, S>
 {
-    pub fn new(name: String, manager: &mut BufferManager) -> Self {
-        let filepath = format!("disktables/{}", name);
-        match create_dir("disktables") {
-            Err(_) => {}
-            Ok(()) => {}
-        }
-        let mut s = Self {
-            memtable: BTreeMap::new(),
-            disktable: filepath,
-            memtable_size: 0,
-            disktable_index: BTreeMap::new(),
-            merge_count: 0,
-        };
-
-        s.build_index(manager);
-        s
+    pub fn new(name: String, manager: &mut BufferManager<S>) -> Self {
+        Self::with_options(name, manager, &Options::default(), None).unwrap()
     }
 
-    pub fn put(self: &mut Self, manager: &mut BufferManager, k: K, v: Option<V>) {
-        let encoded_k = bincode::serialize(&k).unwrap();
-        let key_size = encoded_k.len();
+    // the operator must be the same every time the tree is opened, operands written
+    // before it was last closed are folded with it
+    pub fn with_merge_operator(
+        name: String,
+        manager: &mut BufferManager<S>,
+        merge_operator: Rc<dyn MergeOperator<K, V>>,
+    ) -> Self {
+        Self::with_options(name, manager, &Options::default(), Some(merge_operator)).unwrap()
+    }
 
-        let encoded_v = bincode::serialize(&v).unwrap();
-        let val_size = encoded_v.len();
+    // opens the tree at name under the data directory of the options, which are checked
+    // first
+    pub fn with_options(
+        name: String,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+        merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let filepath = format!("{}/{}", options.data_dir, name);
+        let storage = manager.storage();
+        storage.create_dir_all(&options.data_dir).unwrap();
 
-        let res = self.memtable.insert(k, v);
-        match res {
-            None => {}
-            Some(x) => {
-                let old_val_size = bincode::serialize(&x).unwrap();
-                self.memtable_size -= old_val_size.len();
-            }
+        // the newest generation is the live one, together with its log
+        let generation = generations(storage, &filepath).pop().unwrap_or(0);
+        let (wal, records) = Wal::open(storage, wal_path(&filepath, generation), options.sync);
+        let mut s = Self::load(
+            filepath,
+            generation,
+            manager,
+            options,
+            merge_operator,
+            Journal::Own(wal),
+        );
+        for record in records {
+            s.apply_record(&record);
         }
+        Ok(s)
+    }
 
-        self.memtable_size += key_size + val_size;
+    // opens the column family of a database at name under the data directory, in the
+    // generation the manifest names. The database has checked the options and replays the
+    // family's writes from its log
+    pub(crate) fn open_family(
+        name: String,
+        family: &str,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+        manifest: Rc<RefCell<Manifest>>,
+        merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+    ) -> Self {
+        let filepath = format!("{}/{}", options.data_dir, name);
+        let state = manifest.borrow().family(family).unwrap_or_default();
+        let journal = Journal::Family {
+            name: family.to_string(),
+            manifest,
+        };
+        let mut s = Self::load(
+            filepath,
+            state.generation,
+            manager,
+            options,
+            merge_operator,
+            journal,
+        );
+        s.seq = s.seq.max(state.flushed_seq);
+        s
+    }
 
-        if self.memtable_size > manager.num_blocks * 2048 {
-            self.merge(manager);
-            self.build_index(manager);
+    fn load(
+        filepath: String,
+        generation: u64,
+        manager: &mut BufferManager<S>,
+        options: &Options,
+        merge_operator: Option<Rc<dyn MergeOperator<K, V>>>,
+        journal: Journal<S::File>,
+    ) -> Self {
+        remove_stale_files(manager.storage(), &filepath, generation);
+        let disktable = Disktable::open(manager, generation_path(&filepath, generation), options);
+        Self {
+            memtable: Rc::new(BTreeMap::new()),
+            memtable_size: 0,
+            path: filepath,
+            generation,
+            seq: disktable.max_seq,
+            disktable: Rc::new(disktable),
+            retired: Vec::new(),
+            journal,
+            merge_count: 0,
+            snapshots: Pins::default(),
+            merge_operator,
+            clock: Rc::new(SystemClock),
+            options: options.clone(),
         }
     }
 
-    fn build_index(self: &mut Self, manager: &mut BufferManager) {
-        let mut offset = 0;
+    // the time deadlines are checked against, the system clock unless replaced
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
 
-        while let Some(s) = self.get_page(&self.disktable, manager, offset) {
-            let Some((k, _)) = s.cells.first_key_value() else {
-                return;
-            };
-            self.disktable_index.insert(k.clone(), offset);
-            offset += BLOCK_SIZE;
+    // logs the batch as one record and applies it under consecutive sequence numbers. A
+    // merge needed to make room happens first, so it never splits the batch. A batch with
+    // an entry too large for a page is turned down as a whole
+    pub fn write(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        mut batch: WriteBatch<K, V>,
+    ) -> Result<(), EntryTooLarge> {
+        self.check_batch(manager, &mut batch)?;
+        if batch.is_empty() {
+            return Ok(());
         }
+        let (first, ops) = self.prepare(manager, batch);
+        let Journal::Own(wal) = &mut self.journal else {
+            panic!("column families are written through their database");
+        };
+        wal.append(&bincode::serialize(&(first, &ops)).unwrap());
+        self.apply(first, ops);
+        Ok(())
     }
 
-    pub fn get(self: &Self, manager: &mut BufferManager, k: K) -> Option<V> {
-        if let Some(x) = self.memtable.get(&k) {
-            return x.clone();
+    // what Database::write logs for this family, together with the other families
+    pub(crate) fn prepare_record(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        mut batch: WriteBatch<K, V>,
+    ) -> Result<Option<Vec<u8>>, EntryTooLarge> {
+        self.check_batch(manager, &mut batch)?;
+        if batch.is_empty() {
+            return Ok(None);
         }
+        let (first, ops) = self.prepare(manager, batch);
+        Ok(Some(bincode::serialize(&(first, &ops)).unwrap()))
+    }
 
-        let mut c = self.disktable_index.upper_bound(Bound::Included(&k));
-        let prev = c.prev().unwrap();
-        let block_offset: usize = *prev.1;
+    // every entry must fit in a page of its own, or merging it to disk would fail with
+    // the write already logged. An operand is checked as the value it folds into, which
+    // is what the merge writes. A key that does not fit was never written, so deleting it
+    // has nothing to do
+    fn check_batch(
+        &self,
+        manager: &mut BufferManager<S>,
+        batch: &mut WriteBatch<K, V>,
+    ) -> Result<(), EntryTooLarge> {
+        batch
+            .ops
+            .retain(|(k, op)| !matches!(op, Op::Delete) || self.check_entry(k, None).is_ok());
+        let snapshot = self.snapshot();
+        // the values the batch leaves its keys with so far
+        let mut folded: BTreeMap<K, Option<V>> = BTreeMap::new();
+        for (k, op) in batch.ops.iter() {
+            match (op, &self.merge_operator) {
+                (Op::Merge(operand), Some(merge_operator)) => {
+                    let existing = match folded.get(k) {
+                        Some(v) => v.clone(),
+                        None => snapshot.get(manager, k.clone()),
+                    };
+                    let v = merge_operator.merge(k, existing, operand.clone());
+                    self.check_entry(k, Some(&v))?;
+                    folded.insert(k.clone(), Some(v));
+                }
+                (Op::Put(v, _) | Op::Merge(v), _) => {
+                    self.check_entry(k, Some(v))?;
+                    folded.insert(k.clone(), Some(v.clone()));
+                }
+                (Op::Delete, _) => {
+                    folded.insert(k.clone(), None);
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let Some(block) = manager.get(self.disktable.clone(), block_offset) else {
-            return None;
+    // tries the entry on an empty page of the format new generations are written in
+    fn check_entry(&self, k: &K, v: Option<&V>) -> Result<(), EntryTooLarge> {
+        let version = Version {
+            key: k.clone(),
+            seq: 0,
+            expires: NEVER,
         };
-        let b = block.as_ref().borrow();
-        let s: SlottedPage<K, V> = decode(&b.bytes);
-        let v = s.cells.get(&k);
+        let mut page = PageBuilder::new(&self.options);
+        if page.add_cell(version, v.cloned()).is_ok() {
+            return Ok(());
+        }
+        Err(EntryTooLarge {
+            size: (bincode::serialized_size(k).unwrap() + bincode::serialized_size(&v).unwrap())
+                as usize,
+            block_size: self.options.block_size,
+        })
+    }
 
-        match v {
-            Some(Some(x)) => Some(x.clone()),
-            Some(None) => None,
-            None => None,
+    // applies a logged record, unless the disktable already holds it
+    pub(crate) fn apply_record(&mut self, record: &[u8]) {
+        let (first, ops): (u64, Vec<(K, i64, Entry<V>)>) = bincode::deserialize(record).unwrap();
+        if first > self.seq {
+            self.apply(first, ops);
         }
     }
 
-    pub fn get_page(
-        self: &Self,
-        file: &String,
-        manager: &mut BufferManager,
-        offset: usize,
-    ) -> Option<SlottedPage<K, V>> {
-        let block_option = manager.get(file.clone(), offset);
-        if let None = block_option {
-            return None;
+    // turns the batch into entries, numbered from the returned sequence number on
+    fn prepare(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        batch: WriteBatch<K, V>,
+    ) -> (u64, Vec<(K, i64, Entry<V>)>) {
+        if self.merge_operator.is_none() {
+            assert!(
+                !batch.ops.iter().any(|(_, op)| matches!(op, Op::Merge(_))),
+                "merge operands need a tree opened with a merge operator"
+            );
+        }
+        if self.memtable_size + batch.size > self.options.memtable_budget {
+            self.merge(manager);
         }
 
-        match block_option {
-            None => None,
-            Some(block) => {
-                let block_bytes = &block.as_ref().borrow().bytes;
-                let page = decode(block_bytes);
-                return Some(page);
-            }
+        let now = self.clock.now().timestamp_millis();
+        let ops: Vec<(K, i64, Entry<V>)> = batch
+            .ops
+            .into_iter()
+            .map(|(k, op)| match op {
+                Op::Put(v, None) => (k, NEVER, Entry::Put(v)),
+                Op::Put(v, Some(ttl)) => {
+                    (k, now.saturating_add(ttl.num_milliseconds()), Entry::Put(v))
+                }
+                Op::Delete => (k, NEVER, Entry::Delete),
+                Op::Merge(operand) => (k, NEVER, Entry::Merge(operand, now)),
+            })
+            .collect();
+        (self.seq + 1, ops)
+    }
+
+    fn apply(&mut self, first: u64, ops: Vec<(K, i64, Entry<V>)>) {
+        self.seq = first + ops.len() as u64 - 1;
+        for (i, (k, expires, v)) in ops.into_iter().enumerate() {
+            self.write_version(k, first + i as u64, expires, v);
+        }
+    }
+
+    // v reads as deleted once ttl has passed, until then it behaves like any other value
+    pub fn put_with_ttl(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        v: V,
+        ttl: TimeDelta,
+    ) -> Result<(), EntryTooLarge> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(k, v, ttl);
+        self.write(manager, batch)
+    }
+
+    // records an operand for k without reading it, the merge operator folds it into the
+    // value of k when k is read or merged to disk
+    pub fn merge_value(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        operand: V,
+    ) -> Result<(), EntryTooLarge> {
+        let mut batch = WriteBatch::new();
+        batch.merge(k, operand);
+        self.write(manager, batch)
+    }
+
+    pub fn snapshot(&self) -> Snapshot<K, V, S> {
+        Snapshot {
+            seq: self.seq,
+            memtable: self.memtable.clone(),
+            disktable: self.disktable.clone(),
+            merge_operator: self.merge_operator.clone(),
+            clock: self.clock.clone(),
         }
     }
 
+    // deletes retired generations that no snapshot holds any more
+    fn reap(&mut self, manager: &mut BufferManager<S>) {
+        self.retired.retain(|d| {
+            if Rc::strong_count(d) > 1 {
+                return true;
+            }
+            manager.remove(&d.path);
+            let _ = manager.storage().delete(&d.path);
+            false
+        });
+    }
+
     fn get_next_disk(
-        self: &Self,
-        manager: &mut BufferManager,
-        iter_option: Option<IntoIter<K, Option<V>>>,
-        mut offset: usize,
-    ) -> Option<((K, Option<V>), IntoIter<K, Option<V>>, usize)> {
+        &self,
+        manager: &mut BufferManager<S>,
+        iter_option: Option<IntoIter<Version<K>, Option<V>>>,
+        mut page_no: usize,
+    ) -> Option<DiskPosition<K, V>> {
         match iter_option {
             Some(mut iter) => match iter.next() {
                 None => {
-                    offset += BLOCK_SIZE;
-                    let Some(page) = self.get_page(&self.disktable, manager, offset) else {
-                        return None;
-                    };
-                    iter = page.cells.into_iter();
-                    let Some(x) = iter.next() else {
-                        return None;
-                    };
-                    Some((x, iter, offset))
+                    page_no += 1;
+                    let page = self
+                        .disktable
+                        .get_page(manager, page_no, Access::Background)?;
+                    iter = page.into_iter();
+                    let x = iter.next()?;
+                    Some((x, iter, page_no))
                 }
-                Some(x) => Some((x, iter, offset)),
+                Some(x) => Some((x, iter, page_no)),
             },
             None => {
-                let Some(page) = self.get_page(&self.disktable, manager, 0) else {
-                    return None;
-                };
-                let mut iter = page.cells.into_iter();
-                let Some(x) = iter.next() else {
-                    return None;
-                };
+                let page = self.disktable.get_page(manager, 0, Access::Background)?;
+                let mut iter = page.into_iter();
+                let x = iter.next()?;
                 Some((x, iter, 0))
             }
         }
     }
 
     fn write_btreemap_to_disk(
-        self: &Self,
-        manager: &mut BufferManager,
-        mut btreemap_iter: IntoIter<K, Option<V>>,
+        &mut self,
+        manager: &mut BufferManager<S>,
+        btreemap_iter: IntoIter<Version<K>, Option<V>>,
     ) {
-        let tmpfilepath = format!("{}_merge", self.disktable);
-
-        let mut curr_s: SlottedPage<K, V> = SlottedPage::new();
-        let mut offset: usize = 0;
-        while let Some((k, v)) = btreemap_iter.next() {
-            let res = curr_s.add_cell(k, v);
-            match res {
-                Err((k, v)) => {
-                    let encoded_page = encode(&curr_s);
-                    manager.write(&tmpfilepath, offset, &encoded_page, BLOCK_SIZE as u32);
-                    offset += BLOCK_SIZE;
-                    curr_s = SlottedPage::new();
-                    match curr_s.add_cell(k, v) {
-                        Err((k, v)) => {
-                            panic!("Error add cell for values  {:?}, {:?}", k, v);
-                        }
-                        Ok(()) => {}
-                    };
+        let tmpfilepath = format!("{}_merge", self.path);
+        // new generations take the configured block size and codec, the header records them
+        let block_size = self.options.block_size;
+        let mut writer = DisktableWriter::create(
+            manager.storage(),
+            &tmpfilepath,
+            block_size,
+            self.options.compression,
+            self.options.page_format,
+        );
+
+        let mut curr_s = PageBuilder::new(&self.options);
+        for (k, v) in btreemap_iter {
+            if let Err((k, v)) = curr_s.add_cell(k, v) {
+                writer.add(curr_s.encode());
+                curr_s = PageBuilder::new(&self.options);
+                if let Err((k, v)) = curr_s.add_cell(k, v) {
+                    panic!("Error add cell for values  {:?}, {:?}", k, v);
                 }
-                Ok(()) => {}
-            };
+            }
         }
-        if curr_s.num_cells > 0 {
-            let encoded_page = encode(&curr_s);
-            manager.write(&tmpfilepath, offset, &encoded_page, BLOCK_SIZE as u32);
+        if !curr_s.is_empty() {
+            writer.add(curr_s.encode());
         }
-        remove_file(&self.disktable).unwrap();
-        rename(&tmpfilepath, &self.disktable).unwrap();
-        manager.rename(&tmpfilepath, &self.disktable);
 
-        return;
+        // the new generation takes over together with an empty log or a manifest entry
+        // saying what it holds, the old one stays until no snapshot reads it
+        writer.finish(self.options.sync);
+        self.generation += 1;
+        let path = generation_path(&self.path, self.generation);
+        manager.storage().rename(&tmpfilepath, &path).unwrap();
+        manager.rename(&tmpfilepath, &path);
+        let storage = manager.storage();
+        match &mut self.journal {
+            Journal::Own(wal) => {
+                let (new, _) = Wal::open(
+                    storage,
+                    wal_path(&self.path, self.generation),
+                    self.options.sync,
+                );
+                std::mem::replace(wal, new).destroy(storage);
+            }
+            Journal::Family { name, manifest } => {
+                let state = FamilyState {
+                    generation: self.generation,
+                    flushed_seq: self.seq,
+                };
+                manifest.borrow_mut().record(storage, name, state);
+            }
+        }
+        let disktable = Rc::new(Disktable::open(manager, path, &self.options));
+        let old = std::mem::replace(&mut self.disktable, disktable);
+        self.retired.push(old);
+        self.reap(manager);
     }
 
-    pub fn merge(self: &mut Self, manager: &mut BufferManager) {
+    pub fn merge(&mut self, manager: &mut BufferManager<S>) {
         self.merge_count += 1;
         let mut merged_btree = BTreeMap::new();
 
         let old_memtable = self.memtable.clone();
-        let mut memtable_iter = old_memtable.clone().into_iter();
-        self.memtable = BTreeMap::new();
+        let mut memtable_iter = (*old_memtable).clone().into_iter();
+        self.memtable = Rc::new(BTreeMap::new());
         self.memtable_size = 0;
 
-        let mut disktable_iter: IntoIter<K, Option<V>>;
+        let mut disktable_iter: IntoIter<Version<K>, Option<V>>;
 
-        let mut curr_offset = 0;
+        let mut curr_page = 0;
 
         let mut fetch_mem = false;
         let mut fetch_disk = false;
         let mut curr_disk;
 
-        (curr_disk, disktable_iter, curr_offset) =
-            match self.get_next_disk(manager, None, curr_offset) {
-                None => {
-                    self.write_btreemap_to_disk(manager, memtable_iter);
-                    return;
-                }
-                Some(x) => x,
-            };
+        (curr_disk, disktable_iter, curr_page) = match self.get_next_disk(manager, None, curr_page)
+        {
+            None => {
+                let pruned = self.prune(memtable_iter.collect());
+                self.write_btreemap_to_disk(manager, pruned.into_iter());
+                return;
+            }
+            Some(x) => x,
+        };
 
-        let mut curr_mem = memtable_iter.next().unwrap();
+        let Some(mut curr_mem) = memtable_iter.next() else {
+            return;
+        };
         'outer: loop {
             if fetch_mem {
                 let Some(next_mem) = memtable_iter.next() else {
                     if fetch_disk {
                         let Some((d, i, o)) =
-                            self.get_next_disk(manager, Some(disktable_iter), curr_offset)
+                            self.get_next_disk(manager, Some(disktable_iter), curr_page)
                         else {
                             break 'outer;
                         };
                         curr_disk = d;
                         disktable_iter = i;
-                        curr_offset = o;
+                        curr_page = o;
                     }
                     loop {
-                        merged_btree.insert(curr_disk.clone().0, curr_disk.clone().1);
+                        merged_btree.insert(curr_disk.clone().0, curr_disk.clone().1.into());
                         let Some((d, i, o)) =
-                            self.get_next_disk(manager, Some(disktable_iter), curr_offset)
+                            self.get_next_disk(manager, Some(disktable_iter), curr_page)
                         else {
                             break 'outer;
                         };
                         curr_disk = d;
                         disktable_iter = i;
-                        curr_offset = o;
+                        curr_page = o;
                     }
                 };
                 curr_mem = next_mem;
             }
             if fetch_disk {
-                match self.get_next_disk(manager, Some(disktable_iter), curr_offset) {
+                match self.get_next_disk(manager, Some(disktable_iter), curr_page) {
                     Some((d, i, o)) => {
                         curr_disk = d;
                         disktable_iter = i;
-                        curr_offset = o;
+                        curr_page = o;
                     }
                     None => loop {
                         merged_btree.insert(curr_mem.clone().0, curr_mem.clone().1);
@@ -280,15 +823,1376 @@ impl<
                 fetch_mem = true;
                 fetch_disk = false;
             } else {
-                merged_btree.insert(curr_disk.clone().0, curr_disk.clone().1);
+                merged_btree.insert(curr_disk.clone().0, curr_disk.clone().1.into());
                 fetch_mem = false;
                 fetch_disk = true;
             }
         }
 
-        let merged_iter = merged_btree.into_iter();
+        let merged_iter = self.prune(merged_btree).into_iter();
 
         self.write_btreemap_to_disk(manager, merged_iter);
+    }
+
+    // starts a transaction reading the tree as of now
+    pub fn begin(&mut self) -> Transaction<K, V, S> {
+        Transaction {
+            snapshot: self.snapshot(),
+            writes: BTreeMap::new(),
+            _pin: Pin::new(&self.snapshots, self.seq),
+        }
+    }
+
+    // applies the writes of the transaction as one batch, unless another write to one of
+    // its keys was committed after it began
+    pub fn commit(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        tx: Transaction<K, V, S>,
+    ) -> Result<(), TransactionError<K>> {
+        let Transaction {
+            snapshot, writes, ..
+        } = tx;
+        let latest = self.snapshot();
+        for k in writes.keys() {
+            if let Some((seq, _)) = latest.version(manager, k) {
+                if seq > snapshot.seq {
+                    return Err(TransactionError::Conflict(k.clone()));
+                }
+            }
+        }
+
+        // the snapshots share the memtable, which the write would copy while they live
+        drop((latest, snapshot));
+        let mut batch = WriteBatch::new();
+        for (k, v) in writes {
+            batch.push(k, v.into());
+        }
+        self.write(manager, batch)
+            .map_err(TransactionError::TooLarge)
+    }
+
+    // the same as dropping the transaction
+    pub fn rollback(&mut self, tx: Transaction<K, V, S>) {
+        drop(tx);
+    }
+
+    // adds a version to the memtable, replacing the previous memtable version of the key
+    // when no snapshot can still see it. An operand over a value it replaces is folded
+    // right away, one over another operand keeps it as nothing below them is known yet
+    fn write_version(&mut self, k: K, seq: u64, mut expires: i64, mut v: Entry<V>) {
+        let newest = Version {
+            key: k.clone(),
+            seq: u64::MAX,
+            expires: NEVER,
+        };
+        let previous = self
+            .memtable
+            .range(&newest..)
+            .next()
+            .filter(|(p, _)| p.key == k)
+            .map(|(p, _)| p.clone());
+        if let Some(p) = previous {
+            let stacked = matches!(
+                (&v, &self.memtable[&p]),
+                (Entry::Merge(..), Entry::Merge(..))
+            );
+            if !stacked && self.snapshots.borrow().range(p.seq..).next().is_none() {
+                let old = Rc::make_mut(&mut self.memtable).remove(&p).unwrap();
+                self.memtable_size -= bincode::serialized_size(&p).unwrap() as usize
+                    + bincode::serialized_size(&old).unwrap() as usize;
+                if let Entry::Merge(operand, written) = v {
+                    let below = match old {
+                        Entry::Put(value) => (Some(value), p.expires),
+                        _ => (None, NEVER),
+                    };
+                    let (value, deadline) =
+                        apply_operands(&self.merge_operator, &k, below, vec![(operand, written)]);
+                    v = Entry::Put(value.unwrap());
+                    expires = deadline;
+                }
+            }
+        }
+
+        let version = Version {
+            key: k,
+            seq,
+            expires,
+        };
+        self.memtable_size += bincode::serialized_size(&version).unwrap() as usize
+            + bincode::serialized_size(&v).unwrap() as usize;
+        Rc::make_mut(&mut self.memtable).insert(version, v);
+    }
+
+    // folds every operand into the value below it, turns expired values into tombstones
+    // and drops versions no reader can see any more. For each key the newest version
+    // stays, as does the newest version at or below each live snapshot. The oldest versions that are tombstones go too, since
+    // nothing older is left for them to hide, unless a running transaction still needs
+    // them to detect a conflict. The entries must hold every version of their keys
+    fn prune(&self, entries: BTreeMap<Version<K>, Entry<V>>) -> BTreeMap<Version<K>, Option<V>> {
+        let snapshots = self.snapshots.borrow();
+        let oldest_snapshot = snapshots.keys().next().copied();
+        let now = self.clock.now().timestamp_millis();
+        let mut kept = BTreeMap::new();
+        let mut flush = |group: &mut Vec<(Version<K>, Entry<V>)>| {
+            let mut below = (None, NEVER);
+            let mut resolved: Vec<(Version<K>, Option<V>)> = group
+                .drain(..)
+                .rev()
+                .map(|(mut v, entry)| {
+                    below = match entry {
+                        Entry::Put(value) => (Some(value), v.expires),
+                        Entry::Delete => (None, NEVER),
+                        Entry::Merge(operand, written) => apply_operands(
+                            &self.merge_operator,
+                            &v.key,
+                            std::mem::replace(&mut below, (None, NEVER)),
+                            vec![(operand, written)],
+                        ),
+                    };
+                    let value = live(below.clone(), now);
+                    v.expires = if value.is_some() { below.1 } else { NEVER };
+                    (v, value)
+                })
+                .collect();
+            resolved.reverse();
+
+            let mut versions: Vec<(Version<K>, Option<V>)> = Vec::new();
+            for (v, value) in resolved {
+                match versions.last() {
+                    Some((newer, _)) if snapshots.range(v.seq..newer.seq).next().is_none() => {}
+                    _ => versions.push((v, value)),
+                }
+            }
+            while let Some((v, None)) = versions.last() {
+                if oldest_snapshot.is_some_and(|s| v.seq > s) {
+                    break;
+                }
+                versions.pop();
+            }
+            kept.extend(versions);
+        };
+
+        let mut group: Vec<(Version<K>, Entry<V>)> = Vec::new();
+        for (v, entry) in entries.into_iter() {
+            if group.last().is_some_and(|(newer, _)| newer.key != v.key) {
+                flush(&mut group);
+            }
+            group.push((v, entry));
+        }
+        flush(&mut group);
+        kept
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug + PartialEq,
+        S: Storage,
+    > LSMTree<K, V, S>
+{
+    // writes new, None deleting k, only if the current value of k is expected, None
+    // meaning k is absent. On a mismatch nothing is written and the current value is
+    // returned. The tree is only used from one thread, so nothing can slip in between
+    // the read and the write
+    pub fn compare_and_swap(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> Result<(), CasError<V>> {
+        let current = self.get(manager, k.clone());
+        if current != expected {
+            return Err(CasError::Mismatch(current));
+        }
+        self.put(manager, k, new).map_err(CasError::TooLarge)
+    }
+
+    // writes v only if k is absent, otherwise returns the current value as a mismatch
+    pub fn put_if_absent(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        v: V,
+    ) -> Result<(), CasError<V>> {
+        self.compare_and_swap(manager, k, None, Some(v))
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > Disktable<K, V, S>
+{
+    // reads the page table, then the first key of every page into the index and every key
+    // into the bloom filter
+    fn open(manager: &mut BufferManager<S>, path: String, options: &Options) -> Self {
+        let bloom_bits_per_key = options.bloom_bits_per_key;
+        let file = manager.storage().open(&path, false).ok();
+        let mut block_size = options.block_size;
+        let mut compression = Compression::None;
+        let mut format = PageFormat::Slotted;
+        let mut pages = Vec::new();
+        if let Some(f) = &file {
+            let mut header = [0; DISKTABLE_HEADER];
+            f.read_at(&mut header, 0).unwrap();
+            if header[0..4] != DISKTABLE_MAGIC.to_le_bytes() {
+                panic!("{} is not a disktable", path);
+            }
+            block_size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+            compression = Compression::from_id(header[8])
+                .unwrap_or_else(|| panic!("{} has an unknown compression {}", path, header[8]));
+            let count = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+            let table_offset = u64::from_le_bytes(header[13..21].try_into().unwrap());
+            format = PageFormat::from_id(header[21])
+                .unwrap_or_else(|| panic!("{} has an unknown page format {}", path, header[21]));
+
+            let mut table = vec![0; count * PAGE_TABLE_ENTRY];
+            f.read_at(&mut table, table_offset).unwrap();
+            pages = table
+                .chunks(PAGE_TABLE_ENTRY)
+                .map(|e| {
+                    let offset = u64::from_le_bytes(e[0..8].try_into().unwrap());
+                    let len = u32::from_le_bytes(e[8..12].try_into().unwrap());
+                    (offset, len)
+                })
+                .collect();
+        }
+
+        let mut d = Self {
+            path,
+            file,
+            block_size,
+            compression,
+            format,
+            pages,
+            index: BTreeMap::new(),
+            bloom: Bloom::new(&[], 0),
+            max_seq: 0,
+            _values: PhantomData,
+        };
+        let mut page_no = 0;
+        let mut key_hashes = Vec::new();
+        let mut last_key = None;
+
+        while let Some(s) = d.get_page(manager, page_no, Access::Background) {
+            let Some((k, _)) = s.first_key_value() else {
+                break;
+            };
+            d.index.insert(k.clone(), page_no);
+            if let Some(max) = s.keys().map(|v| v.seq).max() {
+                d.max_seq = d.max_seq.max(max);
+            }
+            if bloom_bits_per_key > 0 {
+                for v in s.into_keys() {
+                    if last_key.as_ref() != Some(&v.key) {
+                        key_hashes.push(hash_key(&v.key));
+                        last_key = Some(v.key);
+                    }
+                }
+            }
+            page_no += 1;
+        }
+        d.bloom = Bloom::new(&key_hashes, bloom_bits_per_key);
+        d
+    }
+
+    pub fn get_page(
+        &self,
+        manager: &mut BufferManager<S>,
+        page_no: usize,
+        access: Access,
+    ) -> Option<BTreeMap<Version<K>, Option<V>>> {
+        let block = self.load_page(manager, page_no, access)?;
+        let bytes = &block.as_ref().borrow().bytes;
+        let page = match self.format {
+            PageFormat::Slotted => decode(bytes).cells,
+            PageFormat::Prefix => DataBlock::new(bytes).decode(),
+        };
+        Some(page)
+    }
+
+    // the first entry of the page at or after target, None when there is no such page
+    fn seek_page(
+        &self,
+        manager: &mut BufferManager<S>,
+        page_no: usize,
+        target: &Version<K>,
+    ) -> Option<Option<(Version<K>, Option<V>)>> {
+        let block = self.load_page(manager, page_no, Access::Random)?;
+        let bytes = &block.as_ref().borrow().bytes;
+        let entry = match self.format {
+            PageFormat::Slotted => PageView::new(bytes).seek(target),
+            PageFormat::Prefix => DataBlock::new(bytes).seek(target),
+        };
+        Some(entry)
+    }
+
+    // on a miss of a sequential reader, reads the page together with those of the next
+    // batch that are not cached either
+    fn load_page(
+        &self,
+        manager: &mut BufferManager<S>,
+        page_no: usize,
+        access: Access,
+    ) -> Option<Rc<RefCell<Block>>> {
+        if page_no >= self.pages.len() {
+            return None;
+        }
+        let file = self.file.as_ref()?;
+        if let Some(block) = manager.cached(&self.path, self.cache_offset(page_no), access) {
+            return Some(block);
+        }
+
+        let ahead = match access {
+            Access::Random => 1,
+            _ => manager.read_batch_len(),
+        };
+        let end = (page_no + ahead).min(self.pages.len());
+        let wanted: Vec<usize> = (page_no..end)
+            .filter(|p| *p == page_no || !manager.is_cached(&self.path, self.cache_offset(*p)))
+            .collect();
+        let reads: Vec<(u64, usize)> = wanted
+            .iter()
+            .map(|p| (self.pages[*p].0, self.pages[*p].1 as usize))
+            .collect();
+        let stored = manager.read_batch(file, &reads);
+        let blocks = wanted
+            .into_iter()
+            .zip(stored)
+            .map(|(p, stored)| {
+                let bytes = if stored.len() == self.block_size {
+                    stored
+                } else {
+                    AlignedBuf::from_slice(&self.compression.decompress(&stored, self.block_size))
+                };
+                (self.cache_offset(p), bytes)
+            })
+            .collect();
+        manager
+            .insert(&self.path, blocks, access)
+            .into_iter()
+            .next()
+    }
+
+    // pages are cached decompressed, under the offset they would have if none were
+    // compressed
+    fn cache_offset(&self, page_no: usize) -> usize {
+        (page_no + 1) * self.block_size
+    }
+
+    // the bytes its pages take on disk and would take uncompressed
+    fn sizes(&self) -> (u64, u64) {
+        let stored = self.pages.iter().map(|(_, len)| *len as u64).sum();
+        (stored, (self.pages.len() * self.block_size) as u64)
+    }
+}
+
+// writes a disktable generation front to back. It goes around the buffer manager, since
+// compressed pages do not sit at block offsets
+struct DisktableWriter<F> {
+    file: F,
+    block_size: usize,
+    compression: Compression,
+    format: PageFormat,
+    pages: Vec<(u64, u32)>,
+    len: u64,
+}
+
+impl<F: StorageFile> DisktableWriter<F> {
+    fn create<S: Storage<File = F>>(
+        storage: &S,
+        path: &str,
+        block_size: usize,
+        compression: Compression,
+        format: PageFormat,
+    ) -> Self {
+        // a merge that did not finish may have left a file behind
+        let file = storage.open(path, true).unwrap();
+        file.set_len(0).unwrap();
+        // the header is written last, once the page table is known
+        file.write_at(&vec![0; block_size], 0).unwrap();
+        Self {
+            file,
+            block_size,
+            compression,
+            format,
+            pages: Vec::new(),
+            len: block_size as u64,
+        }
+    }
+
+    fn add(&mut self, page: Vec<u8>) {
+        let mut stored = self.compression.compress(&page);
+        if stored.len() >= self.block_size {
+            stored = page;
+        }
+        self.file.write_at(&stored, self.len).unwrap();
+        self.pages.push((self.len, stored.len() as u32));
+        self.len += stored.len() as u64;
+    }
+
+    fn finish(self, sync: SyncPolicy) {
+        let mut table = Vec::with_capacity(self.pages.len() * PAGE_TABLE_ENTRY);
+        for (offset, len) in self.pages.iter() {
+            table.extend(offset.to_le_bytes());
+            table.extend(len.to_le_bytes());
+        }
+        let file = self.file;
+        file.write_at(&table, self.len).unwrap();
+
+        let mut header = vec![0; DISKTABLE_HEADER];
+        header[0..4].copy_from_slice(&DISKTABLE_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        header[8] = self.compression.id();
+        header[9..13].copy_from_slice(&(self.pages.len() as u32).to_le_bytes());
+        header[13..21].copy_from_slice(&self.len.to_le_bytes());
+        header[21] = self.format.id();
+        file.write_at(&header, 0).unwrap();
+        if sync == SyncPolicy::Always {
+            file.sync().unwrap();
+        }
+    }
+}
+
+// the page a merge is filling, in the format of the options
+enum PageBuilder<K, V> {
+    Slotted(SlottedPage<K, V>),
+    Prefix(DataBlockBuilder<K, V>),
+}
+
+impl<K: Serialize + KnowsSize + Ord + Debug, V: Serialize + KnowsSize> PageBuilder<K, V> {
+    fn new(options: &Options) -> Self {
+        match options.page_format {
+            PageFormat::Slotted => PageBuilder::Slotted(SlottedPage::new(options.block_size)),
+            PageFormat::Prefix => PageBuilder::Prefix(DataBlockBuilder::new(
+                options.block_size,
+                options.restart_interval,
+            )),
+        }
+    }
+
+    fn add_cell(&mut self, k: K, v: Option<V>) -> Result<(), (K, Option<V>)> {
+        match self {
+            PageBuilder::Slotted(page) => page.add_cell(k, v),
+            PageBuilder::Prefix(block) => block.add_cell(k, v),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            PageBuilder::Slotted(page) => page.num_cells == 0,
+            PageBuilder::Prefix(block) => block.num_cells == 0,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            PageBuilder::Slotted(page) => encode(page),
+            PageBuilder::Prefix(block) => block.encode(),
+        }
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > Snapshot<K, V, S>
+{
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, manager: &mut BufferManager<S>, k: K) -> Option<V> {
+        self.version(manager, &k)?.1
+    }
+
+    // the newest visible version of k, with its sequence number and operands folded into
+    // its value, which is None once expired. Memtable versions are always newer than
+    // disktable ones, so the memtable is checked first and the disktable only when it
+    // holds nothing but operands
+    fn version(&self, manager: &mut BufferManager<S>, k: &K) -> Option<(u64, Option<V>)> {
+        let now = self.clock.now().timestamp_millis();
+        let target = Version {
+            key: k.clone(),
+            seq: self.seq,
+            expires: NEVER,
+        };
+        let mut newest = None;
+        let mut operands = Vec::new();
+        for (v, entry) in self.memtable.range(&target..) {
+            if v.key != *k {
+                break;
+            }
+            let seq = *newest.get_or_insert(v.seq);
+            let below = match entry {
+                Entry::Put(value) => (Some(value.clone()), v.expires),
+                Entry::Delete => (None, NEVER),
+                Entry::Merge(operand, written) => {
+                    operands.push((operand.clone(), *written));
+                    continue;
+                }
+            };
+            let value = apply_operands(&self.merge_operator, k, below, operands);
+            return Some((seq, live(value, now)));
+        }
+
+        let below = self
+            .disktable_version(manager, &target)
+            .map(|(v, value)| (v.seq, (value, v.expires)));
+        match (newest, below) {
+            (None, below) => below.map(|(seq, value)| (seq, live(value, now))),
+            (Some(seq), below) => {
+                let below = below.map_or((None, NEVER), |(_, value)| value);
+                let value = apply_operands(&self.merge_operator, k, below, operands);
+                Some((seq, live(value, now)))
+            }
+        }
+    }
+
+    fn disktable_version(
+        &self,
+        manager: &mut BufferManager<S>,
+        target: &Version<K>,
+    ) -> Option<(Version<K>, Option<V>)> {
+        let k = &target.key;
+        if !self.disktable.bloom.may_contain(hash_key(k)) {
+            return None;
+        }
+        let mut c = self.disktable.index.upper_bound(Bound::Included(target));
+        let mut page_no = match c.prev() {
+            Some((_, o)) => *o,
+            None => 0,
+        };
+        // the version may sit at the start of the next page when this one ends with
+        // newer versions of the key
+        while let Some(entry) = self.disktable.seek_page(manager, page_no, target) {
+            if let Some((v, value)) = entry {
+                if v.key != *k {
+                    return None;
+                }
+                return Some((v, value));
+            }
+            page_no += 1;
+        }
+        None
+    }
+
+    // every live key in the range
+    pub fn scan(&self, manager: &mut BufferManager<S>, range: KeyRange<K>) -> Vec<(K, V)> {
+        if range_is_empty(&range) {
+            return Vec::new();
+        }
+        let now = self.clock.now().timestamp_millis();
+        let version = |key, seq| Version {
+            key,
+            seq,
+            expires: NEVER,
+        };
+        let start = match range.0 {
+            Bound::Included(key) => Bound::Included(version(key, u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded(version(key, 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.1 {
+            Bound::Included(key) => Bound::Included(version(key, 0)),
+            Bound::Excluded(key) => Bound::Excluded(version(key, u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        // the newest version of each key with its value and deadline
+        let mut visible: BTreeMap<K, (u64, (Option<V>, i64))> = BTreeMap::new();
+        let mut see = |v: Version<K>, value: Option<V>| {
+            if v.seq > self.seq {
+                return;
+            }
+            match visible.get(&v.key) {
+                Some((newest, _)) if *newest > v.seq => {}
+                _ => {
+                    visible.insert(v.key, (v.seq, (value, v.expires)));
+                }
+            }
+        };
+
+        let mut page_no = match &start {
+            Bound::Unbounded => 0,
+            Bound::Included(k) | Bound::Excluded(k) => {
+                let mut c = self.disktable.index.upper_bound(Bound::Included(k));
+                match c.prev() {
+                    Some((_, o)) => *o,
+                    None => 0,
+                }
+            }
+        };
+        'pages: while let Some(page) = self
+            .disktable
+            .get_page(manager, page_no, Access::Sequential)
+        {
+            if page.is_empty() {
+                break;
+            }
+            for (v, value) in page.into_iter() {
+                let past_end = match &end {
+                    Bound::Included(e) => v > *e,
+                    Bound::Excluded(e) => v >= *e,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    break 'pages;
+                }
+                if (start.as_ref(), end.as_ref()).contains(&v) {
+                    see(v, value);
+                }
+            }
+            page_no += 1;
+        }
+
+        // a key's memtable versions come newest first and are newer than its disktable
+        // version, so its operands fold into the first value below them
+        let mut versions = self
+            .memtable
+            .range((start, end))
+            .filter(|(v, _)| v.seq <= self.seq)
+            .peekable();
+        while let Some((v, entry)) = versions.next() {
+            let mut current = (v, entry);
+            let mut operands = Vec::new();
+            let below = loop {
+                match current.1 {
+                    Entry::Put(value) => break (Some(value.clone()), current.0.expires),
+                    Entry::Delete => break (None, NEVER),
+                    Entry::Merge(operand, written) => operands.push((operand.clone(), *written)),
+                }
+                match versions.next_if(|(older, _)| older.key == v.key) {
+                    Some(older) => current = older,
+                    None => {
+                        break visible
+                            .get(&v.key)
+                            .map_or((None, NEVER), |(_, value)| value.clone())
+                    }
+                }
+            };
+            while versions.next_if(|(older, _)| older.key == v.key).is_some() {}
+            let value = apply_operands(&self.merge_operator, &v.key, below, operands);
+            visible.insert(v.key.clone(), (v.seq, value));
+        }
+
+        visible
+            .into_iter()
+            .filter_map(|(k, (_, value))| live(value, now).map(|v| (k, v)))
+            .collect()
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > Transaction<K, V, S>
+{
+    pub fn get(&self, manager: &mut BufferManager<S>, k: K) -> Option<V> {
+        if let Some(v) = self.writes.get(&k) {
+            return v.clone();
+        }
+        self.snapshot.get(manager, k)
+    }
+
+    // None deletes the key
+    pub fn put(&mut self, k: K, v: Option<V>) {
+        self.writes.insert(k, v);
+    }
+
+    pub fn delete(&mut self, k: K) {
+        self.writes.insert(k, None);
+    }
+
+    pub fn scan(&self, manager: &mut BufferManager<S>, range: KeyRange<K>) -> Vec<(K, V)> {
+        let mut merged: BTreeMap<K, Option<V>> = self
+            .snapshot
+            .scan(manager, range.clone())
+            .into_iter()
+            .map(|(k, v)| (k, Some(v)))
+            .collect();
+        if !range_is_empty(&range) {
+            for (k, v) in self.writes.range(range) {
+                merged.insert(k.clone(), v.clone());
+            }
+        }
+        merged
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect()
+    }
+}
+
+impl<
+        K: Serialize + for<'a> Deserialize<'a> + Ord + Clone + KnowsSize + Debug,
+        V: Serialize + for<'a> Deserialize<'a> + Clone + KnowsSize + Debug,
+        S: Storage,
+    > KeyValueStore<K, V, S> for LSMTree<K, V, S>
+{
+    fn get(&self, manager: &mut BufferManager<S>, k: K) -> Option<V> {
+        self.snapshot().get(manager, k)
+    }
+
+    fn put(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        k: K,
+        v: Option<V>,
+    ) -> Result<(), EntryTooLarge> {
+        let mut batch = WriteBatch::new();
+        batch.push(k, v.into());
+        self.write(manager, batch)
+    }
+
+    fn fits(&self, k: &K, v: &V) -> Result<(), EntryTooLarge> {
+        self.check_entry(k, Some(v))
+    }
+
+    fn delete(&mut self, manager: &mut BufferManager<S>, k: K) {
+        // a key too large to put was never written
+        let _ = self.put(manager, k, None);
+    }
+
+    fn scan(&self, manager: &mut BufferManager<S>, range: KeyRange<K>) -> Vec<(K, V)> {
+        self.snapshot().scan(manager, range)
+    }
+
+    fn flush(&mut self, manager: &mut BufferManager<S>) {
+        self.merge(manager);
+        self.reap(manager);
+    }
+
+    // merges the memtable and then rewrites the disktable keeping only the versions
+    // that are still visible, which merge alone skips when the memtable is empty
+    fn compact(&mut self, manager: &mut BufferManager<S>) {
+        self.merge(manager);
+
+        let mut all = BTreeMap::new();
+        let mut page_no = 0;
+        while let Some(page) = self
+            .disktable
+            .get_page(manager, page_no, Access::Background)
+        {
+            if page.is_empty() {
+                break;
+            }
+            all.extend(page.into_iter().map(|(v, value)| (v, value.into())));
+            page_no += 1;
+        }
+
+        let live = self.prune(all);
+        self.write_btreemap_to_disk(manager, live.into_iter());
+    }
+
+    fn stats(&self, _manager: &mut BufferManager<S>) -> StoreStats {
+        let (stored, uncompressed) = self.disktable.sizes();
+        StoreStats::Lsm(LSMStats {
+            memtable_entries: self.memtable.len(),
+            memtable_bytes: self.memtable_size,
+            disktable_pages: self.disktable.index.len(),
+            disktable_bytes: stored,
+            uncompressed_bytes: uncompressed,
+            merges: self.merge_count,
+            snapshots: self.snapshots.borrow().values().sum(),
+            retired_disktables: self.retired.len(),
+            wal_bytes: match &self.journal {
+                Journal::Own(wal) => wal.len(),
+                Journal::Family { .. } => 0,
+            },
+        })
+    }
+
+    // deletes every disktable generation and the log and forgets any cached pages,
+    // snapshots of the tree must not be read afterwards
+    fn destroy(self: Box<Self>, manager: &mut BufferManager<S>) {
+        if let Journal::Own(wal) = self.journal {
+            wal.destroy(manager.storage());
+        }
+        for d in self.retired.iter().chain([&self.disktable]) {
+            manager.remove(&d.path);
+            let _ = manager.storage().delete(&d.path);
+        }
+    }
+}
+
+// folds operands, given newest first with the time each was written, into the value
+// below them and its deadline. The result keeps the deadline, except that a value which
+// had expired when an operand was written counts as no value
+fn apply_operands<K, V>(
+    merge_operator: &Option<Rc<dyn MergeOperator<K, V>>>,
+    k: &K,
+    below: (Option<V>, i64),
+    operands: Vec<(V, i64)>,
+) -> (Option<V>, i64) {
+    operands
+        .into_iter()
+        .rev()
+        .fold(below, |(existing, expires), (operand, written)| {
+            let merge_operator = merge_operator
+                .as_ref()
+                .expect("merge operands need a tree opened with a merge operator");
+            let (existing, expires) = if expires <= written {
+                (None, NEVER)
+            } else {
+                (existing, expires)
+            };
+            (Some(merge_operator.merge(k, existing, operand)), expires)
+        })
+}
+
+// the value unless its deadline has passed
+fn live<V>((value, expires): (Option<V>, i64), now: i64) -> Option<V> {
+    value.filter(|_| expires > now)
+}
+
+fn generation_path(path: &str, generation: u64) -> String {
+    format!("{}.{}", path, generation)
+}
+
+fn wal_path(path: &str, generation: u64) -> String {
+    format!("{}.{}.wal", path, generation)
+}
+
+// removes the other generations and their logs, left behind by snapshots that were still
+// open when the tree was last used or by a merge that did not finish
+fn remove_stale_files<S: Storage>(storage: &S, path: &str, generation: u64) {
+    let (dir, name) = path.rsplit_once('/').unwrap_or((".", path));
+    let Ok(entries) = storage.list(dir) else {
         return;
+    };
+    for file in entries {
+        let Some(rest) = file.strip_prefix(name).and_then(|r| r.strip_prefix('.')) else {
+            continue;
+        };
+        let g = rest.strip_suffix(".wal").unwrap_or(rest);
+        if g.parse::<u64>().is_ok_and(|g| g != generation) {
+            let _ = storage.delete(&format!("{}/{}", dir, file));
+        }
+    }
+}
+
+// the generations of the disktable at path that exist on disk, oldest first
+fn generations<S: Storage>(storage: &S, path: &str) -> Vec<u64> {
+    let (dir, name) = path.rsplit_once('/').unwrap_or((".", path));
+    let prefix = format!("{}.", name);
+    let mut found: Vec<u64> = match storage.list(dir) {
+        Ok(entries) => entries
+            .iter()
+            .filter_map(|file| file.strip_prefix(&prefix)?.parse().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    found.sort();
+    found
+}
+
+// BTreeMap::range panics on inverted bounds, so callers check first
+fn range_is_empty<K: Ord, R: RangeBounds<K>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{clock::ManualClock, storage::MemStorage};
+
+    // appends every operand to the value
+    struct Append;
+
+    impl MergeOperator<u64, String> for Append {
+        fn merge(&self, _key: &u64, existing: Option<String>, operand: String) -> String {
+            existing.unwrap_or_default() + &operand
+        }
+    }
+
+    #[test]
+    fn entries_too_large_for_a_page_are_not_logged() {
+        let storage = MemStorage::new();
+        let options = Options::default();
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree: LSMTree<u64, String, MemStorage> =
+            LSMTree::with_options("big".to_string(), &mut manager, &options, None).unwrap();
+        let big = "x".repeat(5000);
+        assert!(tree.put(&mut manager, 1, Some(big.clone())).is_err());
+        let mut batch = WriteBatch::new();
+        batch.put(2, big);
+        assert!(tree.write(&mut manager, batch).is_err());
+        tree.put(&mut manager, 3, Some("small".to_string()))
+            .unwrap();
+        tree.flush(&mut manager);
+        drop(tree);
+        manager.flush();
+
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let mut tree: LSMTree<u64, String, MemStorage> =
+            LSMTree::with_options("big".to_string(), &mut manager, &options, None).unwrap();
+        assert_eq!(tree.get(&mut manager, 1), None);
+        assert_eq!(tree.get(&mut manager, 2), None);
+        assert_eq!(tree.get(&mut manager, 3), Some("small".to_string()));
+        tree.flush(&mut manager);
+    }
+
+    #[test]
+    fn prefix_pages_read_back_through_the_tree() {
+        let storage = MemStorage::new();
+        let options = Options::default()
+            .memtable_budget(1 << 12)
+            .page_format(PageFormat::Prefix)
+            .restart_interval(8)
+            .compression(Compression::Lz4);
+        let path = |i: u64| format!("/srv/data/{:02}/{:04}.log", i % 7, i);
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree: LSMTree<String, u64, MemStorage> =
+            LSMTree::with_options("paths".to_string(), &mut manager, &options, None).unwrap();
+        for i in 0..3000 {
+            tree.put(&mut manager, path(i), Some(i)).unwrap();
+        }
+        for i in (0..3000).step_by(5) {
+            tree.put(&mut manager, path(i), None).unwrap();
+        }
+        tree.flush(&mut manager);
+        drop(tree);
+        manager.flush();
+
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let tree: LSMTree<String, u64, MemStorage> =
+            LSMTree::with_options("paths".to_string(), &mut manager, &options, None).unwrap();
+        match tree.stats(&mut manager) {
+            StoreStats::Lsm(stats) => assert!(stats.disktable_pages > 1),
+            _ => unreachable!(),
+        }
+        for i in 0..3000 {
+            let expected = (i % 5 != 0).then_some(i);
+            assert_eq!(tree.get(&mut manager, path(i)), expected);
+        }
+        let range = (
+            Bound::Included("/srv/data/03/".to_string()),
+            Bound::Excluded("/srv/data/04/".to_string()),
+        );
+        let scanned = tree.scan(&mut manager, range);
+        let mut expected: Vec<u64> = (0..3000).filter(|i| i % 7 == 3 && i % 5 != 0).collect();
+        expected.sort_by_key(|i| path(*i));
+        assert_eq!(
+            scanned.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn disktables_keep_the_block_size_they_were_written_with() {
+        let storage = MemStorage::new();
+        let options = Options::default()
+            .block_size(16 * 1024)
+            .memtable_budget(1 << 16);
+        let value = |k: u64| format!("{}-{}", k, "v".repeat(10000));
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree: LSMTree<u64, String, MemStorage> =
+            LSMTree::with_options("wide".to_string(), &mut manager, &options, None).unwrap();
+        // values a 4 KiB page turns down fit a 16 KiB one
+        assert!(tree.fits(&0, &value(0)).is_ok());
+        assert!(tree.fits(&0, &"v".repeat(options.block_size)).is_err());
+        for k in 0..20 {
+            tree.put(&mut manager, k, Some(value(k))).unwrap();
+        }
+        tree.flush(&mut manager);
+        drop(tree);
+        manager.flush();
+
+        for options in [options.clone(), Options::default()] {
+            let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+            let tree: LSMTree<u64, String, MemStorage> =
+                LSMTree::with_options("wide".to_string(), &mut manager, &options, None).unwrap();
+            match tree.stats(&mut manager) {
+                StoreStats::Lsm(stats) => assert!(stats.disktable_pages >= 20),
+                _ => unreachable!(),
+            }
+            for k in 0..20 {
+                assert_eq!(tree.get(&mut manager, k), Some(value(k)));
+            }
+            let scanned = tree.scan(&mut manager, (Bound::Included(5), Bound::Excluded(8)));
+            assert_eq!(scanned, (5..8).map(|k| (k, value(k))).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn operands_that_would_grow_a_value_past_a_page_are_turned_down() {
+        let storage = MemStorage::new();
+        let options = Options::default();
+        let open = |manager: &mut BufferManager<MemStorage>| -> LSMTree<u64, String, MemStorage> {
+            LSMTree::with_options(
+                "append".to_string(),
+                manager,
+                &options,
+                Some(Rc::new(Append)),
+            )
+            .unwrap()
+        };
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage.clone());
+        let mut tree = open(&mut manager);
+        let chunk = "x".repeat(500);
+        let mut accepted = String::new();
+        for _ in 0..options.block_size / chunk.len() + 1 {
+            if tree.merge_value(&mut manager, 1, chunk.clone()).is_err() {
+                break;
+            }
+            accepted += &chunk;
+        }
+        assert!(!accepted.is_empty() && accepted.len() < options.block_size);
+
+        // nor in a batch that puts the value first
+        let mut batch = WriteBatch::new();
+        batch.put(2, "y".repeat(accepted.len()));
+        batch.merge(2, chunk.clone());
+        batch.merge(2, chunk.clone());
+        assert!(tree.write(&mut manager, batch).is_err());
+        assert_eq!(tree.get(&mut manager, 2), None);
+
+        // the log holds only what was accepted, so reopening and merging to disk works
+        drop(tree);
+        let mut tree = open(&mut manager);
+        assert_eq!(tree.get(&mut manager, 1), Some(accepted.clone()));
+        tree.compact(&mut manager);
+        tree.flush(&mut manager);
+        drop(tree);
+        manager.flush();
+
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let tree = open(&mut manager);
+        assert_eq!(tree.get(&mut manager, 1), Some(accepted));
+    }
+
+    type Tree = LSMTree<u64, String, MemStorage>;
+
+    fn open(name: &str, manager: &mut BufferManager<MemStorage>) -> Tree {
+        LSMTree::with_options(
+            name.to_string(),
+            manager,
+            &Options::default(),
+            Some(Rc::new(Append)),
+        )
+        .unwrap()
+    }
+
+    // every version the tree holds, live or not, as key and sequence number
+    fn stored_versions(tree: &Tree, manager: &mut BufferManager<MemStorage>) -> Vec<(u64, u64)> {
+        let mut versions: BTreeSet<(u64, u64)> =
+            tree.memtable.keys().map(|v| (v.key, v.seq)).collect();
+        let mut page_no = 0;
+        while let Some(page) = tree.disktable.get_page(manager, page_no, Access::Random) {
+            versions.extend(page.keys().map(|v| (v.key, v.seq)));
+            page_no += 1;
+        }
+        versions.into_iter().collect()
+    }
+
+    fn stored_keys(tree: &Tree, manager: &mut BufferManager<MemStorage>) -> BTreeSet<u64> {
+        stored_versions(tree, manager)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect()
+    }
+
+    #[test]
+    fn values_expire_at_their_deadline_and_are_dropped_by_merges() {
+        let options = Options::default();
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), MemStorage::new());
+        let mut tree: LSMTree<u64, String, MemStorage> = LSMTree::with_options(
+            "ttl".to_string(),
+            &mut manager,
+            &options,
+            Some(Rc::new(Append)),
+        )
+        .unwrap();
+        let clock = Rc::new(ManualClock::new(
+            DateTime::from_timestamp(1 << 30, 0).unwrap(),
+        ));
+        tree.set_clock(clock.clone());
+        let ttl = TimeDelta::seconds(10);
+        let all = || (Bound::Unbounded, Bound::Unbounded);
+
+        // 1 expires in the memtable, 2 after it was merged to disk
+        tree.put_with_ttl(&mut manager, 2, "on disk".to_string(), ttl)
+            .unwrap();
+        tree.flush(&mut manager);
+        tree.put_with_ttl(&mut manager, 1, "in memory".to_string(), ttl)
+            .unwrap();
+        tree.put(&mut manager, 3, Some("kept".to_string())).unwrap();
+        assert_eq!(stored_keys(&tree, &mut manager), BTreeSet::from([1, 2, 3]));
+
+        clock.advance(ttl - TimeDelta::milliseconds(1));
+        assert_eq!(tree.get(&mut manager, 1), Some("in memory".to_string()));
+        assert_eq!(tree.get(&mut manager, 2), Some("on disk".to_string()));
+        assert_eq!(tree.scan(&mut manager, all()).len(), 3);
+
+        clock.advance(TimeDelta::milliseconds(1));
+        assert_eq!(tree.get(&mut manager, 1), None);
+        assert_eq!(tree.get(&mut manager, 2), None);
+        let live: Vec<u64> = tree
+            .scan(&mut manager, all())
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(live, vec![3]);
+
+        // merging the memtable to disk rewrites the disktable without either
+        tree.flush(&mut manager);
+        assert_eq!(stored_keys(&tree, &mut manager), BTreeSet::from([3]));
+
+        // with nothing in the memtable a flush leaves the disktable be, a compaction
+        // rewrites it
+        tree.put_with_ttl(&mut manager, 6, "on disk".to_string(), ttl)
+            .unwrap();
+        tree.flush(&mut manager);
+        clock.advance(ttl);
+        tree.flush(&mut manager);
+        assert_eq!(tree.get(&mut manager, 6), None);
+        assert_eq!(stored_keys(&tree, &mut manager), BTreeSet::from([3, 6]));
+        tree.compact(&mut manager);
+        assert_eq!(stored_keys(&tree, &mut manager), BTreeSet::from([3]));
+
+        // an operand folds into a value that is still live, and starts over from nothing
+        // on one that expired
+        tree.put_with_ttl(&mut manager, 4, "a".to_string(), ttl)
+            .unwrap();
+        tree.put_with_ttl(&mut manager, 5, "a".to_string(), ttl)
+            .unwrap();
+        tree.flush(&mut manager);
+        tree.merge_value(&mut manager, 4, "b".to_string()).unwrap();
+        clock.advance(ttl);
+        tree.merge_value(&mut manager, 5, "b".to_string()).unwrap();
+        assert_eq!(tree.get(&mut manager, 4), None);
+        assert_eq!(tree.get(&mut manager, 5), Some("b".to_string()));
+        tree.compact(&mut manager);
+        assert_eq!(tree.get(&mut manager, 5), Some("b".to_string()));
+        assert_eq!(stored_keys(&tree, &mut manager), BTreeSet::from([3, 5]));
+    }
+
+    #[test]
+    fn transactions_read_their_snapshot_and_their_own_writes() {
+        let mut manager = BufferManager::with_storage(64, MemStorage::new());
+        let mut tree = open("tx.read", &mut manager);
+        tree.put(&mut manager, 1, Some("old".to_string())).unwrap();
+        tree.put(&mut manager, 3, Some("gone".to_string())).unwrap();
+
+        let mut tx = tree.begin();
+        tree.put(&mut manager, 1, Some("new".to_string())).unwrap();
+        tree.put(&mut manager, 4, Some("later".to_string()))
+            .unwrap();
+        tree.flush(&mut manager);
+        tx.put(2, Some("mine".to_string()));
+        tx.delete(3);
+
+        assert_eq!(tx.get(&mut manager, 1), Some("old".to_string()));
+        assert_eq!(tx.get(&mut manager, 2), Some("mine".to_string()));
+        assert_eq!(tx.get(&mut manager, 3), None);
+        assert_eq!(tx.get(&mut manager, 4), None);
+        let seen = tx.scan(&mut manager, (Bound::Unbounded, Bound::Unbounded));
+        assert_eq!(seen, vec![(1, "old".to_string()), (2, "mine".to_string())]);
+        assert_eq!(tree.get(&mut manager, 2), None);
+
+        tree.commit(&mut manager, tx).unwrap();
+        assert_eq!(tree.get(&mut manager, 2), Some("mine".to_string()));
+        assert_eq!(tree.get(&mut manager, 3), None);
+    }
+
+    #[test]
+    fn transactions_conflict_on_keys_written_since_they_began() {
+        let mut manager = BufferManager::with_storage(64, MemStorage::new());
+        let mut tree = open("tx.conflict", &mut manager);
+        tree.put(&mut manager, 1, Some("a".to_string())).unwrap();
+
+        let mut first = tree.begin();
+        let mut second = tree.begin();
+        first.put(1, Some("first".to_string()));
+        second.put(1, Some("second".to_string()));
+        second.put(2, Some("second".to_string()));
+        tree.commit(&mut manager, first).unwrap();
+        assert!(matches!(
+            tree.commit(&mut manager, second),
+            Err(TransactionError::Conflict(1))
+        ));
+        assert_eq!(tree.get(&mut manager, 1), Some("first".to_string()));
+        assert_eq!(tree.get(&mut manager, 2), None);
+
+        // a delete merged to disk while the transaction runs still conflicts
+        let mut tx = tree.begin();
+        tx.put(1, Some("tx".to_string()));
+        tree.delete(&mut manager, 1);
+        tree.compact(&mut manager);
+        assert!(matches!(
+            tree.commit(&mut manager, tx),
+            Err(TransactionError::Conflict(1))
+        ));
+
+        // writes to other keys do not
+        let mut tx = tree.begin();
+        tx.put(5, Some("tx".to_string()));
+        tree.put(&mut manager, 6, Some("other".to_string()))
+            .unwrap();
+        tree.commit(&mut manager, tx).unwrap();
+        assert_eq!(tree.get(&mut manager, 5), Some("tx".to_string()));
+    }
+
+    #[test]
+    fn merges_keep_versions_only_while_a_transaction_needs_them() {
+        let mut manager = BufferManager::with_storage(64, MemStorage::new());
+        let mut tree = open("tx.prune", &mut manager);
+        tree.put(&mut manager, 1, Some("v1".to_string())).unwrap();
+        tree.put(&mut manager, 1, Some("v2".to_string())).unwrap();
+        tree.flush(&mut manager);
+        let v2 = tree.seq;
+        assert_eq!(stored_versions(&tree, &mut manager), vec![(1, v2)]);
+
+        // the version the transaction sees and the tombstone after it both stay
+        let tx = tree.begin();
+        tree.put(&mut manager, 1, Some("v3".to_string())).unwrap();
+        tree.delete(&mut manager, 1);
+        let deleted = tree.seq;
+        tree.compact(&mut manager);
+        assert_eq!(
+            stored_versions(&tree, &mut manager),
+            vec![(1, v2), (1, deleted)]
+        );
+
+        // a transaction that is dropped without ending lets them go
+        drop(tx);
+        assert!(tree.snapshots.borrow().is_empty());
+        tree.compact(&mut manager);
+        assert_eq!(stored_versions(&tree, &mut manager), vec![]);
+
+        // and so does one that unwinds
+        let pin = |tree: &mut Tree| -> Result<(), ()> {
+            let _tx = tree.begin();
+            Err(())
+        };
+        assert!(pin(&mut tree).is_err());
+        assert!(tree.snapshots.borrow().is_empty());
+    }
+
+    #[test]
+    fn snapshots_keep_their_view_and_generation_until_dropped() {
+        let storage = MemStorage::new();
+        let mut manager = BufferManager::with_storage(64, storage.clone());
+        let mut tree = open("snap", &mut manager);
+        for k in 0..100 {
+            tree.put(&mut manager, k, Some(format!("old {}", k)))
+                .unwrap();
+        }
+        tree.flush(&mut manager);
+        let held = generation_path(&tree.path, tree.generation);
+        let snapshot = tree.snapshot();
+        let all = || (Bound::Unbounded, Bound::Unbounded);
+        let before = snapshot.scan(&mut manager, all());
+        assert_eq!(before.len(), 100);
+
+        for k in 0..100 {
+            match k % 3 {
+                0 => tree.delete(&mut manager, k),
+                _ => tree
+                    .put(&mut manager, k, Some(format!("new {}", k)))
+                    .unwrap(),
+            }
+        }
+        tree.put(&mut manager, 1000, Some("added".to_string()))
+            .unwrap();
+        tree.flush(&mut manager);
+        tree.put(&mut manager, 2, Some("newer".to_string()))
+            .unwrap();
+        tree.compact(&mut manager);
+        assert_eq!(tree.get(&mut manager, 2), Some("newer".to_string()));
+        assert_eq!(tree.get(&mut manager, 3), None);
+
+        assert_eq!(snapshot.get(&mut manager, 2), Some("old 2".to_string()));
+        assert_eq!(snapshot.get(&mut manager, 3), Some("old 3".to_string()));
+        assert_eq!(snapshot.get(&mut manager, 1000), None);
+        assert_eq!(snapshot.scan(&mut manager, all()), before);
+        assert!(storage.open(&held, false).is_ok());
+
+        // the next merge deletes the generation once nothing holds it
+        drop(snapshot);
+        assert!(storage.open(&held, false).is_ok());
+        tree.flush(&mut manager);
+        assert!(storage.open(&held, false).is_err());
+        let live = generation_path(&tree.path, tree.generation);
+        assert!(storage.open(&live, false).is_ok());
+    }
+
+    #[test]
+    fn compare_and_swap_writes_only_over_the_expected_value() {
+        let mut manager = BufferManager::with_storage(64, MemStorage::new());
+        let mut tree = open("cas", &mut manager);
+        let some = |v: &str| Some(v.to_string());
+        tree.put(&mut manager, 1, some("a")).unwrap();
+
+        tree.compare_and_swap(&mut manager, 1, some("a"), some("b"))
+            .unwrap();
+        assert_eq!(tree.get(&mut manager, 1), some("b"));
+        assert!(matches!(
+            tree.compare_and_swap(&mut manager, 1, some("a"), some("c")),
+            Err(CasError::Mismatch(Some(v))) if v == "b"
+        ));
+        assert!(matches!(
+            tree.put_if_absent(&mut manager, 1, "c".to_string()),
+            Err(CasError::Mismatch(Some(v))) if v == "b"
+        ));
+        tree.compare_and_swap(&mut manager, 1, some("b"), None)
+            .unwrap();
+        assert_eq!(tree.get(&mut manager, 1), None);
+
+        // a tombstone in the memtable hides the value on disk below it
+        tree.put_if_absent(&mut manager, 1, "again".to_string())
+            .unwrap();
+        tree.flush(&mut manager);
+        tree.delete(&mut manager, 1);
+        assert!(matches!(tree.memtable.values().next(), Some(Entry::Delete)));
+        tree.compare_and_swap(&mut manager, 1, None, some("after delete"))
+            .unwrap();
+        assert_eq!(tree.get(&mut manager, 1), some("after delete"));
+
+        // a key only on disk
+        tree.put(&mut manager, 2, some("disk")).unwrap();
+        tree.flush(&mut manager);
+        assert!(tree.memtable.is_empty());
+        assert!(matches!(
+            tree.compare_and_swap(&mut manager, 2, None, some("x")),
+            Err(CasError::Mismatch(Some(v))) if v == "disk"
+        ));
+        tree.compare_and_swap(&mut manager, 2, some("disk"), some("swapped"))
+            .unwrap();
+        assert_eq!(tree.get(&mut manager, 2), some("swapped"));
+
+        // a pending operand counts as folded into the value below it
+        tree.flush(&mut manager);
+        tree.merge_value(&mut manager, 2, "+op".to_string())
+            .unwrap();
+        assert!(matches!(
+            tree.compare_and_swap(&mut manager, 2, some("swapped"), some("y")),
+            Err(CasError::Mismatch(Some(v))) if v == "swapped+op"
+        ));
+        tree.compare_and_swap(&mut manager, 2, some("swapped+op"), some("y"))
+            .unwrap();
+        tree.merge_value(&mut manager, 3, "op".to_string()).unwrap();
+        assert!(matches!(
+            tree.put_if_absent(&mut manager, 3, "z".to_string()),
+            Err(CasError::Mismatch(Some(v))) if v == "op"
+        ));
+        tree.flush(&mut manager);
+        assert_eq!(tree.get(&mut manager, 2), some("y"));
+        assert_eq!(tree.get(&mut manager, 3), some("op"));
     }
 }
@@ -1,52 +1,114 @@
 #![feature(btree_cursors)]
 
+pub mod aligned_buf;
+pub mod bloom;
+pub mod bplus_tree;
 pub mod buffer_manager;
+pub mod clock;
+pub mod compression;
+pub mod data_block;
+pub mod database;
+#[cfg(test)]
+pub mod fault_storage;
 pub mod fixed;
+pub mod heap_file;
+pub mod io_backend;
+pub mod key_encoding;
+pub mod kv_store;
 pub mod lsm_tree;
+pub mod manifest;
+pub mod options;
+pub mod repl;
 pub mod slotted_page;
+pub mod sql;
+pub mod storage;
 pub mod storage_engine;
+pub mod wal;
 
-use lsm_tree::LSMTree;
+use options::Options;
+use repl::Shell;
 
-use buffer_manager::BufferManager;
+const USAGE: &str = "\
+usage: NopeDB [--config <file>] [--exec <commands>]... [--file <script>] [script]
 
-const BLOCK_SIZE: usize = 4096;
+With no arguments an interactive shell is started on stdin.
+--exec runs the given commands and exits, --file or a bare path runs a script.
+--config reads options such as the data directory from a TOML file.";
 
-fn main() {
-    let avail_mem = usize::pow(2, 24);
-    let num_blocks = avail_mem / BLOCK_SIZE;
-    let mut manager: BufferManager = buffer_manager::BufferManager::new(num_blocks);
-
-    let mut l: LSMTree<u128, u128> = LSMTree::new("thing".to_string(), &mut manager);
+// what the shell runs instead of reading stdin, in the order given
+enum Script {
+    Exec(String),
+    File(String),
+}
 
-    for i in 0u128..1000000u128 {
-        l.put(&mut manager, i, Some(i + 1));
-        match l.get(&mut manager, i) {
-            None => {
-                panic!();
+fn main() {
+    let mut options = Options::default();
+    let mut scripts: Vec<Script> = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--exec" => match args.next() {
+                Some(commands) => scripts.push(Script::Exec(commands)),
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "-f" | "--file" => match args.next() {
+                Some(path) => scripts.push(Script::File(path)),
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "-c" | "--config" => match args.next() {
+                Some(path) => match Options::from_toml_file(&path) {
+                    Ok(o) => options = o,
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        std::process::exit(2);
+                    }
+                },
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
             }
-            Some(x) => {
-                assert_eq!(i + 1, x)
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                eprintln!("error: unknown option {}\n{}", flag, USAGE);
+                std::process::exit(2);
             }
+            path => scripts.push(Script::File(path.to_string())),
         }
     }
 
-    for i in 0u128..1000000u128 {
-        let tmp = l.get(&mut manager, i);
-        match tmp {
-            Some(x) => {
-                if x != i + 1 {
-                    println!("Incorrect value get for key {:?}, got {}", i, x);
-                    panic!();
-                }
-            }
-            None => {
-                panic!("got none for key {}", i);
-            }
+    let mut shell = match Shell::new(options) {
+        Ok(shell) => shell,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
         }
+    };
+    if scripts.is_empty() {
+        shell.run_interactive();
+        shell.close();
+        return;
     }
 
-    l.merge(&mut manager);
-    manager.flush();
-    println!("done!");
+    for script in scripts {
+        let res = match script {
+            Script::Exec(commands) => shell.run_script(&commands),
+            Script::File(path) => shell.run_file(&path),
+        };
+        if let Err(e) = res {
+            eprintln!("error: {}", e);
+            shell.close();
+            std::process::exit(1);
+        }
+    }
+    shell.close();
 }
@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    options::SyncPolicy,
+    storage::{Storage, StorageFile},
+};
+
+// what the manifest knows about one column family
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct FamilyState {
+    // the live disktable generation
+    pub generation: u64,
+    // every write up to here is in that generation, the log is replayed past it
+    pub flushed_seq: u64,
+}
+
+// the column families of a database. It is small, so every change rewrites all of it to a
+// temporary file that is renamed over the old one, and a crash leaves one or the other
+pub struct Manifest {
+    path: String,
+    families: BTreeMap<String, FamilyState>,
+    sync: SyncPolicy,
+}
+
+impl Manifest {
+    pub fn open<S: Storage>(storage: &S, path: String, sync: SyncPolicy) -> Self {
+        let families = match storage.open(&path, false) {
+            Ok(file) => {
+                let mut bytes = vec![0; file.len().unwrap() as usize];
+                file.read_at(&mut bytes, 0).unwrap();
+                bincode::deserialize(&bytes).unwrap()
+            }
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            path,
+            families,
+            sync,
+        }
+    }
+
+    pub fn family(&self, name: &str) -> Option<FamilyState> {
+        self.families.get(name).copied()
+    }
+
+    pub fn families(&self) -> impl Iterator<Item = &String> {
+        self.families.keys()
+    }
+
+    pub fn record<S: Storage>(&mut self, storage: &S, name: &str, state: FamilyState) {
+        self.families.insert(name.to_string(), state);
+        let tmp = format!("{}.tmp", self.path);
+        let file = storage.open(&tmp, true).unwrap();
+        let bytes = bincode::serialize(&self.families).unwrap();
+        file.set_len(0).unwrap();
+        file.write_at(&bytes, 0).unwrap();
+        if self.sync == SyncPolicy::Always {
+            file.sync().unwrap();
+        }
+        storage.rename(&tmp, &self.path).unwrap();
+    }
+}
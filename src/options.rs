@@ -0,0 +1,342 @@
+use std::{fmt, fs::read_to_string};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer_manager::DEFAULT_DIRTY_RATIO, compression::Compression, data_block::PageFormat,
+    io_backend::IoBackendKind,
+};
+
+// the range of block sizes, files record which one they were written with
+pub const MIN_BLOCK_SIZE: usize = 1 << 12;
+pub const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+// how the trees are tuned. Built with the setters below or read from a TOML file whose
+// keys are the field names, every key left out keeps its default:
+//
+//     data_dir = "/var/lib/nopedb"
+//     memtable_budget = 4194304
+//     compression = "lz4"
+//     page_format = "prefix"
+//     sync = "always"
+//     io_backend = "sync"
+//     direct_io = false
+//     dirty_ratio = 40
+//
+// Nothing is checked until a tree or database is opened with it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    // where every tree, log and database directory lives
+    pub(crate) data_dir: String,
+    // bytes of memtable entries a tree holds before it merges them to disk
+    pub(crate) memtable_budget: usize,
+    // bytes per page of the files created from now on, a power of two from 4 KiB to 64 KiB.
+    // Existing files keep the size they were written with
+    pub(crate) block_size: usize,
+    // bloom filter bits per disktable key, 0 turns the filters off
+    pub(crate) bloom_bits_per_key: usize,
+    // how the pages of disktables written from now on are compressed. A tree has a single
+    // disktable rather than levels, so one codec covers all of it
+    pub(crate) compression: Compression,
+    // the layout of new disktable pages. Prefix data blocks fit more keys that share
+    // their start, like paths, at the cost of decoding a few keys per lookup
+    pub(crate) page_format: PageFormat,
+    // how many entries of a prefix data block share prefixes before a key is stored whole
+    pub(crate) restart_interval: usize,
+    pub(crate) sync: SyncPolicy,
+    // bytes of blocks the buffer manager caches
+    pub(crate) cache_size: usize,
+    pub(crate) io_backend: IoBackendKind,
+    // whether blocks bypass the operating system's cache, which only holds them twice.
+    // Files on a filesystem without O_DIRECT use the cache either way
+    pub(crate) direct_io: bool,
+    // the percentage of cached blocks that may be dirty, from 1 to 100. Writes that
+    // reach it wait for dirty blocks to be written back
+    pub(crate) dirty_ratio: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    // writes reach the operating system before they return, so they survive the process
+    // dying but not the machine losing power
+    Never,
+    // writes are also synced to disk before they return, and so are flushed disktables
+    // and manifests before they replace the old ones
+    Always,
+}
+
+#[derive(Debug)]
+pub enum OptionsError {
+    Read(String, String),
+    Parse(String, String),
+    BlockSize(usize),
+    MemtableBudget(usize),
+    BloomBits(usize),
+    CacheSize(usize),
+    RestartInterval(usize),
+    DirtyRatio(usize),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionsError::Read(path, e) => write!(f, "cannot read {}: {}", path, e),
+            OptionsError::Parse(path, e) => write!(f, "invalid config {}: {}", path, e),
+            OptionsError::BlockSize(n) => write!(
+                f,
+                "block size {} is not a power of two from {} to {}",
+                n, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            ),
+            OptionsError::MemtableBudget(n) => {
+                write!(f, "memtable budget {} is smaller than a block", n)
+            }
+            OptionsError::BloomBits(n) => {
+                write!(
+                    f,
+                    "{} bloom bits per key is more than {}",
+                    n, MAX_BLOOM_BITS
+                )
+            }
+            OptionsError::CacheSize(n) => write!(
+                f,
+                "cache size {} is less than {} blocks",
+                n, MIN_CACHE_BLOCKS
+            ),
+            OptionsError::RestartInterval(n) => {
+                write!(f, "restart interval {} must be at least 1", n)
+            }
+            OptionsError::DirtyRatio(n) => {
+                write!(f, "dirty ratio {} is not a percentage from 1 to 100", n)
+            }
+        }
+    }
+}
+
+const MAX_BLOOM_BITS: usize = 32;
+// a merge reads one page of the old disktable while it writes one of the new, and the
+// B+Tree holds a few nodes at once
+const MIN_CACHE_BLOCKS: usize = 8;
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            data_dir: "disktables".to_string(),
+            memtable_budget: 1 << 23,
+            block_size: MIN_BLOCK_SIZE,
+            bloom_bits_per_key: 10,
+            compression: Compression::None,
+            page_format: PageFormat::Slotted,
+            restart_interval: 16,
+            sync: SyncPolicy::Never,
+            cache_size: 1 << 24,
+            io_backend: IoBackendKind::Uring,
+            direct_io: true,
+            dirty_ratio: DEFAULT_DIRTY_RATIO,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_toml_file(path: &str) -> Result<Self, OptionsError> {
+        let text = read_to_string(path)
+            .map_err(|e| OptionsError::Read(path.to_string(), e.to_string()))?;
+        toml::from_str(&text).map_err(|e| OptionsError::Parse(path.to_string(), e.to_string()))
+    }
+
+    pub fn data_dir(mut self, dir: &str) -> Self {
+        self.data_dir = dir.to_string();
+        self
+    }
+
+    pub fn memtable_budget(mut self, bytes: usize) -> Self {
+        self.memtable_budget = bytes;
+        self
+    }
+
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn page_format(mut self, format: PageFormat) -> Self {
+        self.page_format = format;
+        self
+    }
+
+    pub fn restart_interval(mut self, entries: usize) -> Self {
+        self.restart_interval = entries;
+        self
+    }
+
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    pub fn io_backend(mut self, kind: IoBackendKind) -> Self {
+        self.io_backend = kind;
+        self
+    }
+
+    pub fn direct_io(mut self, direct: bool) -> Self {
+        self.direct_io = direct;
+        self
+    }
+
+    pub fn dirty_ratio(mut self, percent: usize) -> Self {
+        self.dirty_ratio = percent;
+        self
+    }
+
+    // how many blocks a buffer manager for these options holds
+    pub fn cache_blocks(&self) -> usize {
+        self.cache_size.checked_div(self.block_size).unwrap_or(0)
+    }
+
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if !self.block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size)
+        {
+            return Err(OptionsError::BlockSize(self.block_size));
+        }
+        if self.memtable_budget < self.block_size {
+            return Err(OptionsError::MemtableBudget(self.memtable_budget));
+        }
+        if self.bloom_bits_per_key > MAX_BLOOM_BITS {
+            return Err(OptionsError::BloomBits(self.bloom_bits_per_key));
+        }
+        if self.cache_blocks() < MIN_CACHE_BLOCKS {
+            return Err(OptionsError::CacheSize(self.cache_size));
+        }
+        if self.restart_interval == 0 {
+            return Err(OptionsError::RestartInterval(self.restart_interval));
+        }
+        if !(1..=100).contains(&self.dirty_ratio) {
+            return Err(OptionsError::DirtyRatio(self.dirty_ratio));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // the options in a TOML file holding text, which is removed again
+    fn from_toml(name: &str, text: &str) -> Result<Options, OptionsError> {
+        let path =
+            std::env::temp_dir().join(format!("nopedb-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let options = Options::from_toml_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        options
+    }
+
+    #[test]
+    fn toml_files_set_the_keys_they_name() {
+        let options = from_toml(
+            "keys",
+            r#"
+            data_dir = "/var/lib/nopedb"
+            memtable_budget = 65536
+            block_size = 16384
+            compression = "zstd"
+            page_format = "prefix"
+            sync = "always"
+            io_backend = "sync"
+            direct_io = false
+            dirty_ratio = 40
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.data_dir, "/var/lib/nopedb");
+        assert_eq!(options.memtable_budget, 65536);
+        assert_eq!(options.block_size, 16384);
+        assert_eq!(options.compression, Compression::Zstd);
+        assert_eq!(options.page_format, PageFormat::Prefix);
+        assert_eq!(options.sync, SyncPolicy::Always);
+        assert_eq!(options.io_backend, IoBackendKind::Sync);
+        assert!(!options.direct_io);
+        assert_eq!(options.dirty_ratio, 40);
+        // keys left out keep their defaults
+        let default = Options::default();
+        assert_eq!(options.bloom_bits_per_key, default.bloom_bits_per_key);
+        assert_eq!(options.cache_size, default.cache_size);
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn toml_files_with_unknown_keys_or_values_are_turned_down() {
+        for (name, text) in [
+            (
+                "unknown",
+                "memtable_budget = 65536\nlevel_size_ratio = 10\n",
+            ),
+            ("typo", "data_directory = \"db\"\n"),
+            ("value", "compression = \"gzip\"\n"),
+            ("type", "block_size = \"16k\"\n"),
+        ] {
+            match from_toml(name, text) {
+                Err(OptionsError::Parse(_, e)) => assert!(!e.is_empty()),
+                other => panic!("{} gave {:?}", name, other),
+            }
+        }
+        let missing = std::env::temp_dir().join("nopedb-missing.toml");
+        match Options::from_toml_file(missing.to_str().unwrap()) {
+            Err(OptionsError::Read(..)) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_turns_down_each_value_out_of_range() {
+        let ok = || Options::new().cache_size(64 * MIN_BLOCK_SIZE);
+        assert!(ok().validate().is_ok());
+        for size in [0, 1000, 2048, 3 * 4096, 1 << 17] {
+            let options = ok().block_size(size);
+            assert!(matches!(options.validate(), Err(OptionsError::BlockSize(n)) if n == size));
+        }
+        for size in [MIN_BLOCK_SIZE, MAX_BLOCK_SIZE] {
+            let options = ok().block_size(size).cache_size(MIN_CACHE_BLOCKS * size);
+            assert!(options.validate().is_ok());
+        }
+        for (options, expected) in [
+            (ok().memtable_budget(MIN_BLOCK_SIZE - 1), "MemtableBudget"),
+            (ok().bloom_bits_per_key(MAX_BLOOM_BITS + 1), "BloomBits"),
+            (
+                ok().cache_size((MIN_CACHE_BLOCKS - 1) * MIN_BLOCK_SIZE),
+                "CacheSize",
+            ),
+            (ok().restart_interval(0), "RestartInterval"),
+            (ok().dirty_ratio(0), "DirtyRatio"),
+            (ok().dirty_ratio(101), "DirtyRatio"),
+        ] {
+            let e = options.validate().unwrap_err();
+            assert!(format!("{:?}", e).starts_with(expected), "{:?}", e);
+            assert!(!e.to_string().is_empty());
+        }
+    }
+}
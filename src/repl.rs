@@ -0,0 +1,533 @@
+use std::{
+    fs::{read_to_string, File, OpenOptions},
+    io::{stdin, stdout, BufRead, BufReader, IsTerminal, Read, Write},
+    ops::Bound::{Excluded, Included, Unbounded},
+    rc::Rc,
+};
+
+use chrono::TimeDelta;
+use text_io::read;
+
+use crate::{
+    buffer_manager::BufferManager,
+    io_backend,
+    kv_store::KeyValueStore,
+    lsm_tree::{CasError, LSMTree, MergeOperator, Transaction},
+    options::{Options, OptionsError},
+    sql::{self, executor::Output},
+    storage::FileSystem,
+    storage_engine::{Row, StorageEngine},
+};
+
+const HISTORY_FILE: &str = ".nopedb_history";
+
+const HELP: &str = "\
+commands:
+  put <key> <value>     store a value in the key/value store
+  putex <key> <secs> <value>
+                        store a value that reads as deleted after secs seconds
+  get <key>             look a key up
+  del <key>             delete a key
+  incr <key> [n]        add n, or 1, to the number stored at a key without reading it
+  cas <key> <old> <new> replace old with new, - standing for no value
+  putnx <key> <value>   store a value unless the key already has one
+  scan [start [end]]    list keys in [start, end)
+  begin                 start a transaction over the key/value store
+  commit                apply the transaction's writes, failing on a conflict
+  rollback              discard the transaction's writes
+  tables                list SQL tables and their columns
+  stats                 buffer pool and storage statistics
+  flush                 write memtables and dirty pages to disk
+  checkpoint            flush, then sync every file written since the last checkpoint
+  compact               rewrite disktables without tombstones
+  history               show previous input, !<n> runs entry n again
+  help                  show this message
+  quit                  flush and exit
+anything else is read as SQL, terminated by ;";
+
+// adds up the operands written by incr, a value that is not a number counts as 0
+struct Counter;
+
+impl MergeOperator<String, String> for Counter {
+    fn merge(&self, _key: &String, existing: Option<String>, operand: String) -> String {
+        let base: i64 = existing.and_then(|v| v.parse().ok()).unwrap_or(0);
+        let n: i64 = operand.parse().unwrap_or(0);
+        base.saturating_add(n).to_string()
+    }
+}
+
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+pub struct Shell {
+    manager: BufferManager,
+    engine: StorageEngine,
+    kv: LSMTree<String, String>,
+    // put, get, del and scan go through the open transaction if there is one
+    tx: Option<Transaction<String, String>>,
+    history: Vec<String>,
+    sql_buffer: String,
+}
+
+impl Shell {
+    pub fn new(options: Options) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let mut manager = BufferManager::with_storage_and_backend(
+            options.cache_blocks(),
+            FileSystem::new(options.direct_io),
+            io_backend::open(options.io_backend),
+        );
+        manager.set_dirty_ratio(options.dirty_ratio);
+        let engine = StorageEngine::with_options(&mut manager, &options)?;
+        let kv = LSMTree::with_options(
+            "kv".to_string(),
+            &mut manager,
+            &options,
+            Some(Rc::new(Counter)),
+        )?;
+        Ok(Self {
+            manager,
+            engine,
+            kv,
+            tx: None,
+            history: Vec::new(),
+            sql_buffer: String::new(),
+        })
+    }
+
+    pub fn run_interactive(&mut self) {
+        let interactive = stdin().is_terminal();
+        if interactive {
+            self.load_history();
+            println!("NopeDB shell, type help for commands");
+        }
+
+        let input = stdin();
+        let mut bytes = input.lock().bytes().map(|b| b.unwrap()).peekable();
+        loop {
+            if interactive {
+                if self.sql_buffer.is_empty() {
+                    print!("nopedb> ");
+                } else {
+                    print!("     -> ");
+                }
+                stdout().flush().unwrap();
+            }
+            if bytes.peek().is_none() {
+                break;
+            }
+            let line: String = read!("{}\n", bytes.by_ref());
+
+            let line = match self.expand_history(&line) {
+                Ok(l) => l,
+                Err(e) => {
+                    println!("error: {}", e);
+                    continue;
+                }
+            };
+            if interactive && !line.trim().is_empty() {
+                self.record_history(&line);
+            }
+
+            match self.handle_line(&line) {
+                // the background writer runs while the shell waits for input
+                Ok(Flow::Continue) => self.manager.trickle(),
+                Ok(Flow::Quit) => break,
+                Err(e) => {
+                    println!("error: {}", e);
+                    if !interactive {
+                        self.close();
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+
+        if !self.sql_buffer.trim().is_empty() {
+            println!("error: incomplete SQL statement, missing ;");
+        }
+    }
+
+    // runs a script non-interactively, stopping at the first failing line
+    pub fn run_script(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            if let Flow::Quit = self.handle_line(line)? {
+                return Ok(());
+            }
+        }
+        if !self.sql_buffer.trim().is_empty() {
+            let statement = std::mem::take(&mut self.sql_buffer);
+            self.run_sql(&statement)?;
+        }
+        Ok(())
+    }
+
+    pub fn run_file(&mut self, path: &str) -> Result<(), String> {
+        let text = read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        self.run_script(&text)
+    }
+
+    // merges every memtable and writes all dirty pages so nothing is lost on exit
+    pub fn close(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            println!("rolling back the open transaction");
+            self.kv.rollback(tx);
+        }
+        self.kv.flush(&mut self.manager);
+        self.engine.checkpoint(&mut self.manager);
+    }
+
+    pub fn handle_line(&mut self, line: &str) -> Result<Flow, String> {
+        let trimmed = line.trim();
+        if self.sql_buffer.is_empty() {
+            if trimmed.is_empty() || trimmed.starts_with("--") {
+                return Ok(Flow::Continue);
+            }
+            let mut words = trimmed.splitn(2, char::is_whitespace);
+            let command = words.next().unwrap().to_ascii_lowercase();
+            let rest = words.next().unwrap_or("").trim();
+            if let Some(flow) = self.command(&command, rest)? {
+                return Ok(flow);
+            }
+        }
+
+        self.sql_buffer.push_str(line);
+        self.sql_buffer.push('\n');
+        if trimmed.ends_with(';') {
+            let statement = std::mem::take(&mut self.sql_buffer);
+            self.run_sql(&statement)?;
+        }
+        Ok(Flow::Continue)
+    }
+
+    // returns None when the line is not a shell command and should be read as SQL
+    fn command(&mut self, command: &str, rest: &str) -> Result<Option<Flow>, String> {
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match command {
+            "put" => {
+                let mut parts = rest.splitn(2, char::is_whitespace);
+                let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+                    return Err("usage: put <key> <value>".to_string());
+                };
+                if key.is_empty() {
+                    return Err("usage: put <key> <value>".to_string());
+                }
+                let (key, value) = (key.to_string(), Some(value.trim().to_string()));
+                match &mut self.tx {
+                    Some(tx) => tx.put(key, value),
+                    None => self
+                        .kv
+                        .put(&mut self.manager, key, value)
+                        .map_err(|e| e.to_string())?,
+                }
+                println!("ok");
+            }
+            "putex" => {
+                let mut parts = rest.splitn(3, char::is_whitespace);
+                let (Some(key), Some(secs), Some(value)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err("usage: putex <key> <secs> <value>".to_string());
+                };
+                let Ok(secs) = secs.parse::<u32>() else {
+                    return Err(format!("{} is not a number of seconds", secs));
+                };
+                if self.tx.is_some() {
+                    return Err("putex is not supported inside a transaction".to_string());
+                }
+                self.kv
+                    .put_with_ttl(
+                        &mut self.manager,
+                        key.to_string(),
+                        value.trim().to_string(),
+                        TimeDelta::seconds(secs.into()),
+                    )
+                    .map_err(|e| e.to_string())?;
+                println!("ok");
+            }
+            "get" => {
+                let [key] = args[..] else {
+                    return Err("usage: get <key>".to_string());
+                };
+                let value = match &self.tx {
+                    Some(tx) => tx.get(&mut self.manager, key.to_string()),
+                    None => self.kv.get(&mut self.manager, key.to_string()),
+                };
+                match value {
+                    Some(v) => println!("{}", v),
+                    None => println!("(not found)"),
+                }
+            }
+            "del" => {
+                let [key] = args[..] else {
+                    return Err("usage: del <key>".to_string());
+                };
+                match &mut self.tx {
+                    Some(tx) => tx.delete(key.to_string()),
+                    None => self.kv.delete(&mut self.manager, key.to_string()),
+                }
+                println!("ok");
+            }
+            "incr" => {
+                let (key, n) = match args[..] {
+                    [key] => (key, 1),
+                    [key, n] => match n.parse::<i64>() {
+                        Ok(n) => (key, n),
+                        Err(_) => return Err(format!("{} is not a number", n)),
+                    },
+                    _ => return Err("usage: incr <key> [n]".to_string()),
+                };
+                if self.tx.is_some() {
+                    return Err("incr is not supported inside a transaction".to_string());
+                }
+                self.kv
+                    .merge_value(&mut self.manager, key.to_string(), n.to_string())
+                    .map_err(|e| e.to_string())?;
+                println!("ok");
+            }
+            "cas" => {
+                let [key, expected, new] = args[..] else {
+                    return Err("usage: cas <key> <old> <new>".to_string());
+                };
+                if self.tx.is_some() {
+                    return Err("cas is not supported inside a transaction".to_string());
+                }
+                let value = |v: &str| (v != "-").then(|| v.to_string());
+                match self.kv.compare_and_swap(
+                    &mut self.manager,
+                    key.to_string(),
+                    value(expected),
+                    value(new),
+                ) {
+                    Ok(()) => println!("ok"),
+                    Err(CasError::Mismatch(Some(current))) => {
+                        println!("failed, the value is {}", current)
+                    }
+                    Err(CasError::Mismatch(None)) => println!("failed, the key has no value"),
+                    Err(CasError::TooLarge(e)) => return Err(e.to_string()),
+                }
+            }
+            "putnx" => {
+                let mut parts = rest.splitn(2, char::is_whitespace);
+                let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+                    return Err("usage: putnx <key> <value>".to_string());
+                };
+                if key.is_empty() {
+                    return Err("usage: putnx <key> <value>".to_string());
+                }
+                if self.tx.is_some() {
+                    return Err("putnx is not supported inside a transaction".to_string());
+                }
+                match self.kv.put_if_absent(
+                    &mut self.manager,
+                    key.to_string(),
+                    value.trim().to_string(),
+                ) {
+                    Ok(()) => println!("ok"),
+                    Err(CasError::Mismatch(current)) => {
+                        println!("failed, the value is {}", current.unwrap())
+                    }
+                    Err(CasError::TooLarge(e)) => return Err(e.to_string()),
+                }
+            }
+            "scan" => {
+                let range = match args[..] {
+                    [] => (Unbounded, Unbounded),
+                    [start] => (Included(start.to_string()), Unbounded),
+                    [start, end] => (Included(start.to_string()), Excluded(end.to_string())),
+                    _ => return Err("usage: scan [start [end]]".to_string()),
+                };
+                let entries = match &self.tx {
+                    Some(tx) => tx.scan(&mut self.manager, range),
+                    None => self.kv.scan(&mut self.manager, range),
+                };
+                for (k, v) in entries.iter() {
+                    println!("{} = {}", k, v);
+                }
+                println!("({} keys)", entries.len());
+            }
+            "begin" => {
+                if self.tx.is_some() {
+                    return Err("a transaction is already open".to_string());
+                }
+                self.tx = Some(self.kv.begin());
+                println!("ok");
+            }
+            "commit" => {
+                let Some(tx) = self.tx.take() else {
+                    return Err("no open transaction".to_string());
+                };
+                self.kv
+                    .commit(&mut self.manager, tx)
+                    .map_err(|e| format!("{}, transaction rolled back", e))?;
+                println!("ok");
+            }
+            "rollback" => {
+                let Some(tx) = self.tx.take() else {
+                    return Err("no open transaction".to_string());
+                };
+                self.kv.rollback(tx);
+                println!("ok");
+            }
+            "tables" => {
+                for table in self.engine.tables() {
+                    let schema = &table.schema;
+                    let columns: Vec<String> = schema
+                        .columns
+                        .iter()
+                        .enumerate()
+                        .map(|(i, c)| {
+                            if Some(i) == schema.primary_key {
+                                format!("{} {} PRIMARY KEY", c.name, c.data_type)
+                            } else {
+                                format!("{} {}", c.name, c.data_type)
+                            }
+                        })
+                        .collect();
+                    println!(
+                        "{} ({}) USING {}",
+                        schema.name,
+                        columns.join(", "),
+                        schema.storage
+                    );
+                    for index in table.indexes.iter() {
+                        println!(
+                            "  index {} on {}",
+                            index.def.name, schema.columns[index.def.column].name
+                        );
+                    }
+                }
+            }
+            "stats" => self.print_stats(),
+            "flush" => {
+                self.kv.flush(&mut self.manager);
+                self.engine.flush(&mut self.manager);
+                println!("ok");
+            }
+            "checkpoint" => {
+                self.kv.flush(&mut self.manager);
+                self.engine.checkpoint(&mut self.manager);
+                println!("ok");
+            }
+            "compact" => {
+                self.kv.compact(&mut self.manager);
+                self.engine.compact(&mut self.manager);
+                println!("ok");
+            }
+            "history" => {
+                for (i, line) in self.history.iter().enumerate() {
+                    println!("{:5}  {}", i + 1, line);
+                }
+            }
+            "help" | "\\h" => println!("{}", HELP),
+            "quit" | "exit" | "\\q" => return Ok(Some(Flow::Quit)),
+            _ => return Ok(None),
+        }
+        Ok(Some(Flow::Continue))
+    }
+
+    fn run_sql(&mut self, statement: &str) -> Result<(), String> {
+        let outputs =
+            sql::run(&mut self.engine, &mut self.manager, statement).map_err(|e| e.to_string())?;
+        for output in outputs {
+            match output {
+                Output::Rows { columns, rows } => print_rows(&columns, &rows),
+                Output::Affected(n) => println!("{} row(s) affected", n),
+                Output::Done => println!("ok"),
+            }
+        }
+        Ok(())
+    }
+
+    fn print_stats(&mut self) {
+        let b = self.manager.stats();
+        println!(
+            "buffer pool ({}): {}/{} blocks cached, {} dirty, {} hits, {} misses, {} prefetched, {} evictions",
+            b.io, b.cached, b.capacity, b.dirty, b.hits, b.misses, b.prefetched, b.evictions
+        );
+        println!(
+            "writeback: {} blocks written back, {} writes throttled",
+            b.written_back, b.throttled
+        );
+        println!("kv: {}", self.kv.stats(&mut self.manager));
+        for table in self.engine.tables() {
+            println!(
+                "table {}: {}",
+                table.schema.name,
+                table.stats(&mut self.manager)
+            );
+        }
+    }
+
+    fn expand_history(&self, line: &str) -> Result<String, String> {
+        let trimmed = line.trim();
+        let Some(n) = trimmed.strip_prefix('!') else {
+            return Ok(line.to_string());
+        };
+        let index: usize = n
+            .parse()
+            .map_err(|_| format!("bad history reference {}", trimmed))?;
+        match index.checked_sub(1).and_then(|i| self.history.get(i)) {
+            Some(entry) => {
+                println!("{}", entry);
+                Ok(entry.clone())
+            }
+            None => Err(format!("no history entry {}", index)),
+        }
+    }
+
+    fn load_history(&mut self) {
+        if let Ok(fd) = File::open(HISTORY_FILE) {
+            self.history = BufReader::new(fd).lines().map_while(Result::ok).collect();
+        }
+    }
+
+    fn record_history(&mut self, line: &str) {
+        self.history.push(line.to_string());
+        if let Ok(mut fd) = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(HISTORY_FILE)
+        {
+            let _ = writeln!(fd, "{}", line);
+        }
+    }
+}
+
+fn print_rows(columns: &[String], rows: &[Row]) {
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.len()).collect();
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|r| r.0.iter().map(|v| v.to_string()).collect())
+        .collect();
+    for row in cells.iter() {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+
+    let border: String = widths
+        .iter()
+        .map(|w| format!("+{}", "-".repeat(w + 2)))
+        .collect::<String>()
+        + "+";
+    let line = |values: &[String]| -> String {
+        values
+            .iter()
+            .zip(widths.iter())
+            .map(|(v, w)| format!("| {:w$} ", v, w = w))
+            .collect::<String>()
+            + "|"
+    };
+
+    println!("{}", border);
+    println!("{}", line(columns));
+    println!("{}", border);
+    for row in cells.iter() {
+        println!("{}", line(row));
+    }
+    println!("{}", border);
+    println!("({} row(s))", rows.len());
+}
@@ -1,8 +1,15 @@
+#![allow(
+    clippy::redundant_field_names,
+    clippy::needless_arbitrary_self_type,
+    clippy::needless_late_init,
+    clippy::assign_op_pattern,
+    clippy::useless_conversion
+)]
+
 use std::fmt;
-use std::{collections::BTreeMap, fmt::Debug};
+use std::{collections::BTreeMap, fmt::Debug, marker::PhantomData};
 
 use crate::fixed::KnowsSize;
-use crate::BLOCK_SIZE;
 use chrono::{DateTime, Local};
 use serde::{
     de::{Error, Visitor},
@@ -11,6 +18,8 @@ use serde::{
 
 const PAGE_TYPE_MASK: u16 = 0b1000000000000000;
 const NUM_CELLS_MASK: u16 = 0b0111111111111111;
+const FIXED_HEADER: usize = 6;
+const VARIABLE_HEADER: usize = 2;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 enum PageType {
@@ -22,23 +31,28 @@ pub struct SlottedPage<K, V> {
     page_type: PageType,
     pub num_cells: u16,
     pub cells: BTreeMap<K, Option<V>>,
+    // the page fills a block of this many bytes when encoded
+    block_size: usize,
     space_left: u32, // assume that it's variable size (bad), each keyval is u16 + len(serialized(key)) + len(serialized(val))
 }
 
 impl<K: Serialize + KnowsSize + Ord, V: Serialize + KnowsSize> SlottedPage<K, V> {
-    pub fn new() -> Self {
+    pub fn new(block_size: usize) -> Self {
         let key_bit_width = K::bit_width();
-        let val_bit_width = V::bit_width() + 1;
+        let val_bit_width = V::bit_width();
         let mut page_type = PageType::Fixed;
+        let mut header = FIXED_HEADER;
 
         if val_bit_width < 0 || key_bit_width < 0 {
             page_type = PageType::Variable;
+            header = VARIABLE_HEADER;
         };
         Self {
             page_type: page_type,
             num_cells: 0,
             cells: BTreeMap::new(),
-            space_left: BLOCK_SIZE as u32,
+            block_size,
+            space_left: (block_size - header) as u32,
         }
     }
 
@@ -67,6 +81,7 @@ impl<K: Serialize + KnowsSize + Ord, V: Serialize + KnowsSize> SlottedPage<K, V>
     }
 }
 
+#[allow(dead_code)]
 struct MyOwnDateTime {
     whatever: DateTime<Local>,
 }
@@ -79,6 +94,7 @@ impl Serialize for MyOwnDateTime {
         serializer.serialize_i64(self.whatever.timestamp())
     }
 }
+#[allow(dead_code)]
 struct TimeStampVisitor;
 
 impl<'de> Visitor<'de> for TimeStampVisitor {
@@ -117,7 +133,11 @@ Variable Header format:
 |  is_variable  |    num_cells    |
     1 bit             15 bits
 Slotted page Format:
-| header u8 | offset of cell 1 u8 | offset of cell 2 u8 | ... | offset cell x u8| free space | cell x | cell x - 1 | ... | cell 1 |
+| header | offset of cell 1 u32 | offset of cell 2 u32 | ... | offset cell x u32 | free space | cell x | cell x - 1 | ... | cell 1 |
+Offsets count back from the end of the block, which is between 4 KiB and 64 KiB long. Fixed
+cells are key then value, variable cells are | key len u32 | key | value len u32 | value |.
+Slots are in key order but cells need not be: a page edited in place has its cells wherever
+there was room and unused bytes between them until it is defragmented.
 */
 
 pub fn encode<K: Serialize + KnowsSize + Debug, V: Serialize + KnowsSize>(
@@ -146,90 +166,93 @@ pub fn encode<K: Serialize + KnowsSize + Debug, V: Serialize + KnowsSize>(
         }
     };
 
-    let mut offsets: Vec<u16> = Vec::new();
+    let mut offsets: Vec<u32> = Vec::new();
     let mut key_vals: Vec<Vec<u8>> = Vec::new();
     match page.page_type {
         PageType::Fixed => {
-            let mut offset: u16 = 0;
+            let mut offset: u32 = 0;
             for k in page.cells.iter() {
                 let mut serialized_key = bincode::serialize(k.0).unwrap();
                 let serialized_val = bincode::serialize(k.1).unwrap();
                 serialized_key.extend(serialized_val);
 
-                offset += serialized_key.len() as u16;
+                offset += serialized_key.len() as u32;
                 offsets.push(offset);
                 key_vals.push(serialized_key);
             }
 
-            let mut final_arr = [0; BLOCK_SIZE];
-            final_arr[0..6].copy_from_slice(&encoded_header);
+            let mut final_arr = vec![0; page.block_size];
+            final_arr[0..FIXED_HEADER].copy_from_slice(&encoded_header);
             for (i, v) in offsets.iter().enumerate() {
-                let offset_start = 6 + i * 2;
-                let offset_end = offset_start + 2;
+                let offset_start = FIXED_HEADER + i * 4;
+                let offset_end = offset_start + 4;
                 final_arr[offset_start..offset_end]
                     .copy_from_slice(&bincode::serialize(&v).unwrap());
             }
             for (i, v) in key_vals.iter().enumerate() {
-                let cell_start = BLOCK_SIZE as u16 - offsets[i];
-                let cell_end = cell_start + v.len() as u16;
-                final_arr[cell_start as usize..cell_end as usize].copy_from_slice(v);
+                let cell_start = page.block_size - offsets[i] as usize;
+                let cell_end = cell_start + v.len();
+                final_arr[cell_start..cell_end].copy_from_slice(v);
             }
-            final_arr.to_vec()
+            final_arr
         }
         PageType::Variable => {
-            let mut offset: u16 = 0;
+            let mut offset: u32 = 0;
             for k in page.cells.iter() {
-                offsets.push(offset);
-
                 let mut serialized_cell = Vec::new();
 
                 let serialized_key = bincode::serialize(k.0).unwrap();
-                let serialized_key_len = bincode::serialize(&serialized_key.len()).unwrap();
+                let serialized_key_len =
+                    bincode::serialize(&(serialized_key.len() as u32)).unwrap();
 
                 let serialized_val = bincode::serialize(k.1).unwrap();
-                let serialized_val_len = bincode::serialize(&serialized_key.len()).unwrap();
+                let serialized_val_len =
+                    bincode::serialize(&(serialized_val.len() as u32)).unwrap();
 
                 serialized_cell.extend(serialized_key_len);
                 serialized_cell.extend(serialized_key);
                 serialized_cell.extend(serialized_val_len);
                 serialized_cell.extend(serialized_val);
 
-                offset += serialized_cell.len() as u16;
+                offset += serialized_cell.len() as u32;
+                offsets.push(offset);
                 key_vals.push(serialized_cell);
             }
 
-            let mut final_arr = [0; BLOCK_SIZE];
-            final_arr[0..16].copy_from_slice(&encoded_header);
+            let mut final_arr = vec![0; page.block_size];
+            final_arr[0..VARIABLE_HEADER].copy_from_slice(&encoded_header);
             for (i, v) in offsets.iter().enumerate() {
-                let offset_start = 2 + (i * 2);
-                let offset_end = offset_start + 2;
+                let offset_start = VARIABLE_HEADER + i * 4;
+                let offset_end = offset_start + 4;
                 final_arr[offset_start..offset_end]
                     .copy_from_slice(&bincode::serialize(&v).unwrap());
             }
             for (i, v) in key_vals.iter().enumerate() {
-                let cell_start = BLOCK_SIZE as u16 - offsets[i];
-                let cell_end = cell_start + v.len() as u16;
-                final_arr[cell_start as usize..cell_end as usize].copy_from_slice(v);
+                let cell_start = page.block_size - offsets[i] as usize;
+                let cell_end = cell_start + v.len();
+                final_arr[cell_start..cell_end].copy_from_slice(v);
             }
 
-            final_arr.to_vec()
+            final_arr
         }
     }
 }
 
+// the block is as long as the page was when it was encoded
 pub fn decode<K: Ord + for<'a> Deserialize<'a> + Debug, V: for<'a> Deserialize<'a> + Debug>(
-    buf: &Vec<u8>,
+    buf: &[u8],
 ) -> SlottedPage<K, V> {
+    let block_size = buf.len();
     let packed_header: u16 = bincode::deserialize(&buf[..2]).unwrap();
     let page_type = packed_header & PAGE_TYPE_MASK;
     let page_type_enum: PageType;
     let space_left: u32;
     if page_type > 0 {
         page_type_enum = PageType::Variable;
-        space_left = 4094;
+        space_left = (block_size - VARIABLE_HEADER) as u32;
     } else {
         page_type_enum = PageType::Fixed;
-        space_left = 4090;
+        space_left = (block_size - FIXED_HEADER) as u32;
     }
 
     let num_cells = packed_header & NUM_CELLS_MASK;
@@ -238,6 +261,7 @@ pub fn decode<K: Ord + for<'a> Deserialize<'a> + Debug, V: for<'a> Deserialize<'
         num_cells: num_cells,
         page_type: page_type_enum,
         cells: BTreeMap::new(),
+        block_size,
         space_left: space_left,
     };
     match s.page_type {
@@ -245,36 +269,33 @@ pub fn decode<K: Ord + for<'a> Deserialize<'a> + Debug, V: for<'a> Deserialize<'
             let key_size: u16 = bincode::deserialize(&buf[2..4]).unwrap();
             let val_size: u16 = bincode::deserialize(&buf[4..6]).unwrap();
 
-            for i in 0..s.num_cells {
-                let offset_start = (6 + i * 2) as usize;
-                let offset_end = (offset_start + 2) as usize;
-                let offset: u16 = bincode::deserialize(&buf[offset_start..offset_end]).unwrap();
+            for i in 0..s.num_cells as usize {
+                let offset_start = FIXED_HEADER + i * 4;
+                let offset_end = offset_start + 4;
+                let offset: u32 = bincode::deserialize(&buf[offset_start..offset_end]).unwrap();
 
-                let key_start = BLOCK_SIZE as u16 - offset;
-                let key_end = key_start + key_size;
+                let key_start = block_size - offset as usize;
+                let key_end = key_start + key_size as usize;
 
                 let val_start = key_end;
-                let val_end = val_start + val_size;
+                let val_end = val_start + val_size as usize;
 
-                let key: K =
-                    bincode::deserialize(&buf[key_start as usize..key_end as usize]).unwrap();
-                let value: Option<V> =
-                    bincode::deserialize(&buf[val_start as usize..val_end as usize]).unwrap();
+                let key: K = bincode::deserialize(&buf[key_start..key_end]).unwrap();
+                let value: Option<V> = bincode::deserialize(&buf[val_start..val_end]).unwrap();
 
                 s.cells.insert(key, value);
 
-                s.num_cells += 1;
-                s.space_left = s.space_left - 2 - key_size as u32 - val_size as u32
+                s.space_left = s.space_left - 4 - key_size as u32 - val_size as u32
             }
         }
         PageType::Variable => {
-            for i in 0..s.num_cells {
-                let offset_start = (2 + i * 2) as usize;
-                let offset_end = (offset_start + 2) as usize;
-                let offset: u16 = bincode::deserialize(&buf[offset_start..offset_end]).unwrap();
-                let key_size_start = BLOCK_SIZE - offset as usize;
-                let key_size_end = (offset + 2) as usize;
-                let key_size: u16 =
+            for i in 0..s.num_cells as usize {
+                let offset_start = VARIABLE_HEADER + i * 4;
+                let offset_end = offset_start + 4;
+                let offset: u32 = bincode::deserialize(&buf[offset_start..offset_end]).unwrap();
+                let key_size_start = block_size - offset as usize;
+                let key_size_end = key_size_start + 4;
+                let key_size: u32 =
                     bincode::deserialize(&buf[key_size_start..key_size_end]).unwrap();
 
                 let key_start = key_size_end;
@@ -283,8 +304,8 @@ pub fn decode<K: Ord + for<'a> Deserialize<'a> + Debug, V: for<'a> Deserialize<'
                 let key: K = bincode::deserialize(&buf[key_start..key_end]).unwrap();
 
                 let val_size_start = key_end as usize;
-                let val_size_end = (key_end + 2) as usize;
-                let val_size: u16 =
+                let val_size_end = (key_end + 4) as usize;
+                let val_size: u32 =
                     bincode::deserialize(&buf[val_size_start..val_size_end]).unwrap();
 
                 let val_start = val_size_end;
@@ -294,10 +315,383 @@ pub fn decode<K: Ord + for<'a> Deserialize<'a> + Debug, V: for<'a> Deserialize<'
 
                 s.cells.insert(key, val);
 
-                s.num_cells += 1;
-                s.space_left = s.space_left - 2 - key_size as u32 - val_size as u32
+                s.space_left = s.space_left - 12 - key_size - val_size
             }
         }
     }
     s
 }
+
+// reads cells straight out of an encoded page, deserialising only the ones asked for. The
+// slots are in key order, so lookups binary search them
+pub struct PageView<'a, K, V> {
+    buf: &'a [u8],
+    page_type: PageType,
+    num_cells: usize,
+    // of every cell of a fixed page
+    key_size: usize,
+    val_size: usize,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<'a, K: Ord + for<'b> Deserialize<'b>, V: for<'b> Deserialize<'b>> PageView<'a, K, V> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        let packed_header = u16::from_le_bytes([buf[0], buf[1]]);
+        let num_cells = (packed_header & NUM_CELLS_MASK) as usize;
+        if packed_header & PAGE_TYPE_MASK > 0 {
+            return Self {
+                buf,
+                page_type: PageType::Variable,
+                num_cells,
+                key_size: 0,
+                val_size: 0,
+                _types: PhantomData,
+            };
+        }
+        Self {
+            buf,
+            page_type: PageType::Fixed,
+            num_cells,
+            key_size: u16::from_le_bytes([buf[2], buf[3]]) as usize,
+            val_size: u16::from_le_bytes([buf[4], buf[5]]) as usize,
+            _types: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_cells
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_cells == 0
+    }
+
+    // the key and value bytes of cell i
+    fn cell(&self, i: usize) -> (&'a [u8], &'a [u8]) {
+        let header = match self.page_type {
+            PageType::Fixed => FIXED_HEADER,
+            PageType::Variable => VARIABLE_HEADER,
+        };
+        let at = header + i * 4;
+        let offset = u32::from_le_bytes(self.buf[at..at + 4].try_into().unwrap()) as usize;
+        let start = self.buf.len() - offset;
+        match self.page_type {
+            PageType::Fixed => {
+                let key_end = start + self.key_size;
+                (
+                    &self.buf[start..key_end],
+                    &self.buf[key_end..key_end + self.val_size],
+                )
+            }
+            PageType::Variable => {
+                let key_start = start + 4;
+                let key_end = key_start + read_len(self.buf, start);
+                let val_start = key_end + 4;
+                let val_end = val_start + read_len(self.buf, key_end);
+                (&self.buf[key_start..key_end], &self.buf[val_start..val_end])
+            }
+        }
+    }
+
+    pub fn key(&self, i: usize) -> K {
+        bincode::deserialize(self.cell(i).0).unwrap()
+    }
+
+    pub fn value(&self, i: usize) -> Option<V> {
+        bincode::deserialize(self.cell(i).1).unwrap()
+    }
+
+    // the number of cells before target, which is the slot of the first one at or after it
+    pub fn lower_bound(&self, target: &K) -> usize {
+        let (mut lo, mut hi) = (0, self.num_cells);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.key(mid) < *target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // the first cell at or after target
+    pub fn seek(&self, target: &K) -> Option<(K, Option<V>)> {
+        let i = self.lower_bound(target);
+        if i == self.num_cells {
+            return None;
+        }
+        Some((self.key(i), self.value(i)))
+    }
+
+    // the value of k, None when the page does not hold it
+    pub fn get(&self, k: &K) -> Option<Option<V>> {
+        let i = self.lower_bound(k);
+        if i == self.num_cells || self.key(i) != *k {
+            return None;
+        }
+        Some(self.value(i))
+    }
+}
+
+fn read_len(buf: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap()) as usize
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PageError {
+    Full,
+    // a slot past the end of the page's slots
+    NoSlot(usize),
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::Full => write!(f, "no room left in the page"),
+            PageError::NoSlot(slot) => write!(f, "the page has no slot {}", slot),
+        }
+    }
+}
+
+// edits an encoded page in place, for structures that update their pages rather than
+// rewrite them. The caller keeps the slots in whatever order it needs, PageView only
+// binary searches pages whose slots are in key order
+pub struct PageMut<'a, K, V> {
+    buf: &'a mut [u8],
+    page_type: PageType,
+    key_size: usize,
+    val_size: usize,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<'a, K: Serialize + Ord + for<'b> Deserialize<'b>, V: Serialize + for<'b> Deserialize<'b>>
+    PageMut<'a, K, V>
+{
+    // a page already encoded in buf
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        let view: PageView<K, V> = PageView::new(buf);
+        let (page_type, key_size, val_size) = (view.page_type, view.key_size, view.val_size);
+        Self {
+            buf,
+            page_type,
+            key_size,
+            val_size,
+            _types: PhantomData,
+        }
+    }
+
+    // formats buf as an empty page, fixed when K and V always take the same bytes
+    pub fn init(buf: &'a mut [u8]) -> Self
+    where
+        K: KnowsSize,
+        V: KnowsSize,
+    {
+        buf.fill(0);
+        let key_bit_width = K::bit_width();
+        let val_bit_width = V::bit_width();
+        if key_bit_width < 0 || val_bit_width < 0 {
+            buf[0..2].copy_from_slice(&PAGE_TYPE_MASK.to_le_bytes());
+        } else {
+            buf[2..4].copy_from_slice(&(key_bit_width as u16).to_le_bytes());
+            buf[4..6].copy_from_slice(&((val_bit_width + 1) as u16).to_le_bytes());
+        }
+        Self::new(buf)
+    }
+
+    pub fn view(&self) -> PageView<'_, K, V> {
+        PageView::new(self.buf)
+    }
+
+    pub fn len(&self) -> usize {
+        (u16::from_le_bytes([self.buf[0], self.buf[1]]) & NUM_CELLS_MASK) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn set_len(&mut self, n: usize) {
+        let packed = u16::from_le_bytes([self.buf[0], self.buf[1]]) & PAGE_TYPE_MASK;
+        self.buf[0..2].copy_from_slice(&(packed | n as u16).to_le_bytes());
+    }
+
+    fn header(&self) -> usize {
+        match self.page_type {
+            PageType::Fixed => FIXED_HEADER,
+            PageType::Variable => VARIABLE_HEADER,
+        }
+    }
+
+    fn slot_at(&self, slot: usize) -> usize {
+        self.header() + slot * 4
+    }
+
+    // where the cell of slot starts
+    fn cell_start(&self, slot: usize) -> usize {
+        let at = self.slot_at(slot);
+        self.buf.len() - read_len(self.buf, at)
+    }
+
+    fn cell_len(&self, slot: usize) -> usize {
+        let start = self.cell_start(slot);
+        match self.page_type {
+            PageType::Fixed => self.key_size + self.val_size,
+            PageType::Variable => {
+                let key_len = read_len(self.buf, start);
+                8 + key_len + read_len(self.buf, start + 4 + key_len)
+            }
+        }
+    }
+
+    fn encode_cell(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        match self.page_type {
+            // a None value is shorter than the width every fixed cell takes
+            PageType::Fixed => {
+                let mut cell = [key, value].concat();
+                cell.resize(self.key_size + self.val_size, 0);
+                cell
+            }
+            PageType::Variable => [
+                &(key.len() as u32).to_le_bytes(),
+                key,
+                &(value.len() as u32).to_le_bytes(),
+                value,
+            ]
+            .concat(),
+        }
+    }
+
+    // the bytes between the slots and the lowest cell
+    fn contiguous_space(&self) -> usize {
+        let cells_start = (0..self.len())
+            .map(|slot| self.cell_start(slot))
+            .min()
+            .unwrap_or(self.buf.len());
+        cells_start - self.slot_at(self.len())
+    }
+
+    // the bytes a new cell and its slot can take, once the page is defragmented
+    pub fn free_space(&self) -> usize {
+        let used: usize = (0..self.len()).map(|slot| self.cell_len(slot)).sum();
+        self.buf.len() - self.slot_at(self.len()) - used
+    }
+
+    // puts the cell at slot and moves the slots from there on up by one
+    pub fn insert(&mut self, slot: usize, k: &K, v: &Option<V>) -> Result<(), PageError> {
+        if slot > self.len() {
+            return Err(PageError::NoSlot(slot));
+        }
+        let cell = self.encode_cell(
+            &bincode::serialize(k).unwrap(),
+            &bincode::serialize(v).unwrap(),
+        );
+        self.insert_cell(slot, &cell)
+    }
+
+    fn insert_cell(&mut self, slot: usize, cell: &[u8]) -> Result<(), PageError> {
+        let n = self.len();
+        if n == NUM_CELLS_MASK as usize || cell.len() + 4 > self.free_space() {
+            return Err(PageError::Full);
+        }
+        if cell.len() + 4 > self.contiguous_space() {
+            self.defragment();
+        }
+        let start = self.slot_at(n) + self.contiguous_space() - cell.len();
+        self.buf[start..start + cell.len()].copy_from_slice(cell);
+        let at = self.slot_at(slot);
+        self.buf.copy_within(at..self.slot_at(n), at + 4);
+        let offset = (self.buf.len() - start) as u32;
+        self.buf[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+        self.set_len(n + 1);
+        Ok(())
+    }
+
+    // drops the slot and moves the ones after it down by one. The cell's bytes are free
+    // once the page is defragmented
+    pub fn delete(&mut self, slot: usize) -> Result<(), PageError> {
+        let n = self.len();
+        if slot >= n {
+            return Err(PageError::NoSlot(slot));
+        }
+        let at = self.slot_at(slot);
+        self.buf.copy_within(at + 4..self.slot_at(n), at);
+        self.set_len(n - 1);
+        Ok(())
+    }
+
+    // replaces the value of the cell at slot, in place when it is no longer than before
+    pub fn update(&mut self, slot: usize, v: &Option<V>) -> Result<(), PageError> {
+        if slot >= self.len() {
+            return Err(PageError::NoSlot(slot));
+        }
+        let (key, _) = self.view().cell(slot);
+        let cell = self.encode_cell(key, &bincode::serialize(v).unwrap());
+        let old_len = self.cell_len(slot);
+        if cell.len() <= old_len {
+            let start = self.cell_start(slot);
+            self.buf[start..start + cell.len()].copy_from_slice(&cell);
+            return Ok(());
+        }
+        if cell.len() > self.free_space() + old_len {
+            return Err(PageError::Full);
+        }
+        self.delete(slot)?;
+        self.insert_cell(slot, &cell)
+    }
+
+    // moves the cells together at the end of the block, in slot order like encode lays
+    // them out, so all the free space is between them and the slots
+    pub fn defragment(&mut self) {
+        let cells: Vec<Vec<u8>> = (0..self.len())
+            .map(|slot| {
+                let start = self.cell_start(slot);
+                self.buf[start..start + self.cell_len(slot)].to_vec()
+            })
+            .collect();
+        let slots_end = self.slot_at(cells.len());
+        self.buf[slots_end..].fill(0);
+        let mut offset = 0;
+        for (slot, cell) in cells.iter().enumerate() {
+            offset += cell.len();
+            let start = self.buf.len() - offset;
+            self.buf[start..start + cell.len()].copy_from_slice(cell);
+            let at = self.slot_at(slot);
+            self.buf[at..at + 4].copy_from_slice(&(offset as u32).to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_past_the_end_are_errors() {
+        let mut buf = vec![0; 4096];
+        let mut page: PageMut<String, String> = PageMut::init(&mut buf);
+        assert_eq!(page.delete(0), Err(PageError::NoSlot(0)));
+        assert_eq!(page.update(0, &None), Err(PageError::NoSlot(0)));
+        assert_eq!(
+            page.insert(1, &"a".to_string(), &None),
+            Err(PageError::NoSlot(1))
+        );
+
+        page.insert(0, &"b".to_string(), &Some("2".to_string()))
+            .unwrap();
+        page.insert(0, &"a".to_string(), &Some("1".to_string()))
+            .unwrap();
+        assert_eq!(
+            page.insert(3, &"c".to_string(), &None),
+            Err(PageError::NoSlot(3))
+        );
+        assert_eq!(page.delete(2), Err(PageError::NoSlot(2)));
+        page.delete(1).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(
+            page.view().get(&"a".to_string()),
+            Some(Some("1".to_string()))
+        );
+        assert_eq!(page.view().get(&"b".to_string()), None);
+    }
+}
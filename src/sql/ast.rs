@@ -0,0 +1,96 @@
+use crate::storage_engine::{DataType, StorageKind, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BinaryOp {
+    And,
+    Or,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Column(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    IsNull(Box<Expr>, bool),
+}
+
+#[derive(Clone, Debug)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: DataType,
+    pub primary_key: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum SelectItem {
+    Wildcard,
+    Expr(Expr, Option<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub ascending: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnDef>,
+        primary_key: Option<String>,
+        // None without a USING clause
+        storage: Option<StorageKind>,
+    },
+    DropTable {
+        name: String,
+        if_exists: bool,
+    },
+    CreateIndex {
+        name: String,
+        table: String,
+        column: String,
+    },
+    DropIndex {
+        name: String,
+        if_exists: bool,
+    },
+    Insert {
+        table: String,
+        columns: Option<Vec<String>>,
+        rows: Vec<Vec<Expr>>,
+    },
+    Select {
+        items: Vec<SelectItem>,
+        from: String,
+        selection: Option<Expr>,
+        order_by: Vec<OrderBy>,
+        limit: Option<usize>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Expr)>,
+        selection: Option<Expr>,
+    },
+    Delete {
+        table: String,
+        selection: Option<Expr>,
+    },
+}
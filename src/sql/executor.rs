@@ -0,0 +1,382 @@
+use std::{cmp::Ordering, collections::BTreeSet};
+
+use super::{
+    ast::{BinaryOp, UnaryOp},
+    planner::{Operator, Plan, ScalarExpr},
+    SqlError,
+};
+use crate::{
+    buffer_manager::BufferManager,
+    storage::Storage,
+    storage_engine::{EngineError, Row, RowId, Schema, StorageEngine, Value},
+};
+
+#[derive(Debug)]
+pub enum Output {
+    Rows {
+        columns: Vec<String>,
+        rows: Vec<Row>,
+    },
+    Affected(usize),
+    Done,
+}
+
+pub fn execute<S: Storage + 'static>(
+    engine: &mut StorageEngine<S>,
+    manager: &mut BufferManager<S>,
+    plan: Plan,
+) -> Result<Output, SqlError> {
+    match plan {
+        Plan::CreateTable(schema) => {
+            engine.create_table(manager, schema)?;
+            Ok(Output::Done)
+        }
+        Plan::DropTable { name, if_exists } => {
+            if if_exists && engine.table(&name).is_err() {
+                return Ok(Output::Done);
+            }
+            engine.drop_table(manager, &name)?;
+            Ok(Output::Done)
+        }
+        Plan::CreateIndex { table, def } => {
+            engine.create_index(manager, &table, def)?;
+            Ok(Output::Done)
+        }
+        Plan::DropIndex { name, if_exists } => match engine.drop_index(manager, &name) {
+            Err(EngineError::NoSuchIndex(_)) if if_exists => Ok(Output::Done),
+            res => {
+                res?;
+                Ok(Output::Done)
+            }
+        },
+        Plan::Select { columns, source } => {
+            let rows = run(&source, engine, manager)?;
+            Ok(Output::Rows { columns, rows })
+        }
+        Plan::Insert { table, rows } => {
+            let t = engine.table_mut(manager, &table)?;
+
+            // validate every row before writing any so a failed insert leaves no trace
+            let mut checked = Vec::new();
+            let mut keys = BTreeSet::new();
+            for exprs in rows {
+                let mut values = Vec::new();
+                for e in exprs.iter() {
+                    values.push(eval(e, &Row(Vec::new()))?);
+                }
+                let row = Row(values);
+                check_row(&t.schema, &row)?;
+                t.check_fits(&row)
+                    .map_err(|e| SqlError::Execute(e.to_string()))?;
+
+                if let Some(pk) = t.schema.primary_key {
+                    let key = row.0[pk].clone();
+                    if !keys.insert(key.clone()) || t.get_row(manager, &key).is_some() {
+                        return Err(SqlError::Execute(format!("duplicate primary key {}", key)));
+                    }
+                }
+                checked.push(row);
+            }
+
+            let count = checked.len();
+            for row in checked {
+                t.put_row(manager, row)
+                    .map_err(|e| SqlError::Execute(e.to_string()))?;
+            }
+            Ok(Output::Affected(count))
+        }
+        Plan::Update {
+            table,
+            source,
+            assignments,
+        } => {
+            let matched = locate(&source, engine, manager)?;
+            let t = engine.table_mut(manager, &table)?;
+
+            let mut changed = Vec::new();
+            for (id, row) in matched {
+                let mut updated = row.clone();
+                for (i, e) in assignments.iter() {
+                    updated.0[*i] = eval(e, &row)?;
+                }
+                check_row(&t.schema, &updated)?;
+                t.check_fits(&updated)
+                    .map_err(|e| SqlError::Execute(e.to_string()))?;
+                changed.push((id, row, updated));
+            }
+
+            // the rows of a heap table are changed where they are
+            let Some(pk) = t.schema.primary_key else {
+                let count = changed.len();
+                for (id, _, row) in changed {
+                    if let RowId::Rid(rid) = id {
+                        t.update_row(manager, rid, row)
+                            .map_err(|e| SqlError::Execute(e.to_string()))?;
+                    }
+                }
+                return Ok(Output::Affected(count));
+            };
+
+            let old_keys: BTreeSet<Value> =
+                changed.iter().map(|(_, r, _)| r.0[pk].clone()).collect();
+            let mut new_keys = BTreeSet::new();
+            let mut updates = Vec::new();
+            for (_, row, updated) in changed {
+                let old_key = row.0[pk].clone();
+                let new_key = updated.0[pk].clone();
+                if !new_keys.insert(new_key.clone())
+                    || (!old_keys.contains(&new_key) && t.get_row(manager, &new_key).is_some())
+                {
+                    return Err(SqlError::Execute(format!(
+                        "duplicate primary key {}",
+                        new_key
+                    )));
+                }
+                updates.push((old_key, new_key, updated));
+            }
+
+            let count = updates.len();
+            for (old_key, _, _) in updates.iter() {
+                if !new_keys.contains(old_key) {
+                    t.delete_row(manager, &RowId::Key(old_key.clone()));
+                }
+            }
+            for (_, _, row) in updates {
+                t.put_row(manager, row)
+                    .map_err(|e| SqlError::Execute(e.to_string()))?;
+            }
+            Ok(Output::Affected(count))
+        }
+        Plan::Delete { table, source } => {
+            let matched = locate(&source, engine, manager)?;
+            let t = engine.table_mut(manager, &table)?;
+            let count = matched.len();
+            for (id, _) in matched {
+                t.delete_row(manager, &id);
+            }
+            Ok(Output::Affected(count))
+        }
+    }
+}
+
+fn check_row(schema: &Schema, row: &Row) -> Result<(), SqlError> {
+    for (c, v) in schema.columns.iter().zip(row.0.iter()) {
+        if !c.data_type.accepts(v) {
+            return Err(SqlError::Execute(format!(
+                "column {} expects {}, got {}",
+                c.name, c.data_type, v
+            )));
+        }
+    }
+    if let Some(pk) = schema.primary_key {
+        if row.0[pk] == Value::Null {
+            return Err(SqlError::Execute(format!(
+                "primary key {} cannot be NULL",
+                schema.columns[pk].name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// the rows a scan and the filters over it return, together with where they live, for
+// statements that change them
+fn locate<S: Storage + 'static>(
+    op: &Operator,
+    engine: &StorageEngine<S>,
+    manager: &mut BufferManager<S>,
+) -> Result<Vec<(RowId, Row)>, SqlError> {
+    match op {
+        Operator::TableScan { table, start, end } => {
+            let t = engine.table(table)?;
+            Ok(t.scan(manager, (start.clone(), end.clone())))
+        }
+        Operator::PointLookup { table, key } => {
+            let t = engine.table(table)?;
+            let row = t.get_row(manager, key);
+            Ok(row
+                .map(|row| (RowId::Key(key.clone()), row))
+                .into_iter()
+                .collect())
+        }
+        Operator::IndexScan {
+            table,
+            index,
+            start,
+            end,
+        } => {
+            let t = engine.table(table)?;
+            Ok(t.index_scan(manager, index, start.clone(), end.clone())?)
+        }
+        Operator::Filter { input, predicate } => {
+            let mut rows = Vec::new();
+            for (id, row) in locate(input, engine, manager)? {
+                if eval(predicate, &row)? == Value::Boolean(true) {
+                    rows.push((id, row));
+                }
+            }
+            Ok(rows)
+        }
+        _ => Err(SqlError::Execute(
+            "rows to change come straight from a table".to_string(),
+        )),
+    }
+}
+
+fn run<S: Storage + 'static>(
+    op: &Operator,
+    engine: &StorageEngine<S>,
+    manager: &mut BufferManager<S>,
+) -> Result<Vec<Row>, SqlError> {
+    match op {
+        Operator::TableScan { .. }
+        | Operator::PointLookup { .. }
+        | Operator::IndexScan { .. }
+        | Operator::Filter { .. } => {
+            let rows = locate(op, engine, manager)?;
+            Ok(rows.into_iter().map(|(_, row)| row).collect())
+        }
+        Operator::Project { input, exprs } => {
+            let mut rows = Vec::new();
+            for row in run(input, engine, manager)? {
+                let mut values = Vec::new();
+                for e in exprs {
+                    values.push(eval(e, &row)?);
+                }
+                rows.push(Row(values));
+            }
+            Ok(rows)
+        }
+        Operator::Sort { input, keys } => {
+            let mut keyed = Vec::new();
+            for row in run(input, engine, manager)? {
+                let mut sort_key = Vec::new();
+                for (e, _) in keys {
+                    sort_key.push(eval(e, &row)?);
+                }
+                keyed.push((sort_key, row));
+            }
+            keyed.sort_by(|(a, _), (b, _)| {
+                for (i, (_, ascending)) in keys.iter().enumerate() {
+                    let ord = a[i].cmp(&b[i]);
+                    if ord != Ordering::Equal {
+                        return if *ascending { ord } else { ord.reverse() };
+                    }
+                }
+                Ordering::Equal
+            });
+            Ok(keyed.into_iter().map(|(_, row)| row).collect())
+        }
+        Operator::Limit { input, limit } => {
+            let mut rows = run(input, engine, manager)?;
+            rows.truncate(*limit);
+            Ok(rows)
+        }
+    }
+}
+
+// NULL propagates through operators, comparisons involving NULL are NULL
+pub fn eval(e: &ScalarExpr, row: &Row) -> Result<Value, SqlError> {
+    match e {
+        ScalarExpr::Literal(v) => Ok(v.clone()),
+        ScalarExpr::Column(i) => row
+            .0
+            .get(*i)
+            .cloned()
+            .ok_or_else(|| SqlError::Execute("column reference outside row".to_string())),
+        ScalarExpr::IsNull(inner, negated) => {
+            let is_null = eval(inner, row)? == Value::Null;
+            Ok(Value::Boolean(is_null != *negated))
+        }
+        ScalarExpr::Unary(op, inner) => match (op, eval(inner, row)?) {
+            (_, Value::Null) => Ok(Value::Null),
+            (UnaryOp::Not, Value::Boolean(b)) => Ok(Value::Boolean(!b)),
+            (UnaryOp::Neg, Value::Integer(i)) => i
+                .checked_neg()
+                .map(Value::Integer)
+                .ok_or_else(|| SqlError::Execute("integer overflow".to_string())),
+            (op, v) => Err(SqlError::Execute(format!("cannot apply {:?} to {}", op, v))),
+        },
+        ScalarExpr::Binary(l, BinaryOp::And, r) => {
+            match (truth(eval(l, row)?)?, truth(eval(r, row)?)?) {
+                (Some(false), _) | (_, Some(false)) => Ok(Value::Boolean(false)),
+                (Some(true), Some(true)) => Ok(Value::Boolean(true)),
+                _ => Ok(Value::Null),
+            }
+        }
+        ScalarExpr::Binary(l, BinaryOp::Or, r) => {
+            match (truth(eval(l, row)?)?, truth(eval(r, row)?)?) {
+                (Some(true), _) | (_, Some(true)) => Ok(Value::Boolean(true)),
+                (Some(false), Some(false)) => Ok(Value::Boolean(false)),
+                _ => Ok(Value::Null),
+            }
+        }
+        ScalarExpr::Binary(l, op, r) => {
+            let lv = eval(l, row)?;
+            let rv = eval(r, row)?;
+            if lv == Value::Null || rv == Value::Null {
+                return Ok(Value::Null);
+            }
+            match op {
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                    arithmetic(op, lv, rv)
+                }
+                _ => {
+                    if std::mem::discriminant(&lv) != std::mem::discriminant(&rv) {
+                        return Err(SqlError::Execute(format!(
+                            "cannot compare {} and {}",
+                            lv, rv
+                        )));
+                    }
+                    let ord = lv.cmp(&rv);
+                    let result = match op {
+                        BinaryOp::Eq => ord == Ordering::Equal,
+                        BinaryOp::NotEq => ord != Ordering::Equal,
+                        BinaryOp::Lt => ord == Ordering::Less,
+                        BinaryOp::LtEq => ord != Ordering::Greater,
+                        BinaryOp::Gt => ord == Ordering::Greater,
+                        _ => ord != Ordering::Less,
+                    };
+                    Ok(Value::Boolean(result))
+                }
+            }
+        }
+    }
+}
+
+fn truth(v: Value) -> Result<Option<bool>, SqlError> {
+    match v {
+        Value::Null => Ok(None),
+        Value::Boolean(b) => Ok(Some(b)),
+        other => Err(SqlError::Execute(format!(
+            "expected a boolean, got {}",
+            other
+        ))),
+    }
+}
+
+fn arithmetic(op: &BinaryOp, l: Value, r: Value) -> Result<Value, SqlError> {
+    let (Value::Integer(a), Value::Integer(b)) = (&l, &r) else {
+        if let (BinaryOp::Add, Value::Text(a), Value::Text(b)) = (op, &l, &r) {
+            return Ok(Value::Text(format!("{}{}", a, b)));
+        }
+        return Err(SqlError::Execute(format!(
+            "cannot apply {:?} to {} and {}",
+            op, l, r
+        )));
+    };
+    let result = match op {
+        BinaryOp::Add => a.checked_add(*b),
+        BinaryOp::Sub => a.checked_sub(*b),
+        BinaryOp::Mul => a.checked_mul(*b),
+        _ => {
+            if *b == 0 {
+                return Err(SqlError::Execute("division by zero".to_string()));
+            }
+            a.checked_div(*b)
+        }
+    };
+    result
+        .map(Value::Integer)
+        .ok_or_else(|| SqlError::Execute("integer overflow".to_string()))
+}
@@ -0,0 +1,126 @@
+use super::SqlError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    // identifiers and keywords, keywords are matched case-insensitively by the parser
+    Word(String),
+    QuotedIdent(String),
+    Integer(i64),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    Semicolon,
+    Star,
+    Plus,
+    Minus,
+    Slash,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, SqlError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // -- comments run to the end of the line
+        if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text
+                .parse::<i64>()
+                .map_err(|_| SqlError::Parse(format!("integer {} out of range", text)))?;
+            tokens.push(Token::Integer(n));
+            continue;
+        }
+
+        if c == '\'' || c == '"' {
+            // quotes are escaped by doubling them
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(SqlError::Parse("unterminated quoted string".to_string()));
+                    }
+                    Some(&q) if q == c => {
+                        if chars.get(i + 1) == Some(&c) {
+                            s.push(c);
+                            i += 2;
+                        } else {
+                            i += 1;
+                            break;
+                        }
+                    }
+                    Some(&x) => {
+                        s.push(x);
+                        i += 1;
+                    }
+                }
+            }
+            if c == '\'' {
+                tokens.push(Token::Str(s));
+            } else {
+                tokens.push(Token::QuotedIdent(s));
+            }
+            continue;
+        }
+
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            (',', _) => (Token::Comma, 1),
+            (';', _) => (Token::Semicolon, 1),
+            ('*', _) => (Token::Star, 1),
+            ('+', _) => (Token::Plus, 1),
+            ('-', _) => (Token::Minus, 1),
+            ('/', _) => (Token::Slash, 1),
+            ('=', _) => (Token::Eq, 1),
+            ('!', Some('=')) => (Token::NotEq, 2),
+            ('<', Some('>')) => (Token::NotEq, 2),
+            ('<', Some('=')) => (Token::LtEq, 2),
+            ('<', _) => (Token::Lt, 1),
+            ('>', Some('=')) => (Token::GtEq, 2),
+            ('>', _) => (Token::Gt, 1),
+            _ => {
+                return Err(SqlError::Parse(format!("unexpected character {:?}", c)));
+            }
+        };
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
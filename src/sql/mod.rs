@@ -0,0 +1,328 @@
+use std::fmt;
+
+use crate::{
+    buffer_manager::BufferManager, storage::Storage, storage_engine::EngineError,
+    storage_engine::StorageEngine,
+};
+
+pub mod ast;
+pub mod executor;
+pub mod lexer;
+pub mod parser;
+pub mod planner;
+
+use executor::Output;
+
+#[derive(Debug)]
+pub enum SqlError {
+    Parse(String),
+    Plan(String),
+    Execute(String),
+    Engine(EngineError),
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlError::Parse(msg) => write!(f, "parse error: {}", msg),
+            SqlError::Plan(msg) => write!(f, "planning error: {}", msg),
+            SqlError::Execute(msg) => write!(f, "execution error: {}", msg),
+            SqlError::Engine(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<EngineError> for SqlError {
+    fn from(err: EngineError) -> Self {
+        SqlError::Engine(err)
+    }
+}
+
+// parses, plans and executes each statement in turn, stopping at the first error
+pub fn run<S: Storage + 'static>(
+    engine: &mut StorageEngine<S>,
+    manager: &mut BufferManager<S>,
+    sql: &str,
+) -> Result<Vec<Output>, SqlError> {
+    let mut outputs = Vec::new();
+    for statement in parser::parse(sql)? {
+        let plan = planner::plan(engine, statement)?;
+        outputs.push(executor::execute(engine, manager, plan)?);
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::{
+        options::Options,
+        storage::MemStorage,
+        storage_engine::{IndexKey, Row, RowId, TableStore, Value},
+    };
+
+    const DIR: &str = "sql";
+
+    type Engine = StorageEngine<MemStorage>;
+    type Manager = BufferManager<MemStorage>;
+
+    // an engine over the files of storage, which reopening it on a clone finds again
+    fn open(storage: MemStorage) -> (Engine, Manager) {
+        let options = Options::new().data_dir(DIR).cache_size(64 * 4096);
+        let mut manager = BufferManager::with_storage(options.cache_blocks(), storage);
+        let engine = StorageEngine::with_options(&mut manager, &options).unwrap();
+        (engine, manager)
+    }
+
+    #[test]
+    fn names_stay_inside_the_data_directory() {
+        let (mut engine, mut manager) = open(MemStorage::new());
+        for sql in [
+            "CREATE TABLE \"../evil\" (a INT PRIMARY KEY);",
+            "CREATE TABLE \"x/y\" (a INT PRIMARY KEY);",
+            "CREATE TABLE \"a.b\" (a INT PRIMARY KEY);",
+            "CREATE TABLE \"\" (a INT PRIMARY KEY);",
+            "CREATE TABLE \"a\0b\" (a INT PRIMARY KEY);",
+        ] {
+            match run(&mut engine, &mut manager, sql) {
+                Err(SqlError::Plan(_)) => {}
+                other => panic!("{} gave {:?}", sql, other),
+            }
+        }
+        run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE t (a INT PRIMARY KEY);",
+        )
+        .unwrap();
+        match run(&mut engine, &mut manager, "CREATE INDEX \"t.a\" ON t (a);") {
+            Err(SqlError::Plan(_)) => {}
+            other => panic!("dotted index name gave {:?}", other),
+        }
+        let storage = manager.storage();
+        for dir in [".", "sql/..", "sql/x"] {
+            assert!(storage.list(dir).unwrap().is_empty(), "files in {}", dir);
+        }
+        assert!(storage
+            .list(DIR)
+            .unwrap()
+            .iter()
+            .all(|name| name == "tablefile" || name == "unclean" || name.starts_with("t.table")));
+    }
+
+    #[test]
+    fn table_names_ignore_case() {
+        let (mut engine, mut manager) = open(MemStorage::new());
+        run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE Foo (id INT PRIMARY KEY, name TEXT);
+             INSERT INTO foo VALUES (1, 'a');
+             CREATE INDEX ByName ON FOO (name);",
+        )
+        .unwrap();
+        let outputs = run(
+            &mut engine,
+            &mut manager,
+            "SELECT * FROM fOO WHERE name = 'a';",
+        )
+        .unwrap();
+        match &outputs[0] {
+            Output::Rows { rows, .. } => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(rows[0].0[0], Value::Integer(1));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE FOO (a INT PRIMARY KEY);"
+        )
+        .is_err());
+        run(
+            &mut engine,
+            &mut manager,
+            "DROP INDEX byname; DROP TABLE foo;",
+        )
+        .unwrap();
+        assert!(engine.tables().next().is_none());
+    }
+
+    #[test]
+    fn rows_too_large_for_a_page_are_turned_down() {
+        let (mut engine, mut manager) = open(MemStorage::new());
+        run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE l (id INT PRIMARY KEY, body TEXT);
+             CREATE TABLE b (id INT PRIMARY KEY, body TEXT) USING BTREE;
+             CREATE INDEX l_body ON l (body);",
+        )
+        .unwrap();
+        let big = "x".repeat(5000);
+        for table in ["l", "b"] {
+            let sql = format!("INSERT INTO {} VALUES (1, '{}');", table, big);
+            match run(&mut engine, &mut manager, &sql) {
+                Err(SqlError::Execute(_)) => {}
+                other => panic!("{} gave {:?}", table, other),
+            }
+            let sql = format!("INSERT INTO {} VALUES (2, 'small');", table);
+            run(&mut engine, &mut manager, &sql).unwrap();
+        }
+        engine.flush(&mut manager);
+        let outputs = run(
+            &mut engine,
+            &mut manager,
+            "SELECT * FROM l WHERE body = 'small';",
+        )
+        .unwrap();
+        match &outputs[0] {
+            Output::Rows { rows, .. } => assert_eq!(rows.len(), 1),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn rows(outputs: &[Output]) -> Vec<Row> {
+        match outputs.last() {
+            Some(Output::Rows { rows, .. }) => rows.clone(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn tables_without_a_primary_key_live_in_a_heap() {
+        let (mut engine, mut manager) = open(MemStorage::new());
+        run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE log (level TEXT, message TEXT);
+             INSERT INTO log VALUES ('info', 'a'), ('warn', 'b'), ('info', 'a');
+             CREATE INDEX by_level ON log (level);
+             INSERT INTO log VALUES ('info', 'c');",
+        )
+        .unwrap();
+        assert!(run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE k (a INT) USING BTREE;"
+        )
+        .is_err());
+        assert!(run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE k (a INT PRIMARY KEY) USING HEAP;"
+        )
+        .is_err());
+
+        let got = run(
+            &mut engine,
+            &mut manager,
+            "SELECT message FROM log WHERE level = 'info';",
+        );
+        assert_eq!(rows(&got.unwrap()).len(), 3);
+
+        // rows that grow move to another page, the index follows them
+        let long = "m".repeat(3000);
+        let sql = format!("UPDATE log SET message = '{}' WHERE message = 'a';", long);
+        run(&mut engine, &mut manager, &sql).unwrap();
+        run(
+            &mut engine,
+            &mut manager,
+            "UPDATE log SET level = 'error' WHERE message = 'b';
+             DELETE FROM log WHERE message = 'c';",
+        )
+        .unwrap();
+        engine.flush(&mut manager);
+
+        let (mut engine, mut manager) = open(manager.storage().clone());
+        let got = run(
+            &mut engine,
+            &mut manager,
+            "SELECT message FROM log WHERE level = 'info';",
+        );
+        assert_eq!(
+            rows(&got.unwrap()),
+            vec![Row(vec![Value::Text(long.clone())]); 2]
+        );
+        let got = run(
+            &mut engine,
+            &mut manager,
+            "SELECT level FROM log WHERE level > 'f';",
+        );
+        assert_eq!(
+            rows(&got.unwrap()),
+            vec![Row(vec![Value::Text("info".to_string())]); 2]
+        );
+        let got = run(
+            &mut engine,
+            &mut manager,
+            "SELECT * FROM log WHERE level = 'error';",
+        );
+        assert_eq!(
+            rows(&got.unwrap()),
+            vec![Row(vec![
+                Value::Text("error".to_string()),
+                Value::Text("b".to_string())
+            ])]
+        );
+        run(&mut engine, &mut manager, "DROP TABLE log;").unwrap();
+    }
+
+    #[test]
+    fn indexes_never_point_at_missing_rows_after_a_crash() {
+        let (mut engine, mut manager) = open(MemStorage::new());
+        run(
+            &mut engine,
+            &mut manager,
+            "CREATE TABLE t (id INT PRIMARY KEY, name TEXT);
+             CREATE INDEX t_name ON t (name);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b');",
+        )
+        .unwrap();
+        engine.checkpoint(&mut manager);
+
+        // the writes of two rows cut short between their index entry and the row: one
+        // got its entry without the row, the other the row without the entry
+        let t = engine.table_mut(&mut manager, "t").unwrap();
+        let entry = IndexKey(Value::Text("ghost".to_string()), Value::Integer(3));
+        t.indexes[0]
+            .tree
+            .put(&mut manager, entry, Some(None))
+            .unwrap();
+        t.delete_row(&mut manager, &RowId::Key(Value::Integer(1)));
+        let row = Row(vec![Value::Integer(4), Value::Text("lost".to_string())]);
+        match &mut t.store {
+            TableStore::Tree(tree) => tree.put(&mut manager, Value::Integer(4), Some(row)),
+            TableStore::Heap(_) => unreachable!(),
+        }
+        .unwrap();
+        // a crash loses what is only in the buffer pool, the logs of the trees survive
+        drop(engine);
+        let (mut engine, mut manager) = open(manager.storage().clone());
+        let t = engine.table("t").unwrap();
+        let entries = t.indexes[0]
+            .tree
+            .scan(&mut manager, (Bound::Unbounded, Bound::Unbounded));
+        let names: Vec<Value> = entries.into_iter().map(|(IndexKey(v, _), _)| v).collect();
+        assert_eq!(
+            names,
+            vec![
+                Value::Text("b".to_string()),
+                Value::Text("lost".to_string())
+            ]
+        );
+        let got = run(
+            &mut engine,
+            &mut manager,
+            "SELECT id FROM t WHERE name = 'lost';",
+        );
+        assert_eq!(rows(&got.unwrap()), vec![Row(vec![Value::Integer(4)])]);
+
+        // once checkpointed the engine opens without rebuilding
+        engine.checkpoint(&mut manager);
+        assert!(manager.storage().open("sql/unclean", false).is_err());
+    }
+}
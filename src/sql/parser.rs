@@ -0,0 +1,497 @@
+use super::{
+    ast::{BinaryOp, ColumnDef, Expr, OrderBy, SelectItem, Statement, UnaryOp},
+    lexer::{tokenize, Token},
+    SqlError,
+};
+use crate::storage_engine::{DataType, StorageKind, Value};
+
+// parses a string holding any number of `;` separated statements
+pub fn parse(sql: &str) -> Result<Vec<Statement>, SqlError> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+
+    let mut statements = Vec::new();
+    loop {
+        while parser.eat(&Token::Semicolon) {}
+        if parser.peek().is_none() {
+            break;
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() && !parser.eat(&Token::Semicolon) {
+            return Err(parser.unexpected("; or end of input"));
+        }
+    }
+    Ok(statements)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn unexpected(&self, expected: &str) -> SqlError {
+        match self.peek() {
+            Some(t) => SqlError::Parse(format!("expected {}, found {:?}", expected, t)),
+            None => SqlError::Parse(format!("expected {}, found end of input", expected)),
+        }
+    }
+
+    fn eat(&mut self, t: &Token) -> bool {
+        if self.peek() == Some(t) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, t: &Token) -> Result<(), SqlError> {
+        if self.eat(t) {
+            return Ok(());
+        }
+        Err(self.unexpected(&format!("{:?}", t)))
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), SqlError> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        Err(self.unexpected(keyword))
+    }
+
+    fn ident(&mut self) -> Result<String, SqlError> {
+        match self.peek() {
+            Some(Token::Word(w)) if !is_reserved(w) => {
+                let w = w.clone();
+                self.pos += 1;
+                Ok(w)
+            }
+            Some(Token::QuotedIdent(w)) => {
+                let w = w.clone();
+                self.pos += 1;
+                Ok(w)
+            }
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
+
+    fn ident_list(&mut self) -> Result<Vec<String>, SqlError> {
+        self.expect(&Token::LParen)?;
+        let mut idents = vec![self.ident()?];
+        while self.eat(&Token::Comma) {
+            idents.push(self.ident()?);
+        }
+        self.expect(&Token::RParen)?;
+        Ok(idents)
+    }
+
+    fn statement(&mut self) -> Result<Statement, SqlError> {
+        if self.eat_keyword("CREATE") {
+            return self.create();
+        }
+        if self.eat_keyword("DROP") {
+            return self.drop();
+        }
+        if self.eat_keyword("INSERT") {
+            return self.insert();
+        }
+        if self.eat_keyword("SELECT") {
+            return self.select();
+        }
+        if self.eat_keyword("UPDATE") {
+            return self.update();
+        }
+        if self.eat_keyword("DELETE") {
+            return self.delete();
+        }
+        Err(self.unexpected("statement"))
+    }
+
+    fn create(&mut self) -> Result<Statement, SqlError> {
+        if self.eat_keyword("INDEX") {
+            let name = self.ident()?;
+            self.expect_keyword("ON")?;
+            let table = self.ident()?;
+            let mut columns = self.ident_list()?;
+            if columns.len() != 1 {
+                return Err(SqlError::Parse(
+                    "multi-column indexes are not supported".to_string(),
+                ));
+            }
+            return Ok(Statement::CreateIndex {
+                name,
+                table,
+                column: columns.pop().unwrap(),
+            });
+        }
+        self.expect_keyword("TABLE")?;
+        let name = self.ident()?;
+        self.expect(&Token::LParen)?;
+
+        let mut columns = Vec::new();
+        let mut primary_key = None;
+        loop {
+            if self.eat_keyword("PRIMARY") {
+                self.expect_keyword("KEY")?;
+                let mut key = self.ident_list()?;
+                if key.len() != 1 {
+                    return Err(SqlError::Parse(
+                        "composite primary keys are not supported".to_string(),
+                    ));
+                }
+                primary_key = key.pop();
+            } else {
+                let column_name = self.ident()?;
+                let data_type = self.data_type()?;
+                let mut is_primary = false;
+                if self.eat_keyword("PRIMARY") {
+                    self.expect_keyword("KEY")?;
+                    is_primary = true;
+                }
+                columns.push(ColumnDef {
+                    name: column_name,
+                    data_type,
+                    primary_key: is_primary,
+                });
+            }
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(&Token::RParen)?;
+
+        let mut storage = None;
+        if self.eat_keyword("USING") {
+            storage = Some(match self.next() {
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("LSM") => StorageKind::Lsm,
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("BTREE") => StorageKind::BTree,
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("HEAP") => StorageKind::Heap,
+                _ => return Err(SqlError::Parse("expected LSM, BTREE or HEAP".to_string())),
+            });
+        }
+
+        Ok(Statement::CreateTable {
+            name,
+            columns,
+            primary_key,
+            storage,
+        })
+    }
+
+    fn data_type(&mut self) -> Result<DataType, SqlError> {
+        let Some(Token::Word(w)) = self.peek() else {
+            return Err(self.unexpected("column type"));
+        };
+        let data_type = match w.to_ascii_uppercase().as_str() {
+            "INT" | "INTEGER" | "BIGINT" => DataType::Integer,
+            "TEXT" | "VARCHAR" | "STRING" => DataType::Text,
+            "BOOL" | "BOOLEAN" => DataType::Boolean,
+            _ => return Err(self.unexpected("column type")),
+        };
+        self.pos += 1;
+
+        // VARCHAR(n) style lengths are accepted and ignored
+        if self.eat(&Token::LParen) {
+            let Some(Token::Integer(_)) = self.next() else {
+                return Err(SqlError::Parse("expected type length".to_string()));
+            };
+            self.expect(&Token::RParen)?;
+        }
+        Ok(data_type)
+    }
+
+    fn drop(&mut self) -> Result<Statement, SqlError> {
+        let index = self.eat_keyword("INDEX");
+        if !index {
+            self.expect_keyword("TABLE")?;
+        }
+        let mut if_exists = false;
+        if self.eat_keyword("IF") {
+            self.expect_keyword("EXISTS")?;
+            if_exists = true;
+        }
+        let name = self.ident()?;
+        if index {
+            return Ok(Statement::DropIndex { name, if_exists });
+        }
+        Ok(Statement::DropTable { name, if_exists })
+    }
+
+    fn insert(&mut self) -> Result<Statement, SqlError> {
+        self.expect_keyword("INTO")?;
+        let table = self.ident()?;
+        let mut columns = None;
+        if self.peek() == Some(&Token::LParen) {
+            columns = Some(self.ident_list()?);
+        }
+        self.expect_keyword("VALUES")?;
+
+        let mut rows = Vec::new();
+        loop {
+            self.expect(&Token::LParen)?;
+            let mut row = vec![self.expr()?];
+            while self.eat(&Token::Comma) {
+                row.push(self.expr()?);
+            }
+            self.expect(&Token::RParen)?;
+            rows.push(row);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        Ok(Statement::Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn select(&mut self) -> Result<Statement, SqlError> {
+        let mut items = Vec::new();
+        loop {
+            if self.eat(&Token::Star) {
+                items.push(SelectItem::Wildcard);
+            } else {
+                let expr = self.expr()?;
+                let mut alias = None;
+                if self.eat_keyword("AS") {
+                    alias = Some(self.ident()?);
+                }
+                items.push(SelectItem::Expr(expr, alias));
+            }
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        self.expect_keyword("FROM")?;
+        let from = self.ident()?;
+
+        let mut selection = None;
+        if self.eat_keyword("WHERE") {
+            selection = Some(self.expr()?);
+        }
+
+        let mut order_by = Vec::new();
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let expr = self.expr()?;
+                let mut ascending = true;
+                if self.eat_keyword("DESC") {
+                    ascending = false;
+                } else {
+                    self.eat_keyword("ASC");
+                }
+                order_by.push(OrderBy { expr, ascending });
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+
+        let mut limit = None;
+        if self.eat_keyword("LIMIT") {
+            match self.next() {
+                Some(Token::Integer(n)) if n >= 0 => limit = Some(n as usize),
+                _ => return Err(SqlError::Parse("expected LIMIT count".to_string())),
+            }
+        }
+
+        Ok(Statement::Select {
+            items,
+            from,
+            selection,
+            order_by,
+            limit,
+        })
+    }
+
+    fn update(&mut self) -> Result<Statement, SqlError> {
+        let table = self.ident()?;
+        self.expect_keyword("SET")?;
+
+        let mut assignments = Vec::new();
+        loop {
+            let column = self.ident()?;
+            self.expect(&Token::Eq)?;
+            assignments.push((column, self.expr()?));
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        let mut selection = None;
+        if self.eat_keyword("WHERE") {
+            selection = Some(self.expr()?);
+        }
+
+        Ok(Statement::Update {
+            table,
+            assignments,
+            selection,
+        })
+    }
+
+    fn delete(&mut self) -> Result<Statement, SqlError> {
+        self.expect_keyword("FROM")?;
+        let table = self.ident()?;
+        let mut selection = None;
+        if self.eat_keyword("WHERE") {
+            selection = Some(self.expr()?);
+        }
+        Ok(Statement::Delete { table, selection })
+    }
+
+    // precedence climbs OR, AND, NOT, comparisons, + -, * /, unary minus
+    fn expr(&mut self) -> Result<Expr, SqlError> {
+        let mut left = self.and_expr()?;
+        while self.eat_keyword("OR") {
+            let right = self.and_expr()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::Or, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, SqlError> {
+        let mut left = self.not_expr()?;
+        while self.eat_keyword("AND") {
+            let right = self.not_expr()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::And, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr, SqlError> {
+        if self.eat_keyword("NOT") {
+            let inner = self.not_expr()?;
+            return Ok(Expr::Unary(UnaryOp::Not, Box::new(inner)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, SqlError> {
+        let left = self.additive()?;
+
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Expr::IsNull(Box::new(left), negated));
+        }
+
+        let op = match self.peek() {
+            Some(Token::Eq) => BinaryOp::Eq,
+            Some(Token::NotEq) => BinaryOp::NotEq,
+            Some(Token::Lt) => BinaryOp::Lt,
+            Some(Token::LtEq) => BinaryOp::LtEq,
+            Some(Token::Gt) => BinaryOp::Gt,
+            Some(Token::GtEq) => BinaryOp::GtEq,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.additive()?;
+        Ok(Expr::Binary(Box::new(left), op, Box::new(right)))
+    }
+
+    fn additive(&mut self) -> Result<Expr, SqlError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.multiplicative()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, SqlError> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinaryOp::Mul,
+                Some(Token::Slash) => BinaryOp::Div,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.unary()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, SqlError> {
+        if self.eat(&Token::Minus) {
+            let inner = self.unary()?;
+            return Ok(Expr::Unary(UnaryOp::Neg, Box::new(inner)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, SqlError> {
+        match self.peek().cloned() {
+            Some(Token::Integer(n)) => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Integer(n)))
+            }
+            Some(Token::Str(s)) => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Text(s)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect(&Token::RParen)?;
+                Ok(e)
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("NULL") => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Null))
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("TRUE") => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Boolean(true)))
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("FALSE") => {
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Boolean(false)))
+            }
+            _ => Ok(Expr::Column(self.ident()?)),
+        }
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    const RESERVED: [&str; 32] = [
+        "AND", "AS", "ASC", "BY", "CREATE", "DELETE", "DESC", "DROP", "EXISTS", "FALSE", "FROM",
+        "IF", "INSERT", "INTO", "IS", "KEY", "LIMIT", "NOT", "NULL", "OR", "ORDER", "PRIMARY",
+        "SELECT", "SET", "TABLE", "TRUE", "UPDATE", "VALUES", "WHERE", "INDEX", "ON", "USING",
+    ];
+    RESERVED.iter().any(|r| r.eq_ignore_ascii_case(word))
+}
@@ -0,0 +1,526 @@
+use std::ops::Bound;
+
+use super::{
+    ast::{BinaryOp, Expr, SelectItem, Statement, UnaryOp},
+    executor::eval,
+    SqlError,
+};
+use crate::{
+    storage::Storage,
+    storage_engine::{
+        check_name, Column, DataType, IndexDef, Row, Schema, StorageEngine, StorageKind, Table,
+        Value,
+    },
+};
+
+// expressions with column names resolved to positions in the table row
+#[derive(Clone, Debug)]
+pub enum ScalarExpr {
+    Literal(Value),
+    Column(usize),
+    Unary(UnaryOp, Box<ScalarExpr>),
+    Binary(Box<ScalarExpr>, BinaryOp, Box<ScalarExpr>),
+    IsNull(Box<ScalarExpr>, bool),
+}
+
+#[derive(Debug)]
+pub enum Operator {
+    TableScan {
+        table: String,
+        start: Bound<Value>,
+        end: Bound<Value>,
+    },
+    PointLookup {
+        table: String,
+        key: Value,
+    },
+    // bounds are on the indexed column, matching rows are fetched by primary key
+    IndexScan {
+        table: String,
+        index: String,
+        start: Bound<Value>,
+        end: Bound<Value>,
+    },
+    Filter {
+        input: Box<Operator>,
+        predicate: ScalarExpr,
+    },
+    Project {
+        input: Box<Operator>,
+        exprs: Vec<ScalarExpr>,
+    },
+    Sort {
+        input: Box<Operator>,
+        keys: Vec<(ScalarExpr, bool)>,
+    },
+    Limit {
+        input: Box<Operator>,
+        limit: usize,
+    },
+}
+
+#[derive(Debug)]
+pub enum Plan {
+    CreateTable(Schema),
+    DropTable {
+        name: String,
+        if_exists: bool,
+    },
+    CreateIndex {
+        table: String,
+        def: IndexDef,
+    },
+    DropIndex {
+        name: String,
+        if_exists: bool,
+    },
+    Insert {
+        table: String,
+        rows: Vec<Vec<ScalarExpr>>,
+    },
+    Select {
+        columns: Vec<String>,
+        source: Operator,
+    },
+    Update {
+        table: String,
+        source: Operator,
+        assignments: Vec<(usize, ScalarExpr)>,
+    },
+    Delete {
+        table: String,
+        source: Operator,
+    },
+}
+
+pub fn plan<S: Storage + 'static>(
+    engine: &StorageEngine<S>,
+    statement: Statement,
+) -> Result<Plan, SqlError> {
+    match statement {
+        Statement::CreateTable {
+            name,
+            columns,
+            primary_key,
+            storage,
+        } => plan_create(name, columns, primary_key, storage),
+        Statement::DropTable { name, if_exists } => Ok(Plan::DropTable { name, if_exists }),
+        Statement::CreateIndex {
+            name,
+            table,
+            column,
+        } => {
+            check_name(&name).map_err(|e| SqlError::Plan(e.to_string()))?;
+            let schema = &engine.table(&table)?.schema;
+            let column = resolve_column(schema, &column)?;
+            Ok(Plan::CreateIndex {
+                table,
+                def: IndexDef { name, column },
+            })
+        }
+        Statement::DropIndex { name, if_exists } => Ok(Plan::DropIndex { name, if_exists }),
+        Statement::Insert {
+            table,
+            columns,
+            rows,
+        } => {
+            let schema = &engine.table(&table)?.schema;
+            let positions: Vec<usize> = match columns {
+                None => (0..schema.columns.len()).collect(),
+                Some(names) => {
+                    let mut positions = Vec::new();
+                    for name in names {
+                        let i = resolve_column(schema, &name)?;
+                        if positions.contains(&i) {
+                            return Err(SqlError::Plan(format!("column {} listed twice", name)));
+                        }
+                        positions.push(i);
+                    }
+                    positions
+                }
+            };
+
+            let mut bound_rows = Vec::new();
+            for row in rows {
+                if row.len() != positions.len() {
+                    return Err(SqlError::Plan(format!(
+                        "expected {} values, got {}",
+                        positions.len(),
+                        row.len()
+                    )));
+                }
+                let mut bound = vec![ScalarExpr::Literal(Value::Null); schema.columns.len()];
+                for (expr, i) in row.into_iter().zip(positions.iter()) {
+                    bound[*i] = bind(schema, expr)?;
+                }
+                bound_rows.push(bound);
+            }
+            Ok(Plan::Insert {
+                table,
+                rows: bound_rows,
+            })
+        }
+        Statement::Select {
+            items,
+            from,
+            selection,
+            order_by,
+            limit,
+        } => {
+            let table = engine.table(&from)?;
+            let schema = &table.schema;
+
+            let mut columns = Vec::new();
+            let mut exprs = Vec::new();
+            let mut aliases: Vec<(String, Expr)> = Vec::new();
+            for item in items {
+                match item {
+                    SelectItem::Wildcard => {
+                        for (i, c) in schema.columns.iter().enumerate() {
+                            columns.push(c.name.clone());
+                            exprs.push(ScalarExpr::Column(i));
+                        }
+                    }
+                    SelectItem::Expr(expr, alias) => {
+                        let name = match (&alias, &expr) {
+                            (Some(a), _) => a.clone(),
+                            (None, Expr::Column(c)) => c.clone(),
+                            (None, _) => format!("column{}", columns.len() + 1),
+                        };
+                        if let Some(a) = alias {
+                            aliases.push((a, expr.clone()));
+                        }
+                        columns.push(name);
+                        exprs.push(bind(schema, expr)?);
+                    }
+                }
+            }
+
+            let mut source = plan_source(table, selection)?;
+
+            if !order_by.is_empty() {
+                let mut keys = Vec::new();
+                for o in order_by {
+                    // ORDER BY may name a select alias instead of a table column
+                    let expr = match &o.expr {
+                        Expr::Column(c) if schema.column_index(c).is_none() => aliases
+                            .iter()
+                            .find(|(a, _)| a.eq_ignore_ascii_case(c))
+                            .map(|(_, e)| e.clone())
+                            .unwrap_or(o.expr),
+                        _ => o.expr,
+                    };
+                    keys.push((bind(schema, expr)?, o.ascending));
+                }
+                source = Operator::Sort {
+                    input: Box::new(source),
+                    keys,
+                };
+            }
+
+            if let Some(limit) = limit {
+                source = Operator::Limit {
+                    input: Box::new(source),
+                    limit,
+                };
+            }
+
+            Ok(Plan::Select {
+                columns,
+                source: Operator::Project {
+                    input: Box::new(source),
+                    exprs,
+                },
+            })
+        }
+        Statement::Update {
+            table,
+            assignments,
+            selection,
+        } => {
+            let t = engine.table(&table)?;
+            let mut bound = Vec::new();
+            for (column, expr) in assignments {
+                bound.push((resolve_column(&t.schema, &column)?, bind(&t.schema, expr)?));
+            }
+            let source = plan_source(t, selection)?;
+            Ok(Plan::Update {
+                table,
+                source,
+                assignments: bound,
+            })
+        }
+        Statement::Delete { table, selection } => {
+            let source = plan_source(engine.table(&table)?, selection)?;
+            Ok(Plan::Delete { table, source })
+        }
+    }
+}
+
+fn plan_create(
+    name: String,
+    defs: Vec<super::ast::ColumnDef>,
+    primary_key: Option<String>,
+    storage: Option<StorageKind>,
+) -> Result<Plan, SqlError> {
+    check_name(&name).map_err(|e| SqlError::Plan(e.to_string()))?;
+    let mut columns: Vec<Column> = Vec::new();
+    let mut key = primary_key;
+    for def in defs {
+        if columns
+            .iter()
+            .any(|c| c.name.eq_ignore_ascii_case(&def.name))
+        {
+            return Err(SqlError::Plan(format!("column {} defined twice", def.name)));
+        }
+        if def.primary_key {
+            if key.is_some() {
+                return Err(SqlError::Plan("more than one primary key".to_string()));
+            }
+            key = Some(def.name.clone());
+        }
+        columns.push(Column {
+            name: def.name,
+            data_type: def.data_type,
+        });
+    }
+
+    // a table without a primary key lives in a heap file, and only there
+    let storage = match (&key, storage) {
+        (None, None | Some(StorageKind::Heap)) => StorageKind::Heap,
+        (Some(_), None) => StorageKind::Lsm,
+        (Some(_), Some(StorageKind::Heap)) => {
+            return Err(SqlError::Plan(format!(
+                "heap table {} cannot have a primary key",
+                name
+            )))
+        }
+        (None, Some(kind)) => {
+            return Err(SqlError::Plan(format!(
+                "table {} needs a primary key for {} storage",
+                name, kind
+            )))
+        }
+        (Some(_), Some(kind)) => kind,
+    };
+    let mut schema = Schema {
+        name,
+        columns,
+        primary_key: None,
+        storage,
+    };
+    if let Some(key) = key {
+        schema.primary_key = Some(resolve_column(&schema, &key)?);
+    }
+    Ok(Plan::CreateTable(schema))
+}
+
+// bounds a WHERE clause places on one column, from conjuncts comparing it to a constant
+struct ColumnBounds {
+    point: Option<Value>,
+    start: Bound<Value>,
+    end: Bound<Value>,
+}
+
+impl ColumnBounds {
+    // no bounds at all
+    fn none() -> Self {
+        ColumnBounds {
+            point: None,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    fn is_range(&self) -> bool {
+        !matches!(
+            (&self.start, &self.end),
+            (Bound::Unbounded, Bound::Unbounded)
+        )
+    }
+}
+
+fn column_bounds(predicate: &ScalarExpr, column: usize, data_type: DataType) -> ColumnBounds {
+    let mut bounds = ColumnBounds::none();
+    for conjunct in conjuncts(predicate) {
+        let ScalarExpr::Binary(l, op, r) = conjunct else {
+            continue;
+        };
+        let (op, v) = match (l.as_ref(), r.as_ref()) {
+            (ScalarExpr::Column(c), other) if *c == column => (op.clone(), other),
+            (other, ScalarExpr::Column(c)) if *c == column => (flip(op), other),
+            _ => continue,
+        };
+        let Some(v) = constant(v) else {
+            continue;
+        };
+        if v == Value::Null || !data_type.accepts(&v) {
+            continue;
+        }
+        match op {
+            BinaryOp::Eq => bounds.point = Some(v),
+            BinaryOp::Gt => bounds.start = tighter_start(bounds.start, Bound::Excluded(v)),
+            BinaryOp::GtEq => bounds.start = tighter_start(bounds.start, Bound::Included(v)),
+            BinaryOp::Lt => bounds.end = tighter_end(bounds.end, Bound::Excluded(v)),
+            BinaryOp::LtEq => bounds.end = tighter_end(bounds.end, Bound::Included(v)),
+            _ => {}
+        }
+    }
+    bounds
+}
+
+// picks the access path for a WHERE clause, the full predicate is always re-applied on top.
+// Preference runs primary key equality, index equality, primary key range, index range
+fn plan_source<S: Storage>(
+    table: &Table<S>,
+    selection: Option<Expr>,
+) -> Result<Operator, SqlError> {
+    let schema = &table.schema;
+    let name = schema.name.clone();
+    let Some(selection) = selection else {
+        return Ok(Operator::TableScan {
+            table: name,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        });
+    };
+
+    let predicate = bind(schema, selection)?;
+    let key_bounds = match schema.primary_key {
+        Some(pk) => column_bounds(&predicate, pk, schema.columns[pk].data_type),
+        None => ColumnBounds::none(),
+    };
+
+    let index_bounds: Vec<(String, ColumnBounds)> = table
+        .indexes
+        .iter()
+        .map(|i| {
+            let data_type = schema.columns[i.def.column].data_type;
+            (
+                i.def.name.clone(),
+                column_bounds(&predicate, i.def.column, data_type),
+            )
+        })
+        .collect();
+
+    let scan = if let Some(key) = key_bounds.point {
+        Operator::PointLookup { table: name, key }
+    } else if let Some((index, b)) = index_bounds.iter().find(|(_, b)| b.point.is_some()) {
+        let v = b.point.clone().unwrap();
+        Operator::IndexScan {
+            table: name,
+            index: index.clone(),
+            start: Bound::Included(v.clone()),
+            end: Bound::Included(v),
+        }
+    } else if key_bounds.is_range() {
+        Operator::TableScan {
+            table: name,
+            start: key_bounds.start,
+            end: key_bounds.end,
+        }
+    } else if let Some((index, b)) = index_bounds.into_iter().find(|(_, b)| b.is_range()) {
+        Operator::IndexScan {
+            table: name,
+            index,
+            start: b.start,
+            end: b.end,
+        }
+    } else {
+        Operator::TableScan {
+            table: name,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    };
+    Ok(Operator::Filter {
+        input: Box::new(scan),
+        predicate,
+    })
+}
+
+pub fn conjuncts(e: &ScalarExpr) -> Vec<&ScalarExpr> {
+    match e {
+        ScalarExpr::Binary(l, BinaryOp::And, r) => {
+            let mut v = conjuncts(l);
+            v.extend(conjuncts(r));
+            v
+        }
+        _ => vec![e],
+    }
+}
+
+pub fn flip(op: &BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::LtEq => BinaryOp::GtEq,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::GtEq => BinaryOp::LtEq,
+        other => other.clone(),
+    }
+}
+
+// evaluates expressions that reference no columns
+pub fn constant(e: &ScalarExpr) -> Option<Value> {
+    if references_column(e) {
+        return None;
+    }
+    eval(e, &Row(Vec::new())).ok()
+}
+
+fn references_column(e: &ScalarExpr) -> bool {
+    match e {
+        ScalarExpr::Literal(_) => false,
+        ScalarExpr::Column(_) => true,
+        ScalarExpr::Unary(_, inner) | ScalarExpr::IsNull(inner, _) => references_column(inner),
+        ScalarExpr::Binary(l, _, r) => references_column(l) || references_column(r),
+    }
+}
+
+pub fn tighter_start(a: Bound<Value>, b: Bound<Value>) -> Bound<Value> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+pub fn tighter_end(a: Bound<Value>, b: Bound<Value>) -> Bound<Value> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+fn resolve_column(schema: &Schema, name: &str) -> Result<usize, SqlError> {
+    schema
+        .column_index(name)
+        .ok_or_else(|| SqlError::Plan(format!("no column {} in table {}", name, schema.name)))
+}
+
+fn bind(schema: &Schema, e: Expr) -> Result<ScalarExpr, SqlError> {
+    Ok(match e {
+        Expr::Literal(v) => ScalarExpr::Literal(v),
+        Expr::Column(name) => ScalarExpr::Column(resolve_column(schema, &name)?),
+        Expr::Unary(op, inner) => ScalarExpr::Unary(op, Box::new(bind(schema, *inner)?)),
+        Expr::Binary(l, op, r) => {
+            ScalarExpr::Binary(Box::new(bind(schema, *l)?), op, Box::new(bind(schema, *r)?))
+        }
+        Expr::IsNull(inner, negated) => {
+            ScalarExpr::IsNull(Box::new(bind(schema, *inner)?), negated)
+        }
+    })
+}
@@ -0,0 +1,268 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    rc::Rc,
+};
+
+#[cfg(target_os = "linux")]
+const O_DIRECT: i32 = libc::O_DIRECT;
+// elsewhere files opened for blocks go through the page cache like any other
+#[cfg(not(target_os = "linux"))]
+const O_DIRECT: i32 = 0;
+
+// where the buffer manager and the trees keep their files. Paths are the strings the rest
+// of the code builds, a directory is everything before the last /
+pub trait Storage {
+    type File: StorageFile + 'static;
+
+    // create makes the file when it does not exist yet
+    fn open(&self, path: &str, create: bool) -> io::Result<Self::File>;
+
+    // like open, for files read and written only in whole blocks, which may then bypass
+    // the operating system's cache
+    fn open_direct(&self, path: &str, create: bool) -> io::Result<Self::File> {
+        self.open(path, create)
+    }
+
+    // moves the file over another one, which it replaces
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    // handles to the file that are still open keep reading what it held
+    fn delete(&self, path: &str) -> io::Result<()>;
+
+    // the names of the files in dir, without the directory
+    fn list(&self, dir: &str) -> io::Result<Vec<String>>;
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()>;
+}
+
+pub trait StorageFile {
+    // fills buf from offset on, failing if the file ends first
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    // writes all of buf at offset, growing the file if needed
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    // returns once everything written so far survives a crash
+    fn sync(&self) -> io::Result<()>;
+}
+
+// the operating system's filesystem. Files opened for blocks use O_DIRECT unless that is
+// switched off, except those on a filesystem that refuses it, like tmpfs, which go through
+// the page cache instead
+#[derive(Clone, Debug)]
+pub struct FileSystem {
+    direct: bool,
+    // whether O_DIRECT worked for each file opened for blocks so far
+    direct_files: Rc<RefCell<HashMap<String, bool>>>,
+}
+
+impl Default for FileSystem {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl FileSystem {
+    pub fn new(direct: bool) -> Self {
+        Self {
+            direct,
+            direct_files: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    fn options(create: bool) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(create);
+        options
+    }
+}
+
+impl Storage for FileSystem {
+    type File = File;
+
+    fn open(&self, path: &str, create: bool) -> io::Result<File> {
+        Self::options(create).open(path)
+    }
+
+    fn open_direct(&self, path: &str, create: bool) -> io::Result<File> {
+        if !self.direct || self.direct_files.borrow().get(path) == Some(&false) {
+            return self.open(path, create);
+        }
+        // a filesystem without O_DIRECT fails the open with EINVAL
+        match Self::options(create).custom_flags(O_DIRECT).open(path) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                self.direct_files
+                    .borrow_mut()
+                    .insert(path.to_string(), false);
+                self.open(path, create)
+            }
+            Err(e) => Err(e),
+            Ok(file) => {
+                self.direct_files
+                    .borrow_mut()
+                    .insert(path.to_string(), true);
+                Ok(file)
+            }
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(from, to)?;
+        let mut direct_files = self.direct_files.borrow_mut();
+        direct_files.remove(to);
+        if let Some(direct) = direct_files.remove(from) {
+            direct_files.insert(to.to_string(), direct);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        self.direct_files.borrow_mut().remove(path);
+        fs::remove_file(path)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(fs::read_dir(dir)?
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .collect())
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+}
+
+impl StorageFile for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// files that live in memory and are gone with the last clone of the storage, so trees on
+// it leave nothing behind and do not share files with anyone else. Clones share the
+// files, a tree reopened on a clone finds what the one before it wrote
+#[derive(Clone, Debug, Default)]
+pub struct MemStorage {
+    files: Rc<RefCell<BTreeMap<String, MemFile>>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemFile {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    type File = MemFile;
+
+    fn open(&self, path: &str, create: bool) -> io::Result<MemFile> {
+        let mut files = self.files.borrow_mut();
+        match files.get(path) {
+            Some(file) => Ok(file.clone()),
+            None if create => Ok(files.entry(path.to_string()).or_default().clone()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        match self.files.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .files
+            .borrow()
+            .keys()
+            .filter_map(|path| {
+                let (parent, name) = path.rsplit_once('/').unwrap_or((".", path));
+                (parent == dir).then(|| name.to_string())
+            })
+            .collect())
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let bytes = self.bytes.borrow();
+        let start = offset as usize;
+        if start + buf.len() > bytes.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buf.copy_from_slice(&bytes[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut bytes = self.bytes.borrow_mut();
+        let start = offset as usize;
+        if start + buf.len() > bytes.len() {
+            bytes.resize(start + buf.len(), 0);
+        }
+        bytes[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.bytes.borrow().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.bytes.borrow_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, path.to_string())
+}
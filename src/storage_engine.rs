@@ -1,18 +1,791 @@
-// use std::fs::{create_dir, remove_dir};
+use std::{collections::BTreeMap, fmt, mem::take, ops::Bound};
 
-// use crate::buffer_manager::BufferManager;
+use serde::{Deserialize, Serialize};
 
-// // use tablefile to keep track of table information, load it all into memory at the start
+use crate::{
+    bplus_tree::BPlusTree,
+    buffer_manager::BufferManager,
+    fixed::KnowsSize,
+    heap_file::{HeapFile, Rid},
+    kv_store::{EntryTooLarge, KeyRange, KeyValueStore, StoreStats},
+    lsm_tree::LSMTree,
+    options::{Options, OptionsError},
+    storage::{FileSystem, Storage, StorageFile},
+};
 
-// fn create_table(b: BufferManager, table_name: String) {
-//     match create_dir(table_name) {
-//         Err(err) => {
-//             err.
-//         }
-//         Ok(_) => {}
-//     }
-// }
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Text(String),
+}
 
-// fn delete_table(b: BufferManager, table_name: String) {
-//     match remove_dir(path)
-// }
+impl KnowsSize for Value {
+    fn bit_width() -> i16 {
+        -1
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Row(pub Vec<Value>);
+
+impl KnowsSize for Row {
+    fn bit_width() -> i16 {
+        -1
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DataType {
+    Boolean,
+    Integer,
+    Text,
+}
+
+impl DataType {
+    pub fn accepts(&self, v: &Value) -> bool {
+        matches!(
+            (self, v),
+            (_, Value::Null)
+                | (DataType::Boolean, Value::Boolean(_))
+                | (DataType::Integer, Value::Integer(_))
+                | (DataType::Text, Value::Text(_))
+        )
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataType::Boolean => write!(f, "BOOLEAN"),
+            DataType::Integer => write!(f, "INTEGER"),
+            DataType::Text => write!(f, "TEXT"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+// write-optimised LSM storage or read-optimised B+Tree storage, chosen per table, and a
+// heap file for a table without a primary key
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum StorageKind {
+    Lsm,
+    BTree,
+    Heap,
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageKind::Lsm => write!(f, "LSM"),
+            StorageKind::BTree => write!(f, "BTREE"),
+            StorageKind::Heap => write!(f, "HEAP"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Schema {
+    pub name: String,
+    pub columns: Vec<Column>,
+    // None for a heap table
+    pub primary_key: Option<usize>,
+    pub storage: StorageKind,
+}
+
+impl Schema {
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(name))
+    }
+}
+
+// index entries pair the indexed value with the primary key so duplicate values stay
+// distinct. A heap table has no key, its entries pair the value with the record id made
+// an integer and hold the record id itself as their value
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexKey(pub Value, pub Value);
+
+impl IndexKey {
+    // primary keys and record ids are never NULL, so (v, NULL) sorts before every entry
+    // for v
+    fn first(v: Value) -> Self {
+        IndexKey(v, Value::Null)
+    }
+}
+
+fn rid_value(rid: Rid) -> Value {
+    Value::Integer(((rid.page as i64) << 16) | rid.slot as i64)
+}
+
+// where a statement finds a row again to change it
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RowId {
+    Key(Value),
+    Rid(Rid),
+}
+
+// the value sorting immediately after v, used to turn inclusive bounds on indexed values into
+// exclusive bounds on index keys
+fn successor(v: Value) -> Value {
+    match v {
+        Value::Null => Value::Boolean(false),
+        Value::Boolean(false) => Value::Boolean(true),
+        Value::Boolean(true) => Value::Integer(i64::MIN),
+        Value::Integer(i64::MAX) => Value::Text(String::new()),
+        Value::Integer(i) => Value::Integer(i + 1),
+        Value::Text(mut s) => {
+            s.push('\0');
+            Value::Text(s)
+        }
+    }
+}
+
+impl KnowsSize for IndexKey {
+    fn bit_width() -> i16 {
+        -1
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexDef {
+    pub name: String,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    TableExists(String),
+    NoSuchTable(String),
+    IndexExists(String),
+    NoSuchIndex(String),
+    BadName(String),
+    TooLarge(EntryTooLarge),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::TableExists(name) => write!(f, "table {} already exists", name),
+            EngineError::NoSuchTable(name) => write!(f, "no such table {}", name),
+            EngineError::IndexExists(name) => write!(f, "index {} already exists", name),
+            EngineError::NoSuchIndex(name) => write!(f, "no such index {}", name),
+            EngineError::BadName(name) => write!(f, "{:?} cannot name a table or index", name),
+            EngineError::TooLarge(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub type TableTree<S = FileSystem> = Box<dyn KeyValueStore<Value, Row, S>>;
+pub type IndexTree<S = FileSystem> = Box<dyn KeyValueStore<IndexKey, Option<Rid>, S>>;
+// what an index holds for a row, the rid only for rows in a heap
+type IndexEntry = (IndexKey, Option<Rid>);
+
+// a table's rows, in a tree keyed by the primary key or in a heap file
+pub enum TableStore<S: Storage = FileSystem> {
+    Tree(TableTree<S>),
+    Heap(HeapFile<Row, S>),
+}
+
+pub struct Index<S: Storage = FileSystem> {
+    pub def: IndexDef,
+    pub tree: IndexTree<S>,
+}
+
+// indexes always use the LSM tree, their entries are small and written on every row change
+// the engine has checked the options when it opened
+fn open_index<S: Storage + 'static>(
+    table: &str,
+    index: &str,
+    manager: &mut BufferManager<S>,
+    options: &Options,
+) -> IndexTree<S> {
+    Box::new(LSMTree::with_options(index_file(table, index), manager, options, None).unwrap())
+}
+
+fn open_table<S: Storage + 'static>(
+    kind: StorageKind,
+    name: &str,
+    manager: &mut BufferManager<S>,
+    options: &Options,
+) -> TableStore<S> {
+    let file = table_file(name);
+    match kind {
+        StorageKind::Lsm => TableStore::Tree(Box::new(
+            LSMTree::with_options(file, manager, options, None).unwrap(),
+        )),
+        StorageKind::BTree => TableStore::Tree(Box::new(
+            BPlusTree::with_options(file, manager, options).unwrap(),
+        )),
+        StorageKind::Heap => {
+            TableStore::Heap(HeapFile::with_options(file, manager, options).unwrap())
+        }
+    }
+}
+
+pub struct Table<S: Storage = FileSystem> {
+    pub schema: Schema,
+    pub store: TableStore<S>,
+    pub indexes: Vec<Index<S>>,
+}
+
+impl<S: Storage> Table<S> {
+    // the row with the primary key, a heap table has none
+    pub fn get_row(&self, manager: &mut BufferManager<S>, key: &Value) -> Option<Row> {
+        match &self.store {
+            TableStore::Tree(tree) => tree.get(manager, key.clone()),
+            TableStore::Heap(_) => None,
+        }
+    }
+
+    // rows with primary keys in the range, in key order. A heap table has no keys and
+    // returns all its rows
+    pub fn scan(
+        &self,
+        manager: &mut BufferManager<S>,
+        range: KeyRange<Value>,
+    ) -> Vec<(RowId, Row)> {
+        match &self.store {
+            TableStore::Tree(tree) => tree
+                .scan(manager, range)
+                .into_iter()
+                .map(|(key, row)| (RowId::Key(key), row))
+                .collect(),
+            TableStore::Heap(heap) => heap
+                .scan(manager)
+                .into_iter()
+                .map(|(rid, row)| (RowId::Rid(rid), row))
+                .collect(),
+        }
+    }
+
+    // the entry of the row in the index on column
+    fn index_entry(&self, column: usize, id: &RowId, row: &Row) -> IndexEntry {
+        let value = row.0[column].clone();
+        match id {
+            RowId::Key(key) => (IndexKey(value, key.clone()), None),
+            RowId::Rid(rid) => (IndexKey(value, rid_value(*rid)), Some(*rid)),
+        }
+    }
+
+    // whether the table and every index take the row, checked before any of them is
+    // written
+    pub fn check_fits(&self, row: &Row) -> Result<(), EntryTooLarge> {
+        let id = match (&self.store, self.schema.primary_key) {
+            (TableStore::Tree(tree), Some(pk)) => {
+                tree.fits(&row.0[pk], row)?;
+                RowId::Key(row.0[pk].clone())
+            }
+            (TableStore::Heap(heap), _) => {
+                heap.fits(row)?;
+                RowId::Rid(Rid {
+                    page: u64::MAX,
+                    slot: u16::MAX,
+                })
+            }
+            (TableStore::Tree(_), None) => panic!("table {} has no key", self.schema.name),
+        };
+        for index in self.indexes.iter() {
+            let (entry, target) = self.index_entry(index.def.column, &id, row);
+            index.tree.fits(&entry, &target)?;
+        }
+        Ok(())
+    }
+
+    // writes the row and brings every index in line with it before returning. A row with
+    // the primary key of another replaces it, a heap table takes the row as a new one
+    pub fn put_row(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        row: Row,
+    ) -> Result<(), EntryTooLarge> {
+        self.check_fits(&row)?;
+        let (id, old) = match &mut self.store {
+            TableStore::Tree(tree) => {
+                let key = row.0[self.schema.primary_key.unwrap()].clone();
+                let old = tree.get(manager, key.clone());
+                (RowId::Key(key), old)
+            }
+            TableStore::Heap(heap) => (RowId::Rid(heap.insert(manager, row.clone())?), None),
+        };
+        self.update_indexes(manager, old.map(|old| (id.clone(), old)), &id, &row)?;
+        if let (TableStore::Tree(tree), RowId::Key(key)) = (&mut self.store, id) {
+            tree.put(manager, key, Some(row))?;
+        }
+        Ok(())
+    }
+
+    // replaces the row of a heap table where it is, moving it when its page has no room
+    pub fn update_row(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        rid: Rid,
+        row: Row,
+    ) -> Result<(), EntryTooLarge> {
+        self.check_fits(&row)?;
+        let TableStore::Heap(heap) = &mut self.store else {
+            panic!("table {} is not a heap", self.schema.name);
+        };
+        let Some(old) = heap.get(manager, rid) else {
+            return Ok(());
+        };
+        let new_rid = heap.update(manager, rid, row.clone())?;
+        self.update_indexes(
+            manager,
+            Some((RowId::Rid(rid), old)),
+            &RowId::Rid(new_rid),
+            &row,
+        )
+    }
+
+    // swaps the index entries of the old row for those of the new one, leaving entries
+    // that stay the same alone
+    fn update_indexes(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        old: Option<(RowId, Row)>,
+        id: &RowId,
+        row: &Row,
+    ) -> Result<(), EntryTooLarge> {
+        let entries: Vec<(IndexEntry, Option<IndexKey>)> = self
+            .indexes
+            .iter()
+            .map(|index| {
+                let column = index.def.column;
+                let old = old
+                    .as_ref()
+                    .map(|(old_id, old_row)| self.index_entry(column, old_id, old_row).0);
+                (self.index_entry(column, id, row), old)
+            })
+            .collect();
+        for (index, ((entry, target), old)) in self.indexes.iter_mut().zip(entries) {
+            if old.as_ref() == Some(&entry) {
+                continue;
+            }
+            if let Some(old) = old {
+                index.tree.delete(manager, old);
+            }
+            index.tree.put(manager, entry, Some(target))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_row(&mut self, manager: &mut BufferManager<S>, id: &RowId) {
+        let old = match (&self.store, id) {
+            (TableStore::Tree(tree), RowId::Key(key)) => tree.get(manager, key.clone()),
+            (TableStore::Heap(heap), RowId::Rid(rid)) => heap.get(manager, *rid),
+            _ => None,
+        };
+        let Some(old) = old else {
+            return;
+        };
+        let entries: Vec<IndexKey> = self
+            .indexes
+            .iter()
+            .map(|index| self.index_entry(index.def.column, id, &old).0)
+            .collect();
+        for (index, entry) in self.indexes.iter_mut().zip(entries) {
+            index.tree.delete(manager, entry);
+        }
+        match (&mut self.store, id) {
+            (TableStore::Tree(tree), RowId::Key(key)) => tree.delete(manager, key.clone()),
+            (TableStore::Heap(heap), RowId::Rid(rid)) => heap.delete(manager, *rid),
+            _ => {}
+        }
+    }
+
+    pub fn stats(&self, manager: &mut BufferManager<S>) -> StoreStats {
+        match &self.store {
+            TableStore::Tree(tree) => tree.stats(manager),
+            TableStore::Heap(heap) => StoreStats::Heap(heap.stats(manager)),
+        }
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index<S>> {
+        self.indexes
+            .iter()
+            .find(|i| i.def.name.eq_ignore_ascii_case(name))
+    }
+
+    // rows whose indexed column falls within the bounds, in index order
+    pub fn index_scan(
+        &self,
+        manager: &mut BufferManager<S>,
+        index: &str,
+        start: Bound<Value>,
+        end: Bound<Value>,
+    ) -> Result<Vec<(RowId, Row)>, EngineError> {
+        let Some(index) = self.index(index) else {
+            return Err(EngineError::NoSuchIndex(index.to_string()));
+        };
+        let start = match start {
+            Bound::Included(v) => Bound::Included(IndexKey::first(v)),
+            Bound::Excluded(v) => Bound::Included(IndexKey::first(successor(v))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(v) => Bound::Excluded(IndexKey::first(successor(v))),
+            Bound::Excluded(v) => Bound::Excluded(IndexKey::first(v)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        let mut rows = Vec::new();
+        for (IndexKey(_, key), target) in index.tree.scan(manager, (start, end)) {
+            let found = match (&self.store, target) {
+                (TableStore::Tree(tree), _) => tree
+                    .get(manager, key.clone())
+                    .map(|row| (RowId::Key(key), row)),
+                (TableStore::Heap(heap), Some(rid)) => {
+                    heap.get(manager, rid).map(|row| (RowId::Rid(rid), row))
+                }
+                (TableStore::Heap(_), None) => None,
+            };
+            rows.extend(found);
+        }
+        Ok(rows)
+    }
+
+    fn flush(&mut self, manager: &mut BufferManager<S>) {
+        // a heap file edits its pages in the buffer pool and holds nothing back
+        if let TableStore::Tree(tree) = &mut self.store {
+            tree.flush(manager);
+        }
+        for index in self.indexes.iter_mut() {
+            index.tree.flush(manager);
+        }
+    }
+
+    fn compact(&mut self, manager: &mut BufferManager<S>) {
+        if let TableStore::Tree(tree) = &mut self.store {
+            tree.compact(manager);
+        }
+        for index in self.indexes.iter_mut() {
+            index.tree.compact(manager);
+        }
+    }
+
+    fn destroy(self, manager: &mut BufferManager<S>) {
+        for index in self.indexes {
+            index.tree.destroy(manager);
+        }
+        match self.store {
+            TableStore::Tree(tree) => tree.destroy(manager),
+            TableStore::Heap(heap) => heap.destroy(manager),
+        }
+    }
+}
+
+// names end up in file names under the data directory, where a / or .. would reach out of
+// it and a . could make two table and index pairs share a file
+pub fn check_name(name: &str) -> Result<(), EngineError> {
+    if name.is_empty() || name.contains(['/', '.', '\0']) {
+        return Err(EngineError::BadName(name.to_string()));
+    }
+    Ok(())
+}
+
+// tables and indexes are looked up ignoring case, like columns
+fn table_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+fn table_file(name: &str) -> String {
+    format!("{}.table", name)
+}
+
+fn index_file(table: &str, index: &str) -> String {
+    format!("{}.{}.index", table, index)
+}
+
+fn tablefile(options: &Options) -> String {
+    format!("{}/tablefile", options.data_dir)
+}
+
+fn unclean_marker(options: &Options) -> String {
+    format!("{}/unclean", options.data_dir)
+}
+
+// opens the index and fills it from the rows of the table, over whatever an index of the
+// name left behind
+fn build_index<S: Storage + 'static>(
+    t: &Table<S>,
+    manager: &mut BufferManager<S>,
+    options: &Options,
+    def: &IndexDef,
+) -> Result<IndexTree<S>, EngineError> {
+    open_index(&t.schema.name, &def.name, manager, options).destroy(manager);
+    let mut tree = open_index(&t.schema.name, &def.name, manager, options);
+    let entries: Vec<(IndexKey, Option<Rid>)> = t
+        .scan(manager, (Bound::Unbounded, Bound::Unbounded))
+        .into_iter()
+        .map(|(id, row)| t.index_entry(def.column, &id, &row))
+        .collect();
+    let too_large = entries
+        .iter()
+        .find_map(|(entry, target)| tree.fits(entry, target).err());
+    if let Some(e) = too_large {
+        tree.destroy(manager);
+        return Err(EngineError::TooLarge(e));
+    }
+    for (entry, target) in entries {
+        tree.put(manager, entry, Some(target)).unwrap();
+    }
+    Ok(tree)
+}
+
+// the tablefile keeps every schema and index definition, it is loaded into memory in full
+// when the engine opens.
+//
+// A row and its index entries are written to separate trees one after the other, so a
+// crash between them leaves the indexes out of step with the table. The first write after
+// a checkpoint leaves a marker file, which the next checkpoint removes. An engine that
+// opens to find the marker rebuilds every index from its table
+pub struct StorageEngine<S: Storage = FileSystem> {
+    tables: BTreeMap<String, Table<S>>,
+    options: Options,
+    // whether the marker is there
+    unclean: bool,
+}
+
+impl<S: Storage + 'static> StorageEngine<S> {
+    pub fn open(manager: &mut BufferManager<S>) -> Self {
+        Self::with_options(manager, &Options::default()).unwrap()
+    }
+
+    // every table and index lives under the data directory of the options
+    pub fn with_options(
+        manager: &mut BufferManager<S>,
+        options: &Options,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let storage = manager.storage();
+        storage.create_dir_all(&options.data_dir).unwrap();
+
+        let mut entries: Vec<(Schema, Vec<IndexDef>)> = Vec::new();
+        if let Ok(fd) = storage.open(&tablefile(options), false) {
+            let mut buf = vec![0; fd.len().unwrap() as usize];
+            fd.read_at(&mut buf, 0).unwrap();
+            entries = bincode::deserialize(&buf).unwrap();
+        }
+        let unclean = storage.open(&unclean_marker(options), false).is_ok();
+
+        let mut tables = BTreeMap::new();
+        for (schema, defs) in entries {
+            let store = open_table(schema.storage, &schema.name, manager, options);
+            let indexes = defs
+                .into_iter()
+                .map(|def| Index {
+                    tree: open_index(&schema.name, &def.name, manager, options),
+                    def,
+                })
+                .collect();
+            tables.insert(
+                table_key(&schema.name),
+                Table {
+                    schema,
+                    store,
+                    indexes,
+                },
+            );
+        }
+
+        if unclean {
+            for t in tables.values_mut() {
+                let defs: Vec<IndexDef> = take(&mut t.indexes)
+                    .into_iter()
+                    .map(|index| index.def)
+                    .collect();
+                for def in defs {
+                    let tree = build_index(t, manager, options, &def)
+                        .expect("rows that were indexed before fit in the index again");
+                    t.indexes.push(Index { def, tree });
+                }
+            }
+        }
+
+        Ok(Self {
+            tables,
+            options: options.clone(),
+            unclean,
+        })
+    }
+
+    pub fn table(&self, name: &str) -> Result<&Table<S>, EngineError> {
+        self.tables
+            .get(&table_key(name))
+            .ok_or_else(|| EngineError::NoSuchTable(name.to_string()))
+    }
+
+    // for writing to the table, which the indexes are rebuilt after unless a checkpoint
+    // follows
+    pub fn table_mut(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+    ) -> Result<&mut Table<S>, EngineError> {
+        self.mark_unclean(manager);
+        self.tables
+            .get_mut(&table_key(name))
+            .ok_or_else(|| EngineError::NoSuchTable(name.to_string()))
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &Table<S>> {
+        self.tables.values()
+    }
+
+    pub fn create_table(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        schema: Schema,
+    ) -> Result<(), EngineError> {
+        check_name(&schema.name)?;
+        if self.tables.contains_key(&table_key(&schema.name)) {
+            return Err(EngineError::TableExists(schema.name));
+        }
+        let store = open_table(schema.storage, &schema.name, manager, &self.options);
+        self.tables.insert(
+            table_key(&schema.name),
+            Table {
+                schema,
+                store,
+                indexes: Vec::new(),
+            },
+        );
+        self.save_catalog(manager);
+        Ok(())
+    }
+
+    pub fn drop_table(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+    ) -> Result<(), EngineError> {
+        let Some(table) = self.tables.remove(&table_key(name)) else {
+            return Err(EngineError::NoSuchTable(name.to_string()));
+        };
+        table.destroy(manager);
+        self.save_catalog(manager);
+        Ok(())
+    }
+
+    // creates the index and backfills it from the rows already in the table
+    pub fn create_index(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        table: &str,
+        def: IndexDef,
+    ) -> Result<(), EngineError> {
+        check_name(&def.name)?;
+        if self.find_index(&def.name).is_some() {
+            return Err(EngineError::IndexExists(def.name));
+        }
+        let options = self.options.clone();
+        let t = self.table_mut(manager, table)?;
+        let tree = build_index(t, manager, &options, &def)?;
+        t.indexes.push(Index { def, tree });
+        self.save_catalog(manager);
+        Ok(())
+    }
+
+    pub fn drop_index(
+        &mut self,
+        manager: &mut BufferManager<S>,
+        name: &str,
+    ) -> Result<(), EngineError> {
+        let Some(table) = self.find_index(name) else {
+            return Err(EngineError::NoSuchIndex(name.to_string()));
+        };
+        let t = self.table_mut(manager, &table)?;
+        let i = t
+            .indexes
+            .iter()
+            .position(|i| i.def.name.eq_ignore_ascii_case(name))
+            .unwrap();
+        t.indexes.remove(i).tree.destroy(manager);
+        self.save_catalog(manager);
+        Ok(())
+    }
+
+    // returns the table owning the index, index names are unique across the database
+    fn find_index(&self, name: &str) -> Option<String> {
+        self.tables
+            .values()
+            .find(|t| t.index(name).is_some())
+            .map(|t| t.schema.name.clone())
+    }
+
+    // flushes every tree into the buffer pool and writes all dirty pages out
+    pub fn flush(&mut self, manager: &mut BufferManager<S>) {
+        for table in self.tables.values_mut() {
+            table.flush(manager);
+        }
+        manager.flush();
+    }
+
+    // flushes and syncs every table and index, after which they agree on disk
+    pub fn checkpoint(&mut self, manager: &mut BufferManager<S>) {
+        for table in self.tables.values_mut() {
+            table.flush(manager);
+        }
+        manager.checkpoint();
+        if self.unclean {
+            manager
+                .storage()
+                .delete(&unclean_marker(&self.options))
+                .unwrap();
+            self.unclean = false;
+        }
+    }
+
+    fn mark_unclean(&mut self, manager: &mut BufferManager<S>) {
+        if !self.unclean {
+            manager
+                .storage()
+                .open(&unclean_marker(&self.options), true)
+                .and_then(|fd| fd.sync())
+                .unwrap();
+            self.unclean = true;
+        }
+    }
+
+    pub fn compact(&mut self, manager: &mut BufferManager<S>) {
+        for table in self.tables.values_mut() {
+            table.compact(manager);
+        }
+        manager.flush();
+    }
+
+    fn save_catalog(&self, manager: &mut BufferManager<S>) {
+        let entries: Vec<(&Schema, Vec<&IndexDef>)> = self
+            .tables
+            .values()
+            .map(|t| (&t.schema, t.indexes.iter().map(|i| &i.def).collect()))
+            .collect();
+        let buf = bincode::serialize(&entries).unwrap();
+        let fd = manager
+            .storage()
+            .open(&tablefile(&self.options), true)
+            .unwrap();
+        fd.write_at(&buf, 0).unwrap();
+        fd.set_len(buf.len() as u64).unwrap();
+        fd.sync().unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::File;
+
+use crate::{
+    options::SyncPolicy,
+    storage::{Storage, StorageFile},
+};
+
+/*
+Write-ahead log format, a sequence of records:
+| payload length u32 | crc32 of payload u32 | payload |
+A crash can leave the last record torn. Replay stops at the first record that is cut short
+or fails its checksum and truncates the log there, so later appends follow a valid record.
+*/
+
+const RECORD_HEADER: usize = 8;
+
+pub struct Wal<F = File> {
+    path: String,
+    file: F,
+    len: u64,
+    sync: SyncPolicy,
+}
+
+impl<F: StorageFile> Wal<F> {
+    // opens or creates the log and returns every intact record in it
+    pub fn open<S: Storage<File = F>>(
+        storage: &S,
+        path: String,
+        sync: SyncPolicy,
+    ) -> (Self, Vec<Vec<u8>>) {
+        let file = storage.open(&path, true).unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        file.read_at(&mut buf, 0).unwrap();
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos + RECORD_HEADER <= buf.len() {
+            let len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap());
+            let start = pos + RECORD_HEADER;
+            if start + len > buf.len() || crc32fast::hash(&buf[start..start + len]) != crc {
+                break;
+            }
+            records.push(buf[start..start + len].to_vec());
+            pos = start + len;
+        }
+        if pos < buf.len() {
+            file.set_len(pos as u64).unwrap();
+        }
+
+        let wal = Self {
+            path,
+            file,
+            len: pos as u64,
+            sync,
+        };
+        (wal, records)
+    }
+
+    // the record reaches the operating system before this returns, and the disk as well
+    // when the log syncs every write
+    pub fn append(&mut self, payload: &[u8]) {
+        let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+        record.extend((payload.len() as u32).to_le_bytes());
+        record.extend(crc32fast::hash(payload).to_le_bytes());
+        record.extend(payload);
+        self.file.write_at(&record, self.len).unwrap();
+        if self.sync == SyncPolicy::Always {
+            self.file.sync().unwrap();
+        }
+        self.len += record.len() as u64;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // moves the log over another file, which it replaces
+    pub fn rename<S: Storage<File = F>>(&mut self, storage: &S, to: String) {
+        storage.rename(&self.path, &to).unwrap();
+        self.path = to;
+    }
+
+    pub fn destroy<S: Storage<File = F>>(self, storage: &S) {
+        let _ = storage.delete(&self.path);
+    }
+}